@@ -0,0 +1,65 @@
+use crate::control::{ConcurrencyChange, RateChange, Summary};
+use crate::graph::RequestKind;
+use serde_json::Value;
+use std::error::Error;
+
+/// Action sent by the `ctl` subcommand to the control endpoint of a run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlCommand {
+    Summary,
+    Pause,
+    Resume,
+    /// Cap the concurrency
+    Concurrency(usize),
+    /// Set the budget of a kind of requests, none meaning unlimited
+    Rate(RequestKind, Option<f64>),
+    Stop,
+}
+
+/// Send `command` to the control endpoint at `address` (`host:port` or a
+/// URL), returning the text to print.
+pub async fn send_control(
+    address: &str,
+    command: &ControlCommand,
+) -> Result<String, Box<dyn Error>> {
+    let base = if address.contains("://") {
+        address.trim_end_matches('/').to_string()
+    } else {
+        format!("http://{address}")
+    };
+    let client = reqwest::Client::new();
+    let request = match command {
+        ControlCommand::Summary => client.get(format!("{base}/summary")),
+        ControlCommand::Pause => client.post(format!("{base}/pause")),
+        ControlCommand::Resume => client.post(format!("{base}/resume")),
+        ControlCommand::Concurrency(limit) => client
+            .post(format!("{base}/concurrency"))
+            .json(&ConcurrencyChange { limit: *limit }),
+        ControlCommand::Rate(kind, rps) => client.post(format!("{base}/rate")).json(&RateChange {
+            kind: kind.label().to_string(),
+            rps: *rps,
+        }),
+        ControlCommand::Stop => client.post(format!("{base}/stop")),
+    };
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("Control endpoint {base} unreachable: {e}"))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        // Errors of the endpoint come as {"error": ...}, others as text
+        let error = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|v| v["error"].as_str().map(str::to_string))
+            .unwrap_or(body);
+        return Err(format!("Control request failed with status {status}: {error}").into());
+    }
+    match command {
+        ControlCommand::Summary => Ok(response.json::<Summary>().await?.to_string()),
+        _ => Ok(response.json::<Value>().await?["message"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string()),
+    }
+}
@@ -0,0 +1,5 @@
+mod client;
+mod server;
+
+pub use crate::control::client::*;
+pub use crate::control::server::*;
@@ -0,0 +1,320 @@
+use crate::dashboard::run_stats;
+use crate::graph::{AdaptiveLimiter, AppPool, RateLimits, RequestKind};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use indicatif::{HumanDuration, ProgressBar};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What the control endpoint acts on: the limiter of the dispatch, the rate
+/// limits (of the run, or of every app of the pool) and the progress bar.
+pub struct Control {
+    limiter: &'static AdaptiveLimiter,
+    limits: Option<&'static RateLimits>,
+    pool: Option<&'static AppPool>,
+    progress: ProgressBar,
+}
+
+/// State of a run, as returned by `GET /summary`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    /// `running`, `paused`, `throttled`, `outside window` or `stopping`
+    pub state: String,
+    pub elapsed_secs: u64,
+    /// Rows processed (including rows of other shards) and rows to process
+    pub processed: u64,
+    pub total: u64,
+    pub created: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    pub requests: u64,
+    pub in_flight: usize,
+    pub concurrency: usize,
+    pub ceiling: usize,
+    /// Seconds left before the end of a throttling pause
+    pub throttled_for_secs: Option<u64>,
+    /// Request-per-second budgets, per app with a pool, none when unlimited
+    pub users_rps: Option<f64>,
+    pub methods_rps: Option<f64>,
+    /// Apps of the pool, 0 without one
+    pub apps: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.throttled_for_secs {
+            Some(secs) => writeln!(f, "State        {}, resuming in {secs}s", self.state)?,
+            None => writeln!(f, "State        {}", self.state)?,
+        }
+        let percent = match self.total {
+            0 => 0,
+            total => self.processed * 100 / total,
+        };
+        writeln!(
+            f,
+            "Progress     {}/{} ({percent}%) in {}",
+            self.processed,
+            self.total,
+            HumanDuration(Duration::from_secs(self.elapsed_secs))
+        )?;
+        writeln!(
+            f,
+            "Rows         {} created, {} failed, {} skipped",
+            self.created, self.failed, self.skipped
+        )?;
+        writeln!(f, "Requests     {}", self.requests)?;
+        writeln!(
+            f,
+            "Concurrency  {} (cap {}), {} in flight",
+            self.concurrency, self.ceiling, self.in_flight
+        )?;
+        let rate = |rps: Option<f64>| rps.map_or("unlimited".to_string(), |rps| format!("{rps}/s"));
+        write!(
+            f,
+            "Rate limits  users {}, methods {}",
+            rate(self.users_rps),
+            rate(self.methods_rps)
+        )?;
+        if self.apps > 0 {
+            write!(f, " per app ({} apps)", self.apps)?;
+        }
+        Ok(())
+    }
+}
+
+/// Concurrency cap requested by `POST /concurrency`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConcurrencyChange {
+    pub limit: usize,
+}
+
+/// Budget requested by `POST /rate`: `users` or `methods`, with no rate
+/// meaning unlimited.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RateChange {
+    pub kind: String,
+    pub rps: Option<f64>,
+}
+
+impl Control {
+    pub fn new(
+        limiter: &'static AdaptiveLimiter,
+        limits: Option<&'static RateLimits>,
+        pool: Option<&'static AppPool>,
+        progress: ProgressBar,
+    ) -> Control {
+        Control {
+            limiter,
+            limits,
+            pool,
+            progress,
+        }
+    }
+
+    /// Current state of the run.
+    pub fn summary(&self) -> Summary {
+        let paused_for = self.limiter.paused_for();
+        let state = if self.limiter.is_closed() {
+            "stopping"
+        } else if paused_for.is_some() {
+            "throttled"
+        } else if self.limiter.is_held() {
+            "paused"
+        } else if self.limiter.is_outside_window() {
+            "outside window"
+        } else {
+            "running"
+        };
+        let counts = run_stats().map(|s| s.counts()).unwrap_or_default();
+        Summary {
+            state: state.to_string(),
+            elapsed_secs: self.progress.elapsed().as_secs(),
+            processed: self.progress.position(),
+            total: self.progress.length().unwrap_or(0),
+            created: counts.created,
+            failed: counts.failed,
+            skipped: counts.skipped,
+            requests: run_stats().map_or(0, |s| s.requests()),
+            in_flight: self.limiter.in_flight(),
+            concurrency: self.limiter.limit(),
+            ceiling: self.limiter.ceiling(),
+            throttled_for_secs: paused_for.map(|wait| wait.as_secs() + 1),
+            users_rps: self.rate(RequestKind::UserCreation),
+            methods_rps: self.rate(RequestKind::AuthMethod),
+            apps: self.pool.map_or(0, |pool| pool.app_count()),
+        }
+    }
+
+    fn rate(&self, kind: RequestKind) -> Option<f64> {
+        match (self.pool, self.limits) {
+            (Some(pool), _) => pool.rate(kind),
+            (None, Some(limits)) => limits.bucket(kind).rate(),
+            (None, None) => None,
+        }
+    }
+
+    /// Change the budget of the requests of `kind`, of every app with a pool.
+    pub fn set_rate(&self, kind: RequestKind, rps: Option<f64>) -> Result<(), String> {
+        match (self.pool, self.limits) {
+            (Some(pool), _) => pool.set_rate(kind, rps),
+            (None, Some(limits)) => limits.bucket(kind).set_rate(rps),
+            (None, None) => Err("No rate limits in this run".to_string()),
+        }
+    }
+}
+
+/// Routes of the control endpoint.
+pub fn control_router(control: Arc<Control>) -> Router {
+    Router::new()
+        .route("/summary", get(summary))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route("/concurrency", post(concurrency))
+        .route("/rate", post(rate))
+        .route("/stop", post(stop))
+        .with_state(control)
+}
+
+/// Serve the control endpoint on `listen` in the background, returning the
+/// address bound once it is listening.
+pub async fn serve_control(
+    listen: &str,
+    control: Arc<Control>,
+) -> Result<SocketAddr, Box<dyn Error>> {
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    let address = listener.local_addr()?;
+    if !address.ip().is_loopback() {
+        warn!("Control endpoint bound to {address}: anyone reaching it can pause or stop the run.");
+    }
+    tokio::spawn(async move { axum::serve(listener, control_router(control)).await });
+    info!("Control endpoint listening on http://{address}.");
+    Ok(address)
+}
+
+// Answer of an action, also logged as the trace of who changed the run
+fn done(message: String) -> Response {
+    info!("Control: {message}");
+    Json(json!({ "message": message })).into_response()
+}
+
+fn rejected(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response()
+}
+
+async fn summary(State(control): State<Arc<Control>>) -> Json<Summary> {
+    Json(control.summary())
+}
+
+async fn pause(State(control): State<Arc<Control>>) -> Response {
+    control.limiter.hold(true);
+    done("dispatch of new users paused, running users finish.".to_string())
+}
+
+async fn resume(State(control): State<Arc<Control>>) -> Response {
+    control.limiter.hold(false);
+    done("dispatch of new users resumed.".to_string())
+}
+
+async fn concurrency(
+    State(control): State<Arc<Control>>,
+    Json(change): Json<ConcurrencyChange>,
+) -> Response {
+    let ceiling = control.limiter.set_ceiling(change.limit);
+    done(format!(
+        "concurrency capped at {ceiling} (currently {}).",
+        control.limiter.limit()
+    ))
+}
+
+async fn rate(State(control): State<Arc<Control>>, Json(change): Json<RateChange>) -> Response {
+    let kind = match change.kind.parse::<RequestKind>() {
+        Ok(kind) => kind,
+        Err(e) => return rejected(e),
+    };
+    if let Err(e) = control.set_rate(kind, change.rps) {
+        return rejected(e);
+    }
+    let per_app = if control.pool.is_some() {
+        " per app"
+    } else {
+        ""
+    };
+    done(match change.rps {
+        Some(rps) => format!(
+            "rate limit of {} set to {rps} per second{per_app}.",
+            kind.label()
+        ),
+        None => format!("rate limit of {} removed.", kind.label()),
+    })
+}
+
+async fn stop(State(control): State<Arc<Control>>) -> Response {
+    control.limiter.close();
+    warn!("Stop requested: no new users are dispatched, the run ends once running users finish.");
+    done("stopping once running users finish.".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{send_control, ControlCommand};
+    use crate::graph::TokenBucket;
+
+    #[tokio::test]
+    async fn test_control_endpoint_tunes_the_run() {
+        let limiter: &'static AdaptiveLimiter = Box::leak(Box::new(AdaptiveLimiter::new(4, 1, 8)));
+        let limits: &'static RateLimits = Box::leak(Box::new(RateLimits {
+            users: TokenBucket::new(10.0).unwrap(),
+            methods: TokenBucket::unlimited(),
+        }));
+        let progress = ProgressBar::hidden();
+        progress.set_length(80);
+        progress.set_position(20);
+        let control = Arc::new(Control::new(limiter, Some(limits), None, progress));
+        let address = serve_control("127.0.0.1:0", control).await.unwrap();
+        let address = address.to_string();
+        let send = |command: ControlCommand| {
+            let address = address.clone();
+            async move { send_control(&address, &command).await }
+        };
+
+        send(ControlCommand::Pause).await.unwrap();
+        assert!(limiter.is_held());
+        send(ControlCommand::Concurrency(2)).await.unwrap();
+        assert_eq!((limiter.ceiling(), limiter.limit()), (2, 2));
+        let message = send(ControlCommand::Rate(RequestKind::AuthMethod, Some(5.0)))
+            .await
+            .unwrap();
+        assert_eq!(message, "rate limit of methods set to 5 per second.");
+        assert_eq!(limits.methods.rate(), Some(5.0));
+        send(ControlCommand::Rate(RequestKind::UserCreation, None))
+            .await
+            .unwrap();
+        assert_eq!(limits.users.rate(), None);
+        let error = send(ControlCommand::Rate(RequestKind::UserCreation, Some(-1.0)))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("it must be a positive number"));
+
+        let summary = send(ControlCommand::Summary).await.unwrap();
+        assert!(summary.contains("State        paused"));
+        assert!(summary.contains("Progress     20/80 (25%)"));
+        assert!(summary.contains("Concurrency  2 (cap 2), 0 in flight"));
+        assert!(summary.contains("users unlimited, methods 5/s"));
+
+        send(ControlCommand::Resume).await.unwrap();
+        send(ControlCommand::Stop).await.unwrap();
+        assert!(!limiter.is_held());
+        assert!(limiter.is_closed());
+        assert!(limiter.acquire().await.is_none());
+    }
+}
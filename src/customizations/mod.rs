@@ -1 +1,2 @@
 pub(crate) mod prj1;
+pub(crate) mod smtp;
@@ -1,4 +1,7 @@
 #![allow(non_snake_case)]
+use crate::db::pii;
+use crate::metrics::{observe_response, Stage};
+use crate::telemetry::start_span;
 use chrono::Utc;
 use log::{error, info};
 use reqwest::{header, Client};
@@ -166,6 +169,8 @@ pub async fn send_notification(client: &Client, cfg: &Prj1AppConfig, email: &Str
         email.into(),
     );
 
+    let mut span = start_span("notification");
+    let started = std::time::Instant::now();
     match client
         .post(&cfg.url)
         // mandatory headers ---------------------------------------------------
@@ -177,14 +182,28 @@ pub async fn send_notification(client: &Client, cfg: &Prj1AppConfig, email: &Str
         .await
     {
         Ok(response) => {
+            span.record_response(&response);
+            if !response.status().is_success() {
+                span.fail(&format!("Received {}", response.status()));
+            }
+            observe_response(
+                Stage::Notification,
+                Some(response.status().as_u16()),
+                started.elapsed(),
+            );
             info!(
                 "[{:?}] Successfully sent notification email, with status: {}.",
-                email,
+                pii(email),
                 response.status()
             );
         }
         Err(e) => {
-            error!("[{email:?}] Something went wrong when sending the email: {e:?}");
+            span.fail(&e.to_string());
+            observe_response(Stage::Notification, None, started.elapsed());
+            error!(
+                "[{:?}] Something went wrong when sending the email: {e:?}",
+                pii(email)
+            );
         }
     }
 }
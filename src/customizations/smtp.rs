@@ -0,0 +1,405 @@
+use crate::db::pii;
+use crate::graph::RequestBody;
+use crate::metrics::{observe_response, Stage};
+use crate::telemetry::start_span;
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::{fmt, fs, path::Path};
+
+/// Mirrors the keys that appear in the SMTP TOML configuration file.
+///
+/// Example file:
+/// ```toml
+/// host     = "smtp.example.com"
+/// port     = 587
+/// tls      = "starttls"                         # none | starttls | implicit
+/// username = "mailer"                           # optional
+/// password = "YOUR-SMTP-PASSWORD"               # optional
+/// from     = "Migration <noreply@example.com>"
+/// template = "welcome"                          # welcome | password-reset
+/// link     = "https://login.example.com/reset"  # optional, available as {link}
+/// subject  = "Welcome {displayName}"            # optional, overrides the template
+/// body     = "Sign in with {email}."            # optional, overrides the template
+/// ```
+#[derive(Deserialize, Clone)]
+pub struct SmtpConfig {
+    host: String,
+    port: Option<u16>,
+    #[serde(default)]
+    tls: SmtpTls,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    #[serde(default)]
+    template: SmtpTemplate,
+    #[serde(default)]
+    link: String,
+    subject: Option<String>,
+    body: Option<String>,
+}
+
+// The password never reaches the logs
+impl fmt::Debug for SmtpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmtpConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "[REDACTED]"))
+            .field("from", &self.from)
+            .field("template", &self.template)
+            .field("link", &self.link)
+            .field("subject", &self.subject)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+/// SMTP configuration with its transport, built once per run: the
+/// connections are pooled and shared by every notification.
+#[derive(Debug, Clone)]
+pub struct SmtpNotifier {
+    cfg: SmtpConfig,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpNotifier {
+    pub fn new(cfg: SmtpConfig) -> Result<SmtpNotifier, lettre::transport::smtp::Error> {
+        let transport = build_transport(&cfg)?;
+        Ok(SmtpNotifier { cfg, transport })
+    }
+}
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain text connection, only meant for local relays and test servers
+    None,
+    /// Upgrade a plain connection with STARTTLS (usually port 587)
+    #[default]
+    StartTls,
+    /// TLS from the first byte (usually port 465)
+    Implicit,
+}
+
+/// Built-in message templates.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SmtpTemplate {
+    #[default]
+    Welcome,
+    PasswordReset,
+}
+
+impl SmtpTemplate {
+    fn subject(self) -> &'static str {
+        match self {
+            SmtpTemplate::Welcome => "Welcome, {displayName}",
+            SmtpTemplate::PasswordReset => "Please set a new password",
+        }
+    }
+
+    fn body(self) -> &'static str {
+        match self {
+            SmtpTemplate::Welcome => {
+                "Hello {displayName},\n\nyour account has been created. You can now sign in with {email}.\n\n{link}\n"
+            }
+            SmtpTemplate::PasswordReset => {
+                "Hello {displayName},\n\nyour account {email} has been moved to our new sign-in service. Please set a new password before signing in.\n\n{link}\n"
+            }
+        }
+    }
+}
+
+/// Load and parse an SMTP configuration file.
+///
+/// # Errors
+/// * I/O failures while reading the file
+/// * TOML-syntax or type mismatches while parsing
+pub fn smtp_load_config<P: AsRef<Path>>(path: P) -> Result<SmtpConfig, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)?;
+    let config = toml::from_str::<SmtpConfig>(&contents)?;
+    Ok(config)
+}
+
+/// Replace the `{displayName}`, `{email}` and `{link}` placeholders of a template.
+fn render(template: &str, cfg: &SmtpConfig, display_name: &str, email: &str) -> String {
+    template
+        .replace("{displayName}", display_name)
+        .replace("{email}", email)
+        .replace("{link}", &cfg.link)
+}
+
+/// Build the SMTP transport described in `SmtpConfig`.
+fn build_transport(
+    cfg: &SmtpConfig,
+) -> Result<AsyncSmtpTransport<Tokio1Executor>, lettre::transport::smtp::Error> {
+    let mut builder = match cfg.tls {
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&cfg.host),
+        SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&cfg.host)?,
+        SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&cfg.host)?,
+    };
+    if let Some(port) = cfg.port {
+        builder = builder.port(port);
+    }
+    if let (Some(username), Some(password)) = (&cfg.username, &cfg.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    Ok(builder.build())
+}
+
+/// Send the configured template to the address found in the `emailAddress`
+/// identity of `body`. Users without such an identity are skipped.
+pub async fn send_smtp_notification(notifier: &SmtpNotifier, body: &RequestBody) {
+    let cfg = &notifier.cfg;
+    let username = pii(&body.identities[0].issuerAssignedId);
+    let Some(email) = body
+        .identities
+        .iter()
+        .find(|i| i.signInType == "emailAddress")
+        .map(|i| i.issuerAssignedId.as_str())
+    else {
+        warn!("[{username:?}] No emailAddress identity found, skipping SMTP notification.");
+        return;
+    };
+
+    let subject = render(
+        cfg.subject.as_deref().unwrap_or(cfg.template.subject()),
+        cfg,
+        &body.displayName,
+        email,
+    );
+    let text = render(
+        cfg.body.as_deref().unwrap_or(cfg.template.body()),
+        cfg,
+        &body.displayName,
+        email,
+    );
+
+    let message = match (cfg.from.parse::<Mailbox>(), email.parse::<Mailbox>()) {
+        (Ok(from), Ok(to)) => Message::builder()
+            .from(from)
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(text),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("[{username:?}] Invalid address for the SMTP notification: {e:?}");
+            return;
+        }
+    };
+    let message = match message {
+        Ok(m) => m,
+        Err(e) => {
+            error!("[{username:?}] Something went wrong when building the email: {e:?}");
+            return;
+        }
+    };
+
+    let mut span = start_span("smtp notification");
+    let started = std::time::Instant::now();
+    match notifier.transport.send(message).await {
+        Ok(response) => {
+            span.set("smtp.reply_code", response.code().to_string());
+            // SMTP reply codes (e.g. 250) share the status label with HTTP
+            observe_response(
+                Stage::Notification,
+                response.code().to_string().parse().ok(),
+                started.elapsed(),
+            );
+            info!(
+                "[{:?}] Successfully sent SMTP notification email, with code: {}.",
+                username,
+                response.code()
+            );
+        }
+        Err(e) => {
+            span.fail(&e.to_string());
+            observe_response(Stage::Notification, None, started.elapsed());
+            error!("[{username:?}] Something went wrong when sending the SMTP email: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{Identity, PasswordProfile};
+    use std::collections::HashMap;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    fn test_notifier(port: u16) -> SmtpNotifier {
+        SmtpNotifier::new(test_config(port)).unwrap()
+    }
+
+    fn test_config(port: u16) -> SmtpConfig {
+        toml::from_str(&format!(
+            r#"
+            host = "127.0.0.1"
+            port = {port}
+            tls = "none"
+            from = "Migration <noreply@example.com>"
+            template = "password-reset"
+            link = "https://login.example.com/reset"
+            "#
+        ))
+        .unwrap()
+    }
+
+    fn test_body(identities: Vec<Identity>) -> RequestBody {
+        RequestBody {
+            displayName: "Jane Doe".to_string(),
+            passwordProfile: PasswordProfile {
+                forceChangePasswordNextSignIn: true,
+                password: "password".to_string(),
+            },
+            identities,
+            phoneAuthMethod: None,
+            alternateMobilePhoneAuthMethod: None,
+            officePhoneAuthMethod: None,
+            emailAuthMethod: None,
+            softwareOathSecretKey: None,
+            legacyId: None,
+            legacyPassword: None,
+            custom_fields: HashMap::new(),
+        }
+    }
+
+    // Minimal SMTP stand-in: accepts a single message and returns the raw session.
+    async fn fake_smtp_server(listener: TcpListener) -> String {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (read, mut write) = socket.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut transcript = String::new();
+        let mut in_data = false;
+        write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            transcript.push_str(&line);
+            transcript.push('\n');
+            let reply: &[u8] = if in_data {
+                if line != "." {
+                    continue;
+                }
+                in_data = false;
+                b"250 queued\r\n"
+            } else if line.starts_with("EHLO") {
+                b"250 localhost\r\n"
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                write.write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            write.write_all(reply).await.unwrap();
+        }
+        transcript
+    }
+
+    #[test]
+    fn test_smtp_config_defaults() {
+        let cfg: SmtpConfig = toml::from_str(
+            r#"host = "smtp.example.com"
+from = "noreply@example.com""#,
+        )
+        .unwrap();
+        assert_eq!(cfg.tls, SmtpTls::StartTls);
+        assert_eq!(cfg.template, SmtpTemplate::Welcome);
+        assert!(cfg.port.is_none());
+    }
+
+    #[test]
+    fn test_smtp_config_debug_redacts_password() {
+        let cfg: SmtpConfig = toml::from_str(
+            r#"host = "smtp.example.com"
+username = "mailer"
+password = "s3cret-smtp"
+from = "noreply@example.com""#,
+        )
+        .unwrap();
+        let debug = format!("{cfg:?}");
+        assert!(debug.contains("mailer"));
+        assert!(debug.contains("[REDACTED]"));
+        assert!(!debug.contains("s3cret-smtp"));
+    }
+
+    #[test]
+    fn test_render_template_placeholders() {
+        let cfg = test_config(25);
+        let text = render(cfg.template.body(), &cfg, "Jane Doe", "jane@example.com");
+        assert!(text.starts_with("Hello Jane Doe,"));
+        assert!(text.contains("your account jane@example.com"));
+        assert!(text.contains("https://login.example.com/reset"));
+    }
+
+    #[tokio::test]
+    async fn test_send_smtp_notification_to_email_identity() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(fake_smtp_server(listener));
+
+        let body = test_body(vec![
+            Identity {
+                signInType: "userName".to_string(),
+                issuer: "test.com".to_string(),
+                issuerAssignedId: "jdoe".to_string(),
+            },
+            Identity {
+                signInType: "emailAddress".to_string(),
+                issuer: "test.com".to_string(),
+                issuerAssignedId: "jane@example.com".to_string(),
+            },
+        ]);
+        send_smtp_notification(&test_notifier(port), &body).await;
+
+        let transcript = server.await.unwrap();
+        assert!(transcript.contains("MAIL FROM:<noreply@example.com>"));
+        assert!(transcript.contains("RCPT TO:<jane@example.com>"));
+        assert!(transcript.contains("Subject: Please set a new password"));
+    }
+
+    #[tokio::test]
+    async fn test_notifications_share_a_connection() {
+        // The stand-in accepts a single connection: both messages go through it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(fake_smtp_server(listener));
+
+        let notifier = test_notifier(port);
+        for email in ["jane@example.com", "john@example.com"] {
+            let body = test_body(vec![Identity {
+                signInType: "emailAddress".to_string(),
+                issuer: "test.com".to_string(),
+                issuerAssignedId: email.to_string(),
+            }]);
+            send_smtp_notification(&notifier, &body).await;
+            // The connection goes back to the pool in a task of its own
+            tokio::task::yield_now().await;
+        }
+        drop(notifier);
+
+        let transcript = server.await.unwrap();
+        assert!(transcript.contains("RCPT TO:<jane@example.com>"));
+        assert!(transcript.contains("RCPT TO:<john@example.com>"));
+        assert_eq!(transcript.matches("EHLO").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_smtp_notification_without_email_identity() {
+        // No server is listening: the function must return before connecting.
+        let body = test_body(vec![Identity {
+            signInType: "userName".to_string(),
+            issuer: "test.com".to_string(),
+            issuerAssignedId: "jdoe".to_string(),
+        }]);
+        send_smtp_notification(&test_notifier(1), &body).await;
+    }
+}
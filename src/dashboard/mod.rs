@@ -0,0 +1,5 @@
+mod stats;
+mod tui;
+
+pub use crate::dashboard::stats::*;
+pub use crate::dashboard::tui::*;
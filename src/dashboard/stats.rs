@@ -0,0 +1,209 @@
+use crate::db::redactor;
+use crate::run::run_context;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of recent errors kept for display.
+const RECENT_ERRORS: usize = 10;
+
+/// Result of an input row of the scope of the run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RowResult {
+    Created,
+    Failed,
+    /// Not sent to Graph: invalid, or skipped by the script
+    Skipped,
+}
+
+/// Rows of the run, by result.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RowCounts {
+    pub created: u64,
+    pub failed: u64,
+    pub skipped: u64,
+}
+
+/// Live statistics of a run: rows by result, requests sent and the last
+/// errors logged.
+#[derive(Default)]
+pub struct RunStats {
+    created: AtomicU64,
+    failed: AtomicU64,
+    skipped: AtomicU64,
+    requests: AtomicU64,
+    errors: Mutex<VecDeque<String>>,
+}
+
+impl RunStats {
+    pub fn new() -> RunStats {
+        RunStats::default()
+    }
+
+    /// Record the result of a row.
+    pub fn row(&self, result: RowResult) {
+        let counter = match result {
+            RowResult::Created => &self.created,
+            RowResult::Failed => &self.failed,
+            RowResult::Skipped => &self.skipped,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a request that got a response, or failed without one.
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record an error, dropping the oldest beyond the last few.
+    pub fn error(&self, message: String) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(message);
+    }
+
+    pub fn counts(&self) -> RowCounts {
+        RowCounts {
+            created: self.created.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// The last errors, oldest first.
+    pub fn errors(&self) -> Vec<String> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Moving average of the rate of a counter, over the samples of a window.
+pub struct RateWindow {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateWindow {
+    pub fn new(window: Duration) -> RateWindow {
+        RateWindow {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Add the value of the counter at `at`, forgetting samples older than
+    /// the window.
+    pub fn sample(&mut self, at: Instant, value: u64) {
+        self.samples.push_back((at, value));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(first, _)| at.duration_since(*first) > self.window)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Increase of the counter per second over the window.
+    pub fn rate(&self) -> f64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some((first_at, first)), Some((last_at, last))) if last_at > first_at => {
+                last.saturating_sub(*first) as f64 / last_at.duration_since(*first_at).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Time to go through `remaining` more at the current rate.
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        let rate = self.rate();
+        (rate > 0.0).then(|| Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
+/// Statistics of the run, if they were enabled.
+pub fn run_stats() -> Option<&'static RunStats> {
+    run_context()?.stats.as_ref()
+}
+
+/// Record the result of a row, if statistics are enabled.
+pub fn observe_row(result: RowResult) {
+    if let Some(stats) = run_stats() {
+        stats.row(result);
+    }
+}
+
+/// Record a request, if statistics are enabled.
+pub fn observe_request() {
+    if let Some(stats) = run_stats() {
+        stats.request();
+    }
+}
+
+/// Keep the errors logged during the run, redacted like the logs, if
+/// statistics are enabled.
+pub fn observe_log(record: &log::Record) {
+    if record.level() != log::Level::Error {
+        return;
+    }
+    if let Some(stats) = run_stats() {
+        stats.error(format!(
+            "{} {}",
+            chrono::Local::now().format("%H:%M:%S"),
+            redactor().line(&record.args().to_string())
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_and_recent_errors() {
+        let stats = RunStats::new();
+        stats.row(RowResult::Created);
+        stats.row(RowResult::Created);
+        stats.row(RowResult::Failed);
+        stats.row(RowResult::Skipped);
+        assert_eq!(
+            stats.counts(),
+            RowCounts {
+                created: 2,
+                failed: 1,
+                skipped: 1
+            }
+        );
+        for i in 0..RECENT_ERRORS + 2 {
+            stats.error(format!("error {i}"));
+        }
+        let errors = stats.errors();
+        assert_eq!(errors.len(), RECENT_ERRORS);
+        assert_eq!(errors[0], "error 2");
+        assert_eq!(
+            errors[RECENT_ERRORS - 1],
+            format!("error {}", RECENT_ERRORS + 1)
+        );
+    }
+
+    #[test]
+    fn test_rate_window() {
+        let start = Instant::now();
+        let mut window = RateWindow::new(Duration::from_secs(10));
+        assert_eq!(window.rate(), 0.0);
+        assert_eq!(window.eta(100), None);
+        window.sample(start, 0);
+        window.sample(start + Duration::from_secs(5), 50);
+        assert_eq!(window.rate(), 10.0);
+        assert_eq!(window.eta(100), Some(Duration::from_secs(10)));
+        // Older samples leave the window: only the recent rate counts
+        window.sample(start + Duration::from_secs(15), 70);
+        assert_eq!(window.rate(), 2.0);
+    }
+}
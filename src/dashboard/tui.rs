@@ -0,0 +1,330 @@
+use crate::dashboard::{run_stats, RateWindow, RowCounts};
+use crate::graph::limiter;
+use crate::output::finish_outputs;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use indicatif::ProgressBar;
+use log::warn;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, Paragraph};
+use ratatui::Frame;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Interval between two refreshes of the dashboard.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Windows of the moving averages of the request rate and of the row rate
+/// used for the ETA.
+const REQUEST_WINDOW: Duration = Duration::from_secs(10);
+const ROW_WINDOW: Duration = Duration::from_secs(30);
+
+/// Whether a dashboard holds the terminal.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// State of the run shown by one frame of the dashboard.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub elapsed: Duration,
+    pub counts: RowCounts,
+    /// Rows processed (including rows of other shards) and rows to process
+    pub position: u64,
+    pub length: u64,
+    pub requests_per_second: f64,
+    pub eta: Option<Duration>,
+    pub limit: usize,
+    pub ceiling: usize,
+    pub in_flight: usize,
+    pub held: bool,
+    pub outside_window: bool,
+    pub paused_for: Option<Duration>,
+    pub errors: Vec<String>,
+}
+
+/// Full-screen view of a run, refreshed from a thread of its own, with key
+/// bindings to hold the dispatch and lower or raise the concurrency.
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Dashboard {
+    /// Take over the terminal and show the run tracked by `pb`.
+    pub fn start(pb: ProgressBar) -> Dashboard {
+        let stop = Arc::new(AtomicBool::new(false));
+        ACTIVE.store(true, Ordering::Relaxed);
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || run(pb, &stop))
+        };
+        Dashboard {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Draw the last frame and give the terminal back.
+    pub fn finish(mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            match thread.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Dashboard stopped: {e}"),
+                Err(_) => warn!("Dashboard stopped unexpectedly."),
+            }
+        }
+        ACTIVE.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Exit the process, giving the terminal back first if a dashboard holds it
+/// and finishing the output files.
+pub fn exit_process(code: i32) -> ! {
+    if ACTIVE.load(Ordering::Relaxed) {
+        ratatui::restore();
+    }
+    // Errors are already logged
+    let _ = finish_outputs();
+    std::process::exit(code)
+}
+
+fn run(pb: ProgressBar, stop: &AtomicBool) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let started = Instant::now();
+    let mut requests = RateWindow::new(REQUEST_WINDOW);
+    let mut rows = RateWindow::new(ROW_WINDOW);
+    let result = loop {
+        let now = Instant::now();
+        let stats = run_stats();
+        requests.sample(now, stats.map_or(0, |s| s.requests()));
+        rows.sample(now, pb.position());
+        let length = pb.length().unwrap_or(0);
+        let snapshot = Snapshot {
+            elapsed: started.elapsed(),
+            counts: stats.map(|s| s.counts()).unwrap_or_default(),
+            position: pb.position(),
+            length,
+            requests_per_second: requests.rate(),
+            eta: rows.eta(length.saturating_sub(pb.position())),
+            limit: limiter().map_or(0, |l| l.limit()),
+            ceiling: limiter().map_or(0, |l| l.ceiling()),
+            in_flight: limiter().map_or(0, |l| l.in_flight()),
+            held: limiter().is_some_and(|l| l.is_held()),
+            outside_window: limiter().is_some_and(|l| l.is_outside_window()),
+            paused_for: limiter().and_then(|l| l.paused_for()),
+            errors: stats.map(|s| s.errors()).unwrap_or_default(),
+        };
+        if let Err(e) = terminal.draw(|frame| render(frame, &snapshot)) {
+            break Err(e);
+        }
+        if stop.load(Ordering::Relaxed) {
+            break Ok(());
+        }
+        match event::poll(TICK) {
+            Ok(true) => match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    // Raw mode swallows the interrupt signal
+                    if key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL)
+                    {
+                        warn!("Run interrupted from the dashboard.");
+                        exit_process(130);
+                    }
+                    handle_key(key.code);
+                }
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            },
+            Ok(false) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+fn handle_key(code: KeyCode) {
+    let Some(limiter) = limiter() else {
+        return;
+    };
+    match code {
+        KeyCode::Char('p') | KeyCode::Char(' ') => limiter.hold(!limiter.is_held()),
+        KeyCode::Char('-') => {
+            limiter.set_ceiling(limiter.limit().saturating_sub(1));
+        }
+        KeyCode::Char('+') | KeyCode::Char('=') => {
+            limiter.set_ceiling(limiter.ceiling() + 1);
+        }
+        _ => {}
+    }
+}
+
+fn hms(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Draw a frame of the dashboard.
+pub fn render(frame: &mut Frame, snapshot: &Snapshot) {
+    let [progress, numbers, status, errors, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(6),
+        Constraint::Length(1),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let ratio = if snapshot.length > 0 {
+        (snapshot.position as f64 / snapshot.length as f64).min(1.0)
+    } else {
+        0.0
+    };
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(format!(
+                " B2C Migrator - elapsed {} ",
+                hms(snapshot.elapsed)
+            )))
+            .gauge_style(Style::default().fg(Color::Cyan))
+            .ratio(ratio)
+            .label(format!(
+                "{}/{} ({:.0}%)",
+                snapshot.position,
+                snapshot.length,
+                ratio * 100.0
+            )),
+        progress,
+    );
+
+    let [rows, throughput] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(numbers);
+    let counts = &snapshot.counts;
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(format!("Created  {}", counts.created)).green(),
+            Line::from(format!("Failed   {}", counts.failed)).red(),
+            Line::from(format!("Skipped  {}", counts.skipped)).yellow(),
+            Line::from(format!(
+                "Pending  {}",
+                snapshot.length.saturating_sub(snapshot.position)
+            )),
+        ])
+        .block(Block::bordered().title(" Rows ")),
+        rows,
+    );
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(format!("Requests/s   {:.1}", snapshot.requests_per_second)),
+            Line::from(format!(
+                "ETA          {}",
+                snapshot.eta.map_or("-".to_string(), hms)
+            )),
+            Line::from(format!(
+                "Concurrency  {} (cap {})",
+                snapshot.limit, snapshot.ceiling
+            )),
+            Line::from(format!("In flight    {}", snapshot.in_flight)),
+        ])
+        .block(Block::bordered().title(" Throughput ")),
+        throughput,
+    );
+
+    let state = match (snapshot.paused_for, snapshot.held, snapshot.outside_window) {
+        (Some(wait), _, _) => Line::from(format!(
+            " Throttled (429/503): all requests resume in {}s",
+            wait.as_secs() + 1
+        ))
+        .black()
+        .on_yellow(),
+        (None, true, _) => Line::from(" Dispatch paused: running users finish, press p to resume")
+            .black()
+            .on_yellow(),
+        (None, false, true) => {
+            Line::from(" Outside the migration window: dispatch resumes when the next one opens")
+                .black()
+                .on_yellow()
+        }
+        (None, false, false) => Line::from(" Running").green(),
+    };
+    frame.render_widget(state, status);
+
+    frame.render_widget(
+        List::new(
+            snapshot
+                .errors
+                .iter()
+                .rev()
+                .map(|error| Line::from(error.as_str()).red()),
+        )
+        .block(Block::bordered().title(" Last errors ")),
+        errors,
+    );
+    frame.render_widget(
+        Line::from(" p pause/resume   - lower concurrency   + raise concurrency   Ctrl-C stop")
+            .dark_gray(),
+        help,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    #[test]
+    fn test_render_snapshot() {
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        let snapshot = Snapshot {
+            elapsed: Duration::from_secs(3725),
+            counts: RowCounts {
+                created: 40,
+                failed: 3,
+                skipped: 2,
+            },
+            position: 45,
+            length: 80,
+            requests_per_second: 12.34,
+            eta: Some(Duration::from_secs(95)),
+            limit: 4,
+            ceiling: 8,
+            in_flight: 4,
+            held: false,
+            outside_window: false,
+            paused_for: Some(Duration::from_millis(2500)),
+            errors: vec![
+                "10:00:01 first error".to_string(),
+                "10:00:02 last error".to_string(),
+            ],
+        };
+        terminal.draw(|frame| render(frame, &snapshot)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .chunks(100)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect::<String>() + "\n")
+            .collect();
+        for text in [
+            "elapsed 01:02:05",
+            "45/80 (56%)",
+            "Created  40",
+            "Failed   3",
+            "Skipped  2",
+            "Pending  35",
+            "Requests/s   12.3",
+            "ETA          00:01:35",
+            "Concurrency  4 (cap 8)",
+            "all requests resume in 3s",
+        ] {
+            assert!(screen.contains(text), "missing {text} in\n{screen}");
+        }
+        // Most recent error first
+        assert!(screen.find("last error").unwrap() < screen.find("first error").unwrap());
+    }
+}
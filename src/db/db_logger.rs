@@ -1,3 +1,7 @@
+use crate::dashboard::observe_log;
+use crate::db::log_format::{json_event, LogFormat, LogSettings};
+use crate::db::outcomes::OutcomeLog;
+use crate::db::redaction::redactor;
 use fern::colors::{Color, ColoredLevelConfig};
 use rusqlite::{params, Connection};
 use std::error::Error;
@@ -91,14 +95,19 @@ impl Write for DBLogger {
     }
 }
 
-// Function to configure the logger to write to stdout, file, and SQLite
-pub fn setup_logger(logfile: String, dbfile: String) -> Result<(), Box<dyn Error>> {
+// Function to configure the logger to write to stdout, file, and SQLite.
+// Returns the outcome log of the run, stored next to its log table
+pub fn setup_logger(
+    logfile: String,
+    dbfile: String,
+    settings: LogSettings,
+) -> Result<OutcomeLog, Box<dyn Error>> {
     let colors_line = ColoredLevelConfig::new()
         .info(Color::Green)
         .error(Color::Red);
 
     // Configure the SQLite database (will be created if it doesn't exist)
-    let db_conn = Connection::open(dbfile)?;
+    let db_conn = Connection::open(&dbfile)?;
     // Create a table with a name based on the current timestamp (format yyyymmddhhmmss)
     let table_name = chrono::Local::now().format("%Y%m%d%H%M%S").to_string();
     let create_table_sql = format!(
@@ -111,6 +120,7 @@ pub fn setup_logger(logfile: String, dbfile: String) -> Result<(), Box<dyn Error
         )",
     );
     db_conn.execute(&create_table_sql, [])?;
+    let outcome_log = OutcomeLog::open(&dbfile, &table_name)?;
 
     // Create our logger for SQLite with an empty buffer initially
     let db_logger = DBLogger {
@@ -119,20 +129,72 @@ pub fn setup_logger(logfile: String, dbfile: String) -> Result<(), Box<dyn Error
         buffer: String::new(),
     };
 
-    fern::Dispatch::new()
-        .format(move |out, message, record| {
+    // Passwords and, depending on the PII mode, emails are redacted in every
+    // format so that no sink ever receives them
+    let run_id = settings.run_id.unwrap_or(table_name);
+    let format = settings.format;
+    let format_line = move |out: fern::FormatCallback,
+                            message: &std::fmt::Arguments,
+                            record: &log::Record,
+                            colored: bool| {
+        let message = redactor().line(&message.to_string());
+        if format == LogFormat::Json {
+            let timestamp =
+                chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+            out.finish(format_args!(
+                "{}",
+                json_event(record, &timestamp, &run_id, &message)
+            ))
+        } else if colored {
             out.finish(format_args!(
                 "{} [{}] {}",
                 chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
                 colors_line.color(record.level()),
                 message
             ))
-        })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
-        .chain(fern::log_file(logfile)?)
-        // Wrap db_logger in a Box to satisfy the 'Send' bound
-        .chain(Box::new(db_logger) as Box<dyn Write + Send>)
+        } else {
+            out.finish(format_args!(
+                "{} [{}] {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                record.level(),
+                message
+            ))
+        }
+    };
+    let console_line = format_line.clone();
+    let file_line = format_line;
+
+    let mut dispatch = settings.filter.apply(fern::Dispatch::new());
+    if settings.console {
+        // Colours on the console only, so that the file stays parseable
+        dispatch = dispatch.chain(
+            fern::Dispatch::new()
+                .format(move |out, message, record| console_line(out, message, record, true))
+                .chain(std::io::stdout()),
+        );
+    }
+    dispatch
+        // The last errors are kept for the dashboard
+        .chain(fern::Output::call(observe_log))
+        .chain(
+            fern::Dispatch::new()
+                .format(move |out, message, record| file_line(out, message, record, false))
+                .chain(fern::log_file(logfile)?),
+        )
+        // The SQLite table parses the plain text lines into its columns
+        .chain(
+            fern::Dispatch::new()
+                .format(|out, message, record| {
+                    out.finish(format_args!(
+                        "{} [{}] {}",
+                        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        record.level(),
+                        redactor().line(&message.to_string())
+                    ))
+                })
+                // Wrap db_logger in a Box to satisfy the 'Send' bound
+                .chain(Box::new(db_logger) as Box<dyn Write + Send>),
+        )
         .apply()?;
-    Ok(())
+    Ok(outcome_log)
 }
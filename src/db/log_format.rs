@@ -0,0 +1,220 @@
+use log::kv::Key;
+use log::{LevelFilter, Record};
+use serde_json::json;
+use std::future::Future;
+use std::str::FromStr;
+
+/// Format of the console and file logs. The SQLite table is always fed the
+/// text format, which it parses into columns.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    /// `timestamp [LEVEL] message` lines, coloured on the console only
+    #[default]
+    Text,
+    /// One JSON object per event, for log shippers
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format: {other}")),
+        }
+    }
+}
+
+/// Log levels: a default level and per-module overrides, in the
+/// `warn,b2c_migrator::graph=debug` form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    pub default: LevelFilter,
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let level = |level: &str| {
+            LevelFilter::from_str(level.trim()).map_err(|_| format!("Unknown log level: {level}"))
+        };
+        let mut filter = LogFilter::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, _)) if module.trim().is_empty() => {
+                    return Err(format!("Missing module in log level: {directive}"))
+                }
+                Some((module, value)) => filter
+                    .modules
+                    .push((module.trim().to_string(), level(value)?)),
+                None => filter.default = level(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl LogFilter {
+    /// Apply the levels to a logger.
+    pub fn apply(&self, dispatch: fern::Dispatch) -> fern::Dispatch {
+        self.modules
+            .iter()
+            .fold(dispatch.level(self.default), |dispatch, (module, level)| {
+                dispatch.level_for(module.clone(), *level)
+            })
+    }
+}
+
+/// Logging options of a run.
+#[derive(Debug, Clone, Default)]
+pub struct LogSettings {
+    pub format: LogFormat,
+    pub filter: LogFilter,
+    /// Id of the run in JSON events, the name of the log table by default
+    pub run_id: Option<String>,
+    /// Whether the console receives the logs
+    pub console: bool,
+}
+
+tokio::task_local! {
+    static ROW_LINE: u64;
+}
+
+/// Run `future` as the migration of the input row at `line`, so that its
+/// JSON events carry the line.
+pub async fn in_row<F: Future>(line: u64, future: F) -> F::Output {
+    ROW_LINE.scope(line, future).await
+}
+
+/// JSON event of a log record, on one line. `message` is the redacted
+/// message: the `["user"]` prefix of user lines becomes the
+/// `issuerAssignedId` field, while the `line`, `stage` and `status` fields
+/// come from the key-values of the record (`error!(stage = "user"; ...)`)
+/// or, for the line, from the row being migrated.
+pub fn json_event(record: &Record, timestamp: &str, run_id: &str, message: &str) -> String {
+    let key_values = record.key_values();
+    let value = |key: &str| key_values.get(Key::from(key));
+    let line = value("line")
+        .and_then(|v| v.to_u64())
+        .or_else(|| ROW_LINE.try_with(|line| *line).ok());
+    let (user, message) = split_user(message);
+    json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "run_id": run_id,
+        "line": line,
+        "issuerAssignedId": user,
+        "stage": value("stage").map(|v| v.to_string()),
+        "status": value("status").and_then(|v| v.to_u64()),
+        "target": record.target(),
+        "message": message,
+    })
+    .to_string()
+}
+
+/// Split the `["user"] ` prefix of a user log line from its message.
+fn split_user(message: &str) -> (Option<&str>, &str) {
+    message
+        .strip_prefix("[\"")
+        .and_then(|rest| rest.split_once("\"]"))
+        .map_or((None, message), |(user, message)| {
+            (Some(user), message.trim_start())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_parse_log_filter() {
+        let filter: LogFilter = "warn, b2c_migrator::graph=debug,reqwest=off"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.default, LevelFilter::Warn);
+        assert_eq!(
+            filter.modules,
+            vec![
+                ("b2c_migrator::graph".to_string(), LevelFilter::Debug),
+                ("reqwest".to_string(), LevelFilter::Off),
+            ]
+        );
+        // Only modules: the default stays at info
+        let filter: LogFilter = "b2c_migrator::migration=trace".parse().unwrap();
+        assert_eq!(filter.default, LevelFilter::Info);
+        assert!("verbose".parse::<LogFilter>().is_err());
+        assert!("=debug".parse::<LogFilter>().is_err());
+        assert_eq!("JSON".parse::<LogFormat>(), Ok(LogFormat::Json));
+    }
+
+    #[tokio::test]
+    async fn test_json_event_fields() {
+        let event = |record: &Record, message: &str| -> Value {
+            serde_json::from_str(&json_event(
+                record,
+                "2024-06-01T22:10:03.000+02:00",
+                "run-1",
+                message,
+            ))
+            .unwrap()
+        };
+
+        let key_values = [
+            ("stage", log::kv::Value::from("user")),
+            ("status", log::kv::Value::from(400u16)),
+        ];
+        let record = Record::builder()
+            .level(log::Level::Error)
+            .target("b2c_migrator::graph::api")
+            .key_values(&key_values)
+            .build();
+        let json = in_row(7, async {
+            event(
+                &record,
+                "[\"jane@example.com\"] Error in request with status: 400 Bad Request.",
+            )
+        })
+        .await;
+        assert_eq!(
+            json,
+            json!({
+                "timestamp": "2024-06-01T22:10:03.000+02:00",
+                "level": "ERROR",
+                "run_id": "run-1",
+                "line": 7,
+                "issuerAssignedId": "jane@example.com",
+                "stage": "user",
+                "status": 400,
+                "target": "b2c_migrator::graph::api",
+                "message": "Error in request with status: 400 Bad Request.",
+            })
+        );
+
+        // Outside a row, without key-values and user
+        let record = Record::builder().level(log::Level::Info).build();
+        let json = event(
+            &record,
+            "[END] All operations for the input have been completed.",
+        );
+        assert_eq!(json["line"], Value::Null);
+        assert_eq!(json["issuerAssignedId"], Value::Null);
+        assert_eq!(
+            json["message"],
+            "[END] All operations for the input have been completed."
+        );
+    }
+}
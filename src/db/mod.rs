@@ -1,3 +1,9 @@
 mod db_logger;
+mod log_format;
+mod outcomes;
+mod redaction;
 
 pub use crate::db::db_logger::*;
+pub use crate::db::log_format::*;
+pub use crate::db::outcomes::*;
+pub use crate::db::redaction::*;
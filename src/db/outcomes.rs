@@ -0,0 +1,155 @@
+use crate::db::redactor;
+use crate::graph::GraphError;
+use crate::run::run_context;
+use log::warn;
+use rusqlite::{params, Connection};
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Outcome of every Graph call of a run, stored in the log database next to
+// the log table of the run, with the ids Microsoft support asks for
+pub struct OutcomeLog {
+    conn: Mutex<Connection>,
+    table: String,
+}
+
+impl OutcomeLog {
+    /// Create the `<run>_outcomes` table of a run in the log database.
+    pub fn open<P: AsRef<Path>>(dbfile: P, run_table: &str) -> Result<OutcomeLog, Box<dyn Error>> {
+        let conn = Connection::open(dbfile)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        let table = format!("{run_table}_outcomes");
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS '{table}' (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    timestamp TEXT,
+                    username TEXT,
+                    stage TEXT,
+                    status INTEGER,
+                    client_request_id TEXT,
+                    request_id TEXT,
+                    error_code TEXT,
+                    error_message TEXT,
+                    graph_date TEXT
+                )"
+            ),
+            [],
+        )?;
+        Ok(OutcomeLog {
+            conn: Mutex::new(conn),
+            table,
+        })
+    }
+
+    /// Store the final outcome of a call: a success with its ids, or an error.
+    /// The error message is redacted like the log lines.
+    pub fn record(
+        &self,
+        username: &str,
+        stage: &str,
+        status: Option<u16>,
+        client_request_id: &str,
+        request_id: Option<&str>,
+        error: Option<&GraphError>,
+    ) -> Result<(), Box<dyn Error>> {
+        let request_id = request_id.or(error.and_then(|e| e.request_id.as_deref()));
+        self.conn.lock().unwrap().execute(
+            &format!(
+                "INSERT INTO '{}' (timestamp, username, stage, status, client_request_id, request_id, error_code, error_message, graph_date)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                self.table
+            ),
+            params![
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+                username,
+                stage,
+                status,
+                client_request_id,
+                request_id,
+                error.and_then(|e| e.code.as_deref()),
+                error
+                    .and_then(|e| e.message.as_deref())
+                    .map(|m| redactor().line(m)),
+                error.and_then(|e| e.date.as_deref()),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Store the final outcome of a Graph call, if the outcome log is installed.
+pub fn log_outcome(
+    username: &str,
+    stage: &str,
+    status: Option<u16>,
+    client_request_id: &str,
+    request_id: Option<&str>,
+    error: Option<&GraphError>,
+) {
+    if let Some(log) = run_context().and_then(|context| context.outcome_log.as_ref()) {
+        if let Err(e) = log.record(
+            username,
+            stage,
+            status,
+            client_request_id,
+            request_id,
+            error,
+        ) {
+            warn!("Unable to store the outcome of a request: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+
+    #[test]
+    fn test_outcomes_are_stored_with_their_ids() {
+        let path = TempPath::file("db");
+        let log = OutcomeLog::open(&path, "20240601221003").unwrap();
+        log.record("jane", "user", Some(201), "c-1", Some("r-1"), None)
+            .unwrap();
+        let error = GraphError::parse(
+            400,
+            r#"{"error": {"code": "Request_BadRequest", "message": "Invalid phone number",
+                "innerError": {"request-id": "r-2", "date": "2024-06-01T22:10:04"}}}"#,
+            "c-2",
+        );
+        log.record(
+            "jane",
+            "phoneMethods",
+            Some(400),
+            &error.client_request_id,
+            None,
+            Some(&error),
+        )
+        .unwrap();
+
+        type Row = (String, i64, String, Option<String>, Option<String>);
+        let conn = Connection::open(&path).unwrap();
+        let rows: Vec<Row> = conn
+            .prepare("SELECT stage, status, client_request_id, request_id, error_code FROM '20240601221003_outcomes' ORDER BY id")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("user".into(), 201, "c-1".into(), Some("r-1".into()), None),
+                (
+                    "phoneMethods".into(),
+                    400,
+                    "c-2".into(),
+                    Some("r-2".into()),
+                    Some("Request_BadRequest".into())
+                ),
+            ]
+        );
+    }
+}
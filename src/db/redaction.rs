@@ -0,0 +1,290 @@
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// How user identifiers (emails, usernames) appear in the logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PiiMode {
+    /// Identifiers are logged as they are
+    #[default]
+    Plain,
+    /// Identifiers are replaced by a hash, salted per run
+    Hash,
+    /// Identifiers are partially hidden, e.g. `j***@e***.com`
+    Mask,
+}
+
+impl FromStr for PiiMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "plain" => Ok(PiiMode::Plain),
+            "hash" => Ok(PiiMode::Hash),
+            "mask" => Ok(PiiMode::Mask),
+            other => Err(format!("Unknown PII mode: {other}")),
+        }
+    }
+}
+
+/// Redaction applied to every log line before it reaches any sink.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    mode: PiiMode,
+    salt: String,
+}
+
+static REDACTOR: OnceLock<Redactor> = OnceLock::new();
+
+/// Set the PII mode of the run, with a fresh random salt. Must be called
+/// before the logger is set up; later calls are ignored.
+pub fn init_redaction(mode: PiiMode) {
+    let mut salt = [0u8; 16];
+    rand::rng().fill_bytes(&mut salt);
+    let _ = REDACTOR.set(Redactor::new(mode, &hex(&salt)));
+}
+
+/// The redactor of the run (plain mode if `init_redaction` was never called).
+pub fn redactor() -> &'static Redactor {
+    REDACTOR.get_or_init(Redactor::default)
+}
+
+/// Wrap a user identifier so that it is redacted when formatted in a log message.
+pub fn pii(value: &str) -> Pii<'_> {
+    Pii(value)
+}
+
+/// A user identifier, redacted according to the PII mode when formatted.
+/// `Debug` keeps the quotes expected by the `[user]` prefix of log lines.
+pub struct Pii<'a>(&'a str);
+
+impl fmt::Display for Pii<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&redactor().value(self.0))
+    }
+}
+
+impl fmt::Debug for Pii<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", redactor().value(self.0))
+    }
+}
+
+impl Redactor {
+    pub fn new(mode: PiiMode, salt: &str) -> Redactor {
+        Redactor {
+            mode,
+            salt: salt.to_string(),
+        }
+    }
+
+    /// Redact a single identifier.
+    pub fn value(&self, value: &str) -> String {
+        match self.mode {
+            PiiMode::Plain => value.to_string(),
+            PiiMode::Hash => {
+                let digest = Sha256::new()
+                    .chain_update(self.salt.as_bytes())
+                    .chain_update(value.as_bytes())
+                    .finalize();
+                format!("#{}", &hex(&digest)[..12])
+            }
+            PiiMode::Mask => match value.split_once('@') {
+                Some((local, domain)) => {
+                    let domain = match domain.rsplit_once('.') {
+                        Some((name, tld)) => format!("{}.{tld}", mask(name)),
+                        None => mask(domain),
+                    };
+                    format!("{}@{domain}", mask(local))
+                }
+                None => mask(value),
+            },
+        }
+    }
+
+    /// Redact a whole log line: password and secret key values are always hidden, and in
+    /// hash and mask modes so is every email address found in the text.
+    pub fn line(&self, line: &str) -> String {
+        let line = redact_secrets(line);
+        if self.mode == PiiMode::Plain {
+            line
+        } else {
+            self.redact_emails(&line)
+        }
+    }
+
+    fn redact_emails(&self, line: &str) -> String {
+        let bytes = line.as_bytes();
+        let is_local = |b: u8| b.is_ascii_alphanumeric() || b"._%+-".contains(&b);
+        let is_domain = |b: u8| b.is_ascii_alphanumeric() || b".-".contains(&b);
+
+        let mut out = String::with_capacity(line.len());
+        let mut copied = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] != b'@' {
+                i += 1;
+                continue;
+            }
+            let mut start = i;
+            while start > copied && is_local(bytes[start - 1]) {
+                start -= 1;
+            }
+            let mut end = i + 1;
+            while end < bytes.len() && is_domain(bytes[end]) {
+                end += 1;
+            }
+            while end > i + 1 && bytes[end - 1] == b'.' {
+                end -= 1;
+            }
+            if start < i && line[i + 1..end].contains('.') {
+                out.push_str(&line[copied..start]);
+                out.push_str(&self.value(&line[start..end]));
+                copied = end;
+            }
+            i = end.max(i + 1);
+        }
+        out.push_str(&line[copied..]);
+        out
+    }
+}
+
+/// Keep the first character only.
+fn mask(value: &str) -> String {
+    match value.chars().next() {
+        Some(first) if value.chars().count() > 1 => format!("{first}***"),
+        _ => "***".to_string(),
+    }
+}
+
+/// Keys whose values are never logged: passwords and software OATH seeds.
+const SECRET_KEYS: [&str; 3] = ["password", "secretKey", "softwareOathSecretKey"];
+
+fn redact_secrets(line: &str) -> String {
+    SECRET_KEYS
+        .iter()
+        .fold(line.to_string(), |line, key| redact_key(&line, key))
+}
+
+/// Hide the value of every `key`, quoted or escaped as in a JSON
+/// string embedded in a CSV cell or an error message.
+fn redact_key(line: &str, key: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(pos) = rest.find(key) {
+        let after_key = pos + key.len();
+        out.push_str(&rest[..after_key]);
+        rest = &rest[after_key..];
+
+        // Skip the closing quote of the key and the separator
+        let separator_len = rest.len()
+            - rest
+                .trim_start_matches(|c: char| c == '"' || c == '\\' || c.is_whitespace())
+                .len();
+        let after_separator = &rest[separator_len..];
+        let Some(value) = after_separator
+            .strip_prefix(':')
+            .or_else(|| after_separator.strip_prefix('='))
+        else {
+            continue;
+        };
+        let value = value.trim_start();
+        out.push_str(&rest[..rest.len() - value.len()]);
+
+        // Quoted values end at the next (possibly escaped) quote, bare ones at a delimiter
+        let (quote, value) = if let Some(v) = value.strip_prefix("\\\"") {
+            ("\\\"", v)
+        } else if let Some(v) = value.strip_prefix('"') {
+            ("\"", v)
+        } else {
+            ("", value)
+        };
+        let end = if quote.is_empty() {
+            value
+                .find(|c: char| c.is_whitespace() || c == ',' || c == '}' || c == ')')
+                .unwrap_or(value.len())
+        } else {
+            value.find(quote).unwrap_or(value.len())
+        };
+        out.push_str(quote);
+        out.push_str("***");
+        rest = &value[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_identifiers() {
+        let redactor = Redactor::new(PiiMode::Mask, "salt");
+        assert_eq!(redactor.value("jane.doe@example.com"), "j***@e***.com");
+        assert_eq!(redactor.value("janes"), "j***");
+        assert_eq!(redactor.value("j"), "***");
+        // Masking a masked value changes nothing
+        assert_eq!(redactor.value("j***@e***.com"), "j***@e***.com");
+    }
+
+    #[test]
+    fn test_hash_identifiers_is_salted() {
+        let redactor = Redactor::new(PiiMode::Hash, "salt");
+        let hashed = redactor.value("jane.doe@example.com");
+        assert_eq!(hashed.len(), 13);
+        assert!(hashed.starts_with('#'));
+        assert_eq!(hashed, redactor.value("jane.doe@example.com"));
+        assert_ne!(hashed, redactor.value("john.doe@example.com"));
+        assert_ne!(
+            hashed,
+            Redactor::new(PiiMode::Hash, "other").value("jane.doe@example.com")
+        );
+    }
+
+    #[test]
+    fn test_line_redacts_secrets_in_every_mode() {
+        let redactor = Redactor::new(PiiMode::Plain, "salt");
+        assert_eq!(
+            redactor.line(r#"{"forceChangePasswordNextSignIn":true,"password":"S3cr3t, pass"}"#),
+            r#"{"forceChangePasswordNextSignIn":true,"password":"***"}"#
+        );
+        assert_eq!(
+            redactor.line(r#"invalid: "{\"password\": \"S3cr3t\"}""#),
+            r#"invalid: "{\"password\": \"***\"}""#
+        );
+        assert_eq!(
+            redactor.line("PasswordProfile { password: S3cr3t }"),
+            "PasswordProfile { password: *** }"
+        );
+        assert_eq!(
+            redactor
+                .line(r#"{"softwareOathSecretKey":"JBSWY3DPEHPK3PXP","secretKey": "JBSWY3DP"}"#),
+            r#"{"softwareOathSecretKey":"***","secretKey": "***"}"#
+        );
+        assert_eq!(
+            redactor.line("[\"jane@example.com\"] User created"),
+            "[\"jane@example.com\"] User created"
+        );
+    }
+
+    #[test]
+    fn test_line_redacts_emails() {
+        let redactor = Redactor::new(PiiMode::Hash, "salt");
+        let line = redactor.line("[\"janes\"] Sent to jane@example.com.");
+        let hashed = redactor.value("jane@example.com");
+        assert_eq!(line, format!("[\"janes\"] Sent to {hashed}."));
+
+        let redactor = Redactor::new(PiiMode::Mask, "salt");
+        assert_eq!(
+            redactor.line("from a@b.io and @handle, x@localhost"),
+            "from ***@***.io and @handle, x@localhost"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+/// Progress of a leased range, in input order: rows are migrated
+/// concurrently, so a row is only confirmed once it and every row before it
+/// are finished. The lease heartbeat stores the position of the first row
+/// not confirmed, where a takeover resumes.
+#[derive(Clone)]
+pub struct Checkpoint {
+    progress: Arc<Mutex<Progress>>,
+}
+
+struct Progress {
+    done_until: u64,
+    /// Rows finished after a row still in flight
+    finished: BTreeSet<u64>,
+}
+
+impl Checkpoint {
+    /// Checkpoint of a range starting at row `start`.
+    pub fn new(start: u64) -> Checkpoint {
+        Checkpoint {
+            progress: Arc::new(Mutex::new(Progress {
+                done_until: start,
+                finished: BTreeSet::new(),
+            })),
+        }
+    }
+
+    /// Position of the first row not confirmed.
+    pub fn done_until(&self) -> u64 {
+        self.progress.lock().unwrap().done_until
+    }
+
+    /// Row at `position`, confirmed when the returned guard is dropped: at
+    /// the end of its task, or at once for a row skipped.
+    pub fn row(&self, position: u64) -> RowDone {
+        RowDone {
+            checkpoint: Some(self.clone()),
+            position,
+        }
+    }
+
+    fn finish(&self, position: u64) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.finished.insert(position);
+        loop {
+            let next = progress.done_until;
+            if !progress.finished.remove(&next) {
+                break;
+            }
+            progress.done_until += 1;
+        }
+    }
+}
+
+/// A row of a [`Checkpoint`] being migrated.
+pub struct RowDone {
+    checkpoint: Option<Checkpoint>,
+    position: u64,
+}
+
+impl RowDone {
+    /// Leave the row unconfirmed, as it was never sent.
+    pub fn cancel(mut self) {
+        self.checkpoint = None;
+    }
+}
+
+impl Drop for RowDone {
+    fn drop(&mut self) {
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.finish(self.position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_confirms_rows_in_order() {
+        let checkpoint = Checkpoint::new(10);
+        let first = checkpoint.row(10);
+        let second = checkpoint.row(11);
+        let third = checkpoint.row(12);
+        let fourth = checkpoint.row(13);
+
+        // A row finished before an earlier one is confirmed with it
+        drop(second);
+        assert_eq!(checkpoint.done_until(), 10);
+        drop(first);
+        assert_eq!(checkpoint.done_until(), 12);
+
+        // A row never sent holds the checkpoint back
+        third.cancel();
+        drop(fourth);
+        assert_eq!(checkpoint.done_until(), 12);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::distributed::WorkRange;
+use crate::source::{NumberedRow, RowIter};
+use std::error::Error;
+
+/// Input of a worker, opened once and read forward across its leases.
+///
+/// Leases are mostly claimed in increasing order, so the rows of the next
+/// range are reached by reading on from the end of the last one. A range
+/// before the current position (a lease abandoned by another worker) opens
+/// the input again.
+pub struct RowCursor<F> {
+    open: F,
+    rows: Option<RowIter>,
+    /// Index of the next row of `rows`
+    position: u64,
+}
+
+impl<F> RowCursor<F>
+where
+    F: Fn() -> Result<RowIter, Box<dyn Error>>,
+{
+    /// Cursor over the rows returned by `open`, opened on first use.
+    pub fn new(open: F) -> RowCursor<F> {
+        RowCursor {
+            open,
+            rows: None,
+            position: 0,
+        }
+    }
+
+    /// Rows of `range`, skipping the rows between the current position and
+    /// its start.
+    pub fn range(
+        &mut self,
+        range: WorkRange,
+    ) -> Result<impl Iterator<Item = NumberedRow> + '_, Box<dyn Error>> {
+        if self.rows.is_none() || range.start < self.position {
+            self.rows = Some((self.open)()?);
+            self.position = 0;
+        }
+        let RowCursor { rows, position, .. } = self;
+        let rows = rows.as_mut().expect("The input is open");
+        // Rows skipped are still read, but only once per worker
+        for _ in *position..range.start {
+            if rows.next().is_none() {
+                break;
+            }
+        }
+        *position = range.start;
+        Ok(rows
+            .take((range.end - range.start) as usize)
+            .inspect(move |_| *position += 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::Row;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_cursor_reads_forward_across_ranges() {
+        let opened = Cell::new(0);
+        let mut cursor = RowCursor::new(|| {
+            opened.set(opened.get() + 1);
+            let rows: RowIter = Box::new((1..=10).map(|line| Ok((line, Row::new()))));
+            Ok(rows)
+        });
+        let lines = |cursor: &mut RowCursor<_>, start, end| -> Vec<u64> {
+            cursor
+                .range(WorkRange { start, end })
+                .unwrap()
+                .map(|r| r.unwrap().0)
+                .collect()
+        };
+
+        assert_eq!(lines(&mut cursor, 0, 3), [1, 2, 3]);
+        assert_eq!(lines(&mut cursor, 5, 7), [6, 7]);
+        assert_eq!(lines(&mut cursor, 7, 9), [8, 9]);
+        assert_eq!(opened.get(), 1);
+
+        // An earlier range reopens the input
+        assert_eq!(lines(&mut cursor, 3, 5), [4, 5]);
+        assert_eq!(opened.get(), 2);
+
+        // A range stopped early is resumed from the last row read
+        cursor.range(WorkRange { start: 5, end: 8 }).unwrap().next();
+        assert_eq!(lines(&mut cursor, 6, 8), [7, 8]);
+        assert_eq!(lines(&mut cursor, 9, 12), [10]);
+        assert_eq!(opened.get(), 2);
+    }
+}
@@ -0,0 +1,9 @@
+mod checkpoint;
+mod cursor;
+mod shard;
+mod state;
+
+pub use crate::distributed::checkpoint::*;
+pub use crate::distributed::cursor::*;
+pub use crate::distributed::shard::*;
+pub use crate::distributed::state::*;
@@ -0,0 +1,121 @@
+use crate::graph::Row;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// Slice of the input processed by one of several instances, given as
+/// `--shard i/n` with `i` from 1 to `n`.
+///
+/// Users are assigned by the hash of their first sign-in name, so every
+/// instance can read the whole input and still agree on the owner of each row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid shard {s}: expected i/n, e.g. 1/4");
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let index = index.trim().parse::<u64>().map_err(|_| invalid())?;
+        let count = count.trim().parse::<u64>().map_err(|_| invalid())?;
+        if count == 0 || index == 0 || index > count {
+            return Err(format!("Invalid shard {s}: i must be between 1 and n"));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl Shard {
+    /// Whether the shard owns a row: by its first `issuerAssignedId` (case
+    /// insensitive), or by its line number when it has none.
+    pub fn owns(&self, row: &Row, line: u64) -> bool {
+        match first_sign_in_name(row) {
+            Some(name) => self.owns_key(&name.to_lowercase()),
+            None => self.owns_line(line),
+        }
+    }
+
+    /// Whether the shard owns the row at `line`, for rows that could not be read.
+    pub fn owns_line(&self, line: u64) -> bool {
+        line % self.count == self.index - 1
+    }
+
+    fn owns_key(&self, key: &str) -> bool {
+        let digest = Sha256::digest(key.as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        hash % self.count == self.index - 1
+    }
+}
+
+// Identities are an array, or a JSON string of one in CSV input
+fn first_sign_in_name(row: &Row) -> Option<String> {
+    let identities = match row.get("identities")? {
+        Value::String(s) => serde_json::from_str(s).ok()?,
+        v => v.clone(),
+    };
+    identities
+        .get(0)?
+        .get("issuerAssignedId")?
+        .as_str()
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_shard_parsing() {
+        assert_eq!("2/4".parse::<Shard>(), Ok(Shard { index: 2, count: 4 }));
+        assert_eq!("2/4".parse::<Shard>().unwrap().to_string(), "2/4");
+        assert!("0/4".parse::<Shard>().is_err());
+        assert!("5/4".parse::<Shard>().is_err());
+        assert!("1/0".parse::<Shard>().is_err());
+        assert!("2".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn test_every_row_has_exactly_one_owner() {
+        let shards: Vec<Shard> = (1..=3).map(|i| Shard { index: i, count: 3 }).collect();
+        let mut owned = [0; 3];
+        for n in 0..300u64 {
+            let mut row = Row::new();
+            // Both identity formats, with different case
+            let identities = json!([{"signInType": "emailAddress", "issuer": "d.com", "issuerAssignedId": format!("User{n}@d.com")}]);
+            if n % 2 == 0 {
+                row.insert("identities".into(), identities);
+            } else {
+                row.insert("identities".into(), identities.to_string().into());
+            }
+            let owners: Vec<usize> = (0..3).filter(|&i| shards[i].owns(&row, n)).collect();
+            assert_eq!(owners.len(), 1);
+            owned[owners[0]] += 1;
+
+            let mut lower = Row::new();
+            lower.insert(
+                "identities".into(),
+                json!([{"issuerAssignedId": format!("user{n}@d.com")}]),
+            );
+            assert!(shards[owners[0]].owns(&lower, n + 1));
+        }
+        // Roughly balanced
+        assert!(owned.iter().all(|&n| n > 60));
+
+        // Rows without identities go by line number
+        let empty = Row::new();
+        assert!(shards[1].owns(&empty, 4));
+        assert!(!shards[0].owns(&empty, 4));
+    }
+}
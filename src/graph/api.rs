@@ -1,298 +1,536 @@
+use crate::customizations::{prj1::*, smtp::*};
+use crate::dashboard::exit_process;
+use crate::db::{log_outcome, pii};
+use crate::graph::cloud::TokenCache;
+use crate::graph::error::*;
+use crate::graph::phone::*;
+use crate::graph::pool::*;
+use crate::graph::ratelimit::*;
 use crate::graph::user::*;
-use crate::{customizations::prj1::*, Customizations};
+use crate::metrics::{observe_response, observe_retry, Stage, StageFailure};
+use crate::telemetry::{start_span, SpanGuard};
+use crate::Customizations;
 use log::{error, info, warn};
-use tokio::time::{sleep, Duration};
+use tokio::time::{Duration, Instant};
 
-// Asynchronous function that creates the user on Azure B2C for a CSV row,
-// handling the case where the API responds with 429 "Too Many Requests".
+// Labels of a Graph call in its logs, metrics and stored outcomes
+struct GraphCall<'a> {
+    stage: Stage,
+    kind: RequestKind,
+    // What the call creates, e.g. "email authentication method"
+    what: &'a str,
+    // Row of a lease taken over, which its previous owner may have created
+    taken_over: bool,
+}
+
+// Result of a Graph call
+enum Sent {
+    Created(reqwest::Response),
+    // Conflict on a row of a lease taken over: created by its previous owner
+    AlreadyCreated,
+    // With the status of the response, if one was received
+    Failed(Option<u16>),
+}
+
+impl Sent {
+    // Failure of a call of `stage`, if it failed
+    fn failure(&self, stage: Stage) -> Option<StageFailure> {
+        match self {
+            Sent::Failed(status) => Some(StageFailure {
+                stage,
+                status: *status,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of the migration of a user: whether it was created, and the
+/// first call that failed, the user itself or one of its methods.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UserOutcome {
+    pub created: bool,
+    pub failure: Option<StageFailure>,
+}
+
+// Asynchronous function that creates the user on Azure B2C for a CSV row.
+// Returns whether the user was created, and the first call that failed.
 pub async fn create_user_api_call(
     client: &reqwest::Client,
     endpoint: &str,
     mut body: RequestBody,
-    token: &str,
+    tokens: &TokenCache,
     phone_auth_method: bool,
     email_auth_method: bool,
     customizations: Customizations,
-) {
-    loop {
-        // Clone body to use it in eventual create_phone/mail_auth_method_api_call
-        let original_body = body.clone();
+) -> UserOutcome {
+    let username = pii(&body.identities[0].issuerAssignedId).to_string();
+    let mut span = start_span("create user");
+    span.set("url.full", endpoint);
+    // Clone body to use it in eventual create_phone/mail_auth_method_api_call
+    let original_body = body.clone();
 
-        // Clean body from auth methods values since they must not be part
-        // of the user creation JSON body, they are used, if present, during
-        // the authentication method creation api call
-        body.phoneAuthMethod = None;
-        body.emailAuthMethod = None;
+    // Clean body from auth methods values since they must not be part
+    // of the user creation JSON body, they are used, if present, during
+    // the authentication method creation api call
+    body.phoneAuthMethod = None;
+    body.alternateMobilePhoneAuthMethod = None;
+    body.officePhoneAuthMethod = None;
+    body.emailAuthMethod = None;
+    body.softwareOathSecretKey = None;
 
-        match client
-            .post(endpoint)
-            .header("Authorization", format!("Bearer {token}"))
-            .json(&body)
-            .send()
-            .await
-        {
-            Ok(response) => {
-                let status = response.status();
+    let call = GraphCall {
+        stage: Stage::User,
+        kind: RequestKind::UserCreation,
+        what: "user",
+        taken_over: customizations.taken_over,
+    };
+    let response = match send_graph_request(
+        || client.post(endpoint).json(&body),
+        tokens,
+        &call,
+        &username,
+        &mut span,
+    )
+    .await
+    {
+        Sent::Created(response) => response,
+        Sent::AlreadyCreated => {
+            return UserOutcome {
+                created: true,
+                failure: None,
+            }
+        }
+        sent => {
+            return UserOutcome {
+                created: false,
+                failure: sent.failure(Stage::User),
+            }
+        }
+    };
+    let mut outcome = UserOutcome {
+        created: true,
+        failure: None,
+    };
 
-                if status.is_success() {
-                    // Extract JSON body from response
-                    let json_body: serde_json::Value = match response.json().await {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!(
-                                "[{:?}] Error parsing JSON response: {e:?}",
-                                body.identities[0].issuerAssignedId
-                            );
-                            break;
-                        }
-                    };
+    // Extract JSON body from response
+    let json_body: serde_json::Value = match response.json().await {
+        Ok(v) => v,
+        Err(e) => {
+            span.fail(&format!("Invalid JSON response: {e}"));
+            error!(
+                stage = Stage::User.as_label();
+                "[{:?}] Error parsing JSON response: {e:?}",
+                pii(&body.identities[0].issuerAssignedId)
+            );
+            return outcome;
+        }
+    };
 
-                    info!(
-                        "[{:?}] User created successfully with status: {status}.",
-                        body.identities[0].issuerAssignedId
-                    );
+    // Extract objectId from json body
+    let user_id = json_body
+        .get("id")
+        .and_then(|v| v.as_str())
+        .map(str::to_owned);
 
-                    // Extract objectId from json body
-                    let user_id = json_body
-                        .get("id")
-                        .and_then(|v| v.as_str())
-                        .map(str::to_owned);
+    if user_id.is_none() && (phone_auth_method || email_auth_method) {
+        error!(
+            stage = Stage::User.as_label();
+            "[{:?}] The 'id' field was not found in the response.",
+            pii(&body.identities[0].issuerAssignedId)
+        );
+    }
 
-                    if user_id.is_none() && (phone_auth_method || email_auth_method) {
-                        error!(
-                            "[{:?}] The 'id' field was not found in the response.",
-                            body.identities[0].issuerAssignedId
-                        );
-                    }
+    if let (Some(mapping_file), Some(id)) = (&customizations.mapping_file, &user_id) {
+        let _hook = start_span("mapping file");
+        mapping_file.record(
+            body.legacyId.as_deref(),
+            &body.identities[0].issuerAssignedId,
+            id,
+        );
+    }
 
-                    if let Some(id) = user_id {
-                        if phone_auth_method {
-                            let auth_endpoint =
-                                format!("{endpoint}/{id}/authentication/phoneMethods");
-                            create_phone_auth_method_api_call(
-                                client,
-                                &auth_endpoint,
-                                original_body.clone(),
-                                token,
-                            )
-                            .await;
-                        }
-                        if email_auth_method {
-                            let auth_endpoint =
-                                format!("{endpoint}/{id}/authentication/emailMethods");
-                            create_email_auth_method_api_call(
-                                client,
-                                &auth_endpoint,
-                                original_body,
-                                token,
-                            )
-                            .await;
-                        }
-                    }
+    // Only set in seamless migration mode
+    if let (Some(hash_store), Some(legacy_password), Some(id)) =
+        (&customizations.hash_store, &body.legacyPassword, &user_id)
+    {
+        let sign_in_names: Vec<&str> = body
+            .identities
+            .iter()
+            .filter(|i| i.signInType != "federated")
+            .map(|i| i.issuerAssignedId.as_str())
+            .collect();
+        let _hook = start_span("hash store");
+        hash_store.record(&sign_in_names, id, legacy_password);
+    }
 
-                    // Customization for Proj1
-                    if customizations.prj1 {
-                        send_notification(
-                            client,
-                            &customizations.prj1_config.unwrap(),
-                            &body.identities[0].issuerAssignedId,
-                        )
-                        .await;
-                    }
-                    break;
-                } else if response.status().as_u16() == 401 || response.status().as_u16() == 403 {
-                    error!(
-                        "[{:?}] Something went wrong. Received {}. Maybe token is invalid or expired? Exiting..",
-                        body.identities[0].issuerAssignedId,
-                        response.status()
-                    );
-                    std::process::exit(0);
-                } else if response.status().as_u16() == 429 {
-                    // Extract the Retry-After header and wait for the necessary time expressed in seconds
-                    if let Some(retry_after_value) = response.headers().get("Retry-After") {
-                        if let Ok(retry_after_str) = retry_after_value.to_str() {
-                            if let Ok(wait_secs) = retry_after_str.parse::<u64>() {
-                                warn!(
-                                    "[{:?}] Received 429. Waiting for {} seconds before retrying.",
-                                    body.identities[0].issuerAssignedId, wait_secs
-                                );
-                                sleep(Duration::from_secs(wait_secs)).await;
-                                continue; // Repeat the loop to retry the request
-                            }
-                        }
-                    }
-                    error!(
-                        "[{:?}] Received 429, but Retry-After header is invalid. Task interruption.",
-                        body.identities[0].issuerAssignedId
-                    );
-                    break;
-                } else {
-                    error!(
-                        "[{:?}] Error in request with status: {}.",
-                        body.identities[0].issuerAssignedId,
-                        response.status()
-                    );
-                    break;
-                }
-            }
-            Err(e) => {
-                error!(
-                    "[{:?}] Error in request: {:?}.",
-                    body.identities[0].issuerAssignedId, e
-                );
-                break;
+    // Only set when the password of this user was generated
+    if let (Some(credentials_file), Some(id)) = (&customizations.credentials_file, &user_id) {
+        let _hook = start_span("credentials file");
+        credentials_file.record(
+            body.legacyId.as_deref(),
+            &body.displayName,
+            &body.identities[0].issuerAssignedId,
+            id,
+            &body.passwordProfile.password,
+        );
+    }
+
+    if let Some(id) = user_id {
+        if phone_auth_method {
+            let auth_endpoint = format!("{endpoint}/{id}/authentication/phoneMethods");
+            for (phone_type, phone_number) in original_body.phone_auth_methods() {
+                let failure = create_phone_auth_method_api_call(
+                    client,
+                    &auth_endpoint,
+                    &original_body,
+                    tokens,
+                    phone_type,
+                    phone_number,
+                )
+                .await;
+                outcome.failure = outcome.failure.or(failure);
             }
         }
+        if email_auth_method {
+            let auth_endpoint = format!("{endpoint}/{id}/authentication/emailMethods");
+            let failure = create_email_auth_method_api_call(
+                client,
+                &auth_endpoint,
+                original_body.clone(),
+                tokens,
+            )
+            .await;
+            outcome.failure = outcome.failure.or(failure);
+        }
+        if let Some(secret_key) = &original_body.softwareOathSecretKey {
+            // Software OATH methods may only exist in another API version
+            let users_endpoint = customizations.oath_endpoint.as_deref().unwrap_or(endpoint);
+            let auth_endpoint = format!("{users_endpoint}/{id}/authentication/softwareOathMethods");
+            let failure = create_software_oath_method_api_call(
+                client,
+                &auth_endpoint,
+                &original_body,
+                tokens,
+                secret_key,
+            )
+            .await;
+            outcome.failure = outcome.failure.or(failure);
+        }
+    }
+
+    // Customization for Proj1
+    if customizations.prj1 {
+        send_notification(
+            client,
+            &customizations.prj1_config.unwrap(),
+            &body.identities[0].issuerAssignedId,
+        )
+        .await;
     }
+
+    // Built-in SMTP notification
+    if customizations.smtp {
+        if let Some(smtp_notifier) = &customizations.smtp_notifier {
+            send_smtp_notification(smtp_notifier, &body).await;
+        }
+    }
+    outcome
 }
 
-// Asynchronous function that creates the phone authentication method for a user
+// Asynchronous function that creates a phone authentication method of the given type for a user.
+// Returns the failure of the call, if it failed.
 pub async fn create_phone_auth_method_api_call(
     client: &reqwest::Client,
     endpoint: &str,
-    body: RequestBody,
-    token: &str,
-) {
-    loop {
-        // Create request body from original body
-        let phone_auth_method = body.clone().phoneAuthMethod.unwrap();
-        let auth_body = PhoneAuthMethodRequestBody {
-            phoneNumber: phone_auth_method,
-            phoneType: "mobile".to_string(),
-        };
+    body: &RequestBody,
+    tokens: &TokenCache,
+    phone_type: PhoneType,
+    phone_number: &str,
+) -> Option<StageFailure> {
+    let user = pii(&body.identities[0].issuerAssignedId);
+    let phone_type = phone_type.as_graph();
 
-        match client
-            .post(endpoint)
-            .header("Authorization", format!("Bearer {token}"))
-            .json(&auth_body)
-            .send()
-            .await
-        {
-            Ok(response) => {
-                if response.status().is_success() {
-                    info!(
-                        "[{:?}] Phone authentication method created successfully with status: {}.",
-                        body.identities[0].issuerAssignedId,
-                        response.status()
-                    );
-                    break;
-                } else if response.status().as_u16() == 401 || response.status().as_u16() == 403 {
-                    error!(
-                        "[{:?}] Something went wrong. Received {}. Maybe token is invalid or expired? Exiting..",
-                        body.identities[0].issuerAssignedId,
-                        response.status()
-                    );
-                    std::process::exit(0);
-                } else if response.status().as_u16() == 429 {
-                    // Extract the Retry-After header and wait for the necessary time expressed in seconds
-                    if let Some(retry_after_value) = response.headers().get("Retry-After") {
-                        if let Ok(retry_after_str) = retry_after_value.to_str() {
-                            if let Ok(wait_secs) = retry_after_str.parse::<u64>() {
-                                warn!(
-                                    "[{:?}] Received 429. Waiting for {} seconds before retrying.",
-                                    body.identities[0].issuerAssignedId, wait_secs
-                                );
-                                sleep(Duration::from_secs(wait_secs)).await;
-                                continue; // Repeat the loop to retry the request
-                            }
-                        }
-                    }
-                    error!(
-                        "[{:?}] Received 429, but Retry-After header is invalid. Task interruption.",
-                        body.identities[0].issuerAssignedId
-                    );
-                    break;
-                } else {
-                    error!(
-                        "[{:?}] Error in request with status: {}.",
-                        body.identities[0].issuerAssignedId,
-                        response.status()
-                    );
-                    break;
-                }
-            }
-            Err(e) => {
-                error!(
-                    "[{:?}] Error in request: {:?}.",
-                    body.identities[0].issuerAssignedId, e
-                );
-                break;
-            }
+    // Graph only accepts numbers formatted as "+<country code> <number>".
+    // Numbers are normalised when the row is validated, this only guards
+    // against direct calls.
+    let auth_body = match format_phone_number(phone_number, None) {
+        Ok(formatted) => PhoneAuthMethodRequestBody {
+            phoneNumber: formatted,
+            phoneType: phone_type.to_string(),
+        },
+        Err(e) => {
+            start_span("phone method").fail(&format!("Invalid phone number: {e}"));
+            error!(
+                stage = Stage::PhoneMethods.as_label();
+                "[{user:?}] Phone authentication method ({phone_type}) not created, invalid number {:?}: {e}.",
+                pii(phone_number)
+            );
+            return Some(StageFailure {
+                stage: Stage::PhoneMethods,
+                status: None,
+            });
         }
-    }
+    };
+
+    let mut span = start_span("phone method");
+    span.set("url.full", endpoint);
+    span.set("phone_type", phone_type);
+    let what = format!("phone authentication method ({phone_type})");
+    let call = GraphCall {
+        stage: Stage::PhoneMethods,
+        kind: RequestKind::AuthMethod,
+        what: &what,
+        taken_over: false,
+    };
+    send_graph_request(
+        || client.post(endpoint).json(&auth_body),
+        tokens,
+        &call,
+        &user.to_string(),
+        &mut span,
+    )
+    .await
+    .failure(call.stage)
 }
 
-// Asynchronous function that creates the email authentication method for a user
+// Asynchronous function that creates the email authentication method for a user.
+// Returns the failure of the call, if it failed.
 pub async fn create_email_auth_method_api_call(
     client: &reqwest::Client,
     endpoint: &str,
     body: RequestBody,
-    token: &str,
-) {
-    loop {
-        // Create request body from original body
-        let email_auth_method = body.clone().emailAuthMethod.unwrap();
-        let auth_body = EmailAuthMethodRequestBody {
-            emailAddress: email_auth_method,
-        };
+    tokens: &TokenCache,
+) -> Option<StageFailure> {
+    let username = pii(&body.identities[0].issuerAssignedId).to_string();
+    let mut span = start_span("email method");
+    span.set("url.full", endpoint);
+    let auth_body = EmailAuthMethodRequestBody {
+        emailAddress: body.emailAuthMethod.unwrap(),
+    };
+    let call = GraphCall {
+        stage: Stage::EmailMethods,
+        kind: RequestKind::AuthMethod,
+        what: "email authentication method",
+        taken_over: false,
+    };
+    send_graph_request(
+        || client.post(endpoint).json(&auth_body),
+        tokens,
+        &call,
+        &username,
+        &mut span,
+    )
+    .await
+    .failure(call.stage)
+}
+
+// Asynchronous function that creates a software OATH token authentication method for a user.
+// Returns the failure of the call, if it failed.
+pub async fn create_software_oath_method_api_call(
+    client: &reqwest::Client,
+    endpoint: &str,
+    body: &RequestBody,
+    tokens: &TokenCache,
+    secret_key: &str,
+) -> Option<StageFailure> {
+    let username = pii(&body.identities[0].issuerAssignedId).to_string();
+    let mut span = start_span("software oath method");
+    span.set("url.full", endpoint);
+    let auth_body = SoftwareOathMethodRequestBody {
+        secretKey: secret_key.to_string(),
+    };
+    let call = GraphCall {
+        stage: Stage::SoftwareOathMethods,
+        kind: RequestKind::AuthMethod,
+        what: "software OATH authentication method",
+        taken_over: false,
+    };
+    send_graph_request(
+        || client.post(endpoint).json(&auth_body),
+        tokens,
+        &call,
+        &username,
+        &mut span,
+    )
+    .await
+    .failure(call.stage)
+}
 
-        match client
-            .post(endpoint)
-            .header("Authorization", format!("Bearer {token}"))
-            .json(&auth_body)
+// Send the request built by `request` on behalf of `username`, handling the
+// case where the API responds with 429 "Too Many Requests" or 503 "Service
+// Unavailable" by backing off through the shared limiter, then retrying.
+// Returns the response of a successful call; failures are logged and stored
+// with the outcome of the call. A conflict on a row of a lease taken over
+// means the previous owner of the lease created it already.
+async fn send_graph_request<F>(
+    request: F,
+    tokens: &TokenCache,
+    call: &GraphCall<'_>,
+    username: &str,
+    span: &mut SpanGuard,
+) -> Sent
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    let stage = call.stage;
+    let what = call.what;
+    let mut renewed = false;
+    loop {
+        let client_request_id = new_client_request_id();
+        span.set("graph.client_request_id", client_request_id.as_str());
+        let auth = authorize(tokens, call.kind).await;
+        let started = Instant::now();
+        let response = match request()
+            .header("Authorization", auth.header())
+            .header(CLIENT_REQUEST_ID, &client_request_id)
+            .header("return-client-request-id", "true")
             .send()
             .await
         {
-            Ok(response) => {
-                if response.status().is_success() {
-                    info!(
-                        "[{:?}] Email authentication method created successfully with status: {}.",
-                        body.identities[0].issuerAssignedId,
-                        response.status()
-                    );
-                    break;
-                } else if response.status().as_u16() == 401 || response.status().as_u16() == 403 {
-                    error!(
-                        "[{:?}] Something went wrong. Received {}. Maybe token is invalid or expired? Exiting..",
-                        body.identities[0].issuerAssignedId,
-                        response.status()
-                    );
-                    std::process::exit(0);
-                } else if response.status().as_u16() == 429 {
-                    // Extract the Retry-After header and wait for the necessary time expressed in seconds
-                    if let Some(retry_after_value) = response.headers().get("Retry-After") {
-                        if let Ok(retry_after_str) = retry_after_value.to_str() {
-                            if let Ok(wait_secs) = retry_after_str.parse::<u64>() {
-                                warn!(
-                                    "[{:?}] Received 429. Waiting for {} seconds before retrying.",
-                                    body.identities[0].issuerAssignedId, wait_secs
-                                );
-                                sleep(Duration::from_secs(wait_secs)).await;
-                                continue; // Repeat the loop to retry the request
-                            }
-                        }
-                    }
-                    error!(
-                        "[{:?}] Received 429, but Retry-After header is invalid. Task interruption.",
-                        body.identities[0].issuerAssignedId
-                    );
-                    break;
-                } else {
-                    error!(
-                        "[{:?}] Error in request with status: {}.",
-                        body.identities[0].issuerAssignedId,
-                        response.status()
-                    );
-                    break;
-                }
-            }
+            Ok(response) => response,
             Err(e) => {
+                let error = GraphError::transport(&e, &client_request_id);
+                log_outcome(
+                    username,
+                    stage.as_label(),
+                    None,
+                    &client_request_id,
+                    None,
+                    Some(&error),
+                );
+                span.fail(&error.to_string());
+                observe_response(stage, None, started.elapsed());
+                auth.record_outcome(false, started.elapsed());
                 error!(
-                    "[{:?}] Error in request: {:?}.",
-                    body.identities[0].issuerAssignedId, e
+                    stage = stage.as_label();
+                    "[{username:?}] Error creating {what}: {e:?}."
+                );
+                return Sent::Failed(None);
+            }
+        };
+
+        let status = response.status();
+        span.record_response(&response);
+        observe_response(stage, Some(status.as_u16()), started.elapsed());
+        if status.is_success() {
+            auth.record_outcome(true, started.elapsed());
+            log_outcome(
+                username,
+                stage.as_label(),
+                Some(status.as_u16()),
+                &client_request_id,
+                response_request_id(&response).as_deref(),
+                None,
+            );
+            info!(
+                stage = stage.as_label(),
+                status = status.as_u16();
+                "[{username:?}] {} created successfully with status: {status}.",
+                capitalized(what)
+            );
+            return Sent::Created(response);
+        }
+
+        let retry_after = response
+            .headers()
+            .get("Retry-After")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        // Intermediate errors only go to the retry span and the logs, the
+        // outcome log keeps the last one
+        let error = GraphError::from_response(response, &client_request_id).await;
+        // The token may have been revoked or expired early: retry once with a new one
+        if status.as_u16() == 401 && !renewed && auth.renew().await {
+            warn!(
+                stage = stage.as_label(),
+                status = status.as_u16();
+                "[{username:?}] Received {status} ({error}). Retrying with a renewed token."
+            );
+            renewed = true;
+            continue;
+        }
+        if status.as_u16() == 401 || status.as_u16() == 403 {
+            log_error(username, stage, &error);
+            error!(
+                stage = stage.as_label(),
+                status = status.as_u16();
+                "[{username:?}] Something went wrong. Received {status} ({error}). Maybe token is invalid or expired? Exiting.."
+            );
+            exit_process(0);
+        } else if status.as_u16() == 429 || status.as_u16() == 503 {
+            // Wait for the time expressed in seconds by the Retry-After header
+            if let Some(wait_secs) = retry_after {
+                warn!(
+                    stage = stage.as_label(),
+                    status = status.as_u16();
+                    "[{username:?}] Received {status} ({error}). Waiting for {wait_secs} seconds before retrying."
                 );
-                break;
+                let mut retry = span.child("retry");
+                retry.record_error(&error);
+                retry.set("retry_after_seconds", wait_secs as i64);
+                auth.back_off(Some(Duration::from_secs(wait_secs))).await;
+                drop(retry);
+                observe_retry(stage);
+                continue; // Repeat the loop to retry the request
             }
+            auth.back_off(None).await;
+            log_error(username, stage, &error);
+            span.record_error(&error);
+            span.fail(&error.to_string());
+            error!(
+                stage = stage.as_label(),
+                status = status.as_u16();
+                "[{username:?}] Received {status} ({error}), but Retry-After header is invalid. {} not created.",
+                capitalized(what)
+            );
+            return Sent::Failed(Some(status.as_u16()));
         }
+        if call.taken_over
+            && status.as_u16() == 409
+            && error.code.as_deref() == Some("ObjectConflict")
+        {
+            auth.record_outcome(true, started.elapsed());
+            log_error(username, stage, &error);
+            span.set("already_created", true);
+            info!(
+                stage = stage.as_label(),
+                status = status.as_u16();
+                "[{username:?}] {} already created by the previous owner of its lease.",
+                capitalized(what)
+            );
+            return Sent::AlreadyCreated;
+        }
+        auth.record_outcome(!status.is_server_error(), started.elapsed());
+        log_error(username, stage, &error);
+        span.record_error(&error);
+        span.fail(&error.to_string());
+        error!(
+            stage = stage.as_label(),
+            status = status.as_u16();
+            "[{username:?}] Error creating {what} with status: {status}, {error}."
+        );
+        return Sent::Failed(Some(status.as_u16()));
     }
 }
+
+// First letter of a label made uppercase, to start a log message
+fn capitalized(what: &str) -> String {
+    let mut chars = what.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// Store the error of a non-2xx response as the final outcome of the call
+fn log_error(username: &str, stage: Stage, error: &GraphError) {
+    log_outcome(
+        username,
+        stage.as_label(),
+        error.status,
+        &error.client_request_id,
+        None,
+        Some(error),
+    );
+}
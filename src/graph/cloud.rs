@@ -0,0 +1,357 @@
+use log::{info, warn};
+use serde::Deserialize;
+use std::error::Error;
+use std::str::FromStr;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Time before its expiry at which a token is renewed.
+const RENEW_MARGIN: Duration = Duration::from_secs(300);
+
+/// Microsoft clouds hosting Graph, each with its own login authority.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cloud {
+    /// Azure global service
+    Global,
+    /// Azure US Government (L4)
+    UsGov,
+    /// Azure China operated by 21Vianet
+    China,
+    /// Graph URL and authority given explicitly with `--url` and `--authority`
+    Custom,
+}
+
+impl FromStr for Cloud {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "global" => Ok(Cloud::Global),
+            "usgov" => Ok(Cloud::UsGov),
+            "china" => Ok(Cloud::China),
+            "custom" => Ok(Cloud::Custom),
+            other => Err(format!("Unknown cloud: {other}")),
+        }
+    }
+}
+
+impl Cloud {
+    /// Graph base URL of the cloud, none for custom profiles.
+    pub fn graph_url(self) -> Option<&'static str> {
+        match self {
+            Cloud::Global => Some("https://graph.microsoft.com"),
+            Cloud::UsGov => Some("https://graph.microsoft.us"),
+            Cloud::China => Some("https://microsoftgraph.chinacloudapi.cn"),
+            Cloud::Custom => None,
+        }
+    }
+
+    /// Login authority issuing tokens for the cloud, none for custom profiles.
+    pub fn authority(self) -> Option<&'static str> {
+        match self {
+            Cloud::Global => Some("https://login.microsoftonline.com"),
+            Cloud::UsGov => Some("https://login.microsoftonline.us"),
+            Cloud::China => Some("https://login.chinacloudapi.cn"),
+            Cloud::Custom => None,
+        }
+    }
+}
+
+/// Graph base URL and API version shared by every call of a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphEndpoint {
+    pub base: String,
+    pub version: String,
+}
+
+impl GraphEndpoint {
+    pub fn new(base: &str, version: &str) -> GraphEndpoint {
+        GraphEndpoint {
+            base: base.trim_end_matches('/').to_string(),
+            version: version.to_string(),
+        }
+    }
+
+    /// Same Graph host with another API version, e.g. `beta` only methods.
+    pub fn with_version(&self, version: &str) -> GraphEndpoint {
+        GraphEndpoint::new(&self.base, version)
+    }
+
+    /// Collection of the users, e.g. `https://graph.microsoft.com/v1.0/users`.
+    pub fn users(&self) -> String {
+        format!("{}/{}/users", self.base, self.version)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// Lifetime of the token in seconds
+    #[serde(default = "default_expires_in")]
+    expires_in: u64,
+}
+
+// Lifetime of the tokens of Entra ID, for authorities leaving it out
+fn default_expires_in() -> u64 {
+    3599
+}
+
+/// Bearer token with the time it expires, unknown for a token given as is.
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    pub token: String,
+    pub expires_at: Option<Instant>,
+}
+
+/// Client credentials of an app registration, to acquire its tokens.
+#[derive(Clone)]
+pub struct ClientCredentials {
+    pub authority: String,
+    pub tenant_id: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub graph: GraphEndpoint,
+}
+
+/// Token of an app, renewed with its client credentials before it expires
+/// or once rejected. A token given as is is never renewed.
+pub struct TokenCache {
+    credentials: Option<ClientCredentials>,
+    client: reqwest::Client,
+    token: Mutex<AccessToken>,
+}
+
+impl TokenCache {
+    /// Cache of a token given as is, e.g. with `--token`.
+    pub fn fixed(token: &str) -> TokenCache {
+        TokenCache {
+            credentials: None,
+            client: reqwest::Client::new(),
+            token: Mutex::new(AccessToken {
+                token: token.to_string(),
+                expires_at: None,
+            }),
+        }
+    }
+
+    /// Acquire a first token with `credentials`.
+    pub async fn connect(
+        client: &reqwest::Client,
+        credentials: ClientCredentials,
+    ) -> Result<TokenCache, Box<dyn Error>> {
+        let token = credentials.acquire(client).await?;
+        Ok(TokenCache {
+            credentials: Some(credentials),
+            client: client.clone(),
+            token: Mutex::new(token),
+        })
+    }
+
+    /// Current token, renewed first when it is about to expire. A failed
+    /// renewal is logged and the current token kept until it is rejected.
+    pub async fn token(&self) -> String {
+        let mut token = self.token.lock().await;
+        let expiring = token
+            .expires_at
+            .is_some_and(|at| at.saturating_duration_since(Instant::now()) < RENEW_MARGIN);
+        if let (true, Some(credentials)) = (expiring, &self.credentials) {
+            match credentials.acquire(&self.client).await {
+                Ok(renewed) => {
+                    info!("Token renewed for client {}.", credentials.client_id);
+                    *token = renewed;
+                }
+                Err(e) => warn!(
+                    "Unable to renew the token of client {}: {e}",
+                    credentials.client_id
+                ),
+            }
+        }
+        token.token.clone()
+    }
+
+    /// Renew the token after Graph rejected `rejected` with a 401, unless
+    /// another request already did. Returns the token to retry with.
+    pub async fn renew(&self, rejected: &str) -> Result<String, String> {
+        let Some(credentials) = &self.credentials else {
+            return Err("The token was given as is and cannot be renewed".to_string());
+        };
+        let mut token = self.token.lock().await;
+        if token.token == rejected {
+            *token = credentials
+                .acquire(&self.client)
+                .await
+                .map_err(|e| format!("Unable to renew the token: {e}"))?;
+            info!("Token renewed for client {}.", credentials.client_id);
+        }
+        Ok(token.token.clone())
+    }
+}
+
+impl ClientCredentials {
+    /// Acquire a token with the client credentials flow.
+    pub async fn acquire(&self, client: &reqwest::Client) -> Result<AccessToken, Box<dyn Error>> {
+        acquire_token(
+            client,
+            &self.authority,
+            &self.tenant_id,
+            &self.client_id,
+            &self.client_secret,
+            &self.graph,
+        )
+        .await
+    }
+}
+
+/// Acquire an app-only Graph token with the client credentials flow.
+///
+/// # Errors
+/// * network failures while calling the authority
+/// * non-success responses, e.g. a wrong secret or tenant
+pub async fn acquire_token(
+    client: &reqwest::Client,
+    authority: &str,
+    tenant_id: &str,
+    client_id: &str,
+    client_secret: &str,
+    graph: &GraphEndpoint,
+) -> Result<AccessToken, Box<dyn Error>> {
+    let response = client
+        .post(format!(
+            "{}/{tenant_id}/oauth2/v2.0/token",
+            authority.trim_end_matches('/')
+        ))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id),
+            ("client_secret", client_secret),
+            ("scope", &format!("{}/.default", graph.base)),
+        ])
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!(
+            "Token request to {authority} failed with status {status}: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .into());
+    }
+    let token = response.json::<TokenResponse>().await?;
+    Ok(AccessToken {
+        token: token.access_token,
+        expires_at: Some(Instant::now() + Duration::from_secs(token.expires_in)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloud_profiles() {
+        assert_eq!("USGov".parse::<Cloud>(), Ok(Cloud::UsGov));
+        assert!("moon".parse::<Cloud>().is_err());
+        assert_eq!(
+            Cloud::China.graph_url(),
+            Some("https://microsoftgraph.chinacloudapi.cn")
+        );
+        assert_eq!(
+            Cloud::UsGov.authority(),
+            Some("https://login.microsoftonline.us")
+        );
+        assert!(Cloud::Custom.graph_url().is_none());
+
+        let graph = GraphEndpoint::new("https://graph.microsoft.us/", "v1.0");
+        assert_eq!(graph.users(), "https://graph.microsoft.us/v1.0/users");
+        assert_eq!(
+            graph.with_version("beta").users(),
+            "https://graph.microsoft.us/beta/users"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_acquire_token() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/contoso.onmicrosoft.com/oauth2/v2.0/token")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("grant_type".into(), "client_credentials".into()),
+                mockito::Matcher::UrlEncoded(
+                    "scope".into(),
+                    "https://graph.microsoft.us/.default".into(),
+                ),
+            ]))
+            .with_status(200)
+            .with_body(r#"{"token_type": "Bearer", "expires_in": 3599, "access_token": "eyJ0"}"#)
+            .create_async()
+            .await;
+        let graph = GraphEndpoint::new("https://graph.microsoft.us", "v1.0");
+        let client = reqwest::Client::new();
+        let token = acquire_token(
+            &client,
+            &server.url(),
+            "contoso.onmicrosoft.com",
+            "client",
+            "secret",
+            &graph,
+        )
+        .await
+        .unwrap();
+        assert_eq!(token.token, "eyJ0");
+        assert!(token.expires_at.unwrap() > Instant::now() + Duration::from_secs(3500));
+        mock.assert_async().await;
+
+        server
+            .mock("POST", "/other/oauth2/v2.0/token")
+            .with_status(401)
+            .with_body(r#"{"error": "invalid_client"}"#)
+            .create_async()
+            .await;
+        let err = acquire_token(&client, &server.url(), "other", "client", "bad", &graph)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid_client"));
+    }
+
+    #[tokio::test]
+    async fn test_token_cache_renews_expiring_tokens() {
+        let mut server = mockito::Server::new_async().await;
+        let first = server
+            .mock("POST", "/contoso/oauth2/v2.0/token")
+            .with_body(r#"{"expires_in": 3599, "access_token": "token-1"}"#)
+            .create_async()
+            .await;
+        let credentials = ClientCredentials {
+            authority: server.url(),
+            tenant_id: "contoso".to_string(),
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            graph: GraphEndpoint::new("https://graph.microsoft.com", "v1.0"),
+        };
+        let cache = TokenCache::connect(&reqwest::Client::new(), credentials)
+            .await
+            .unwrap();
+        assert_eq!(cache.token().await, "token-1");
+        first.remove_async().await;
+        let second = server
+            .mock("POST", "/contoso/oauth2/v2.0/token")
+            .with_body(r#"{"expires_in": 3599, "access_token": "token-2"}"#)
+            .expect(2)
+            .create_async()
+            .await;
+
+        // A token about to expire is renewed before it is used
+        cache.token.lock().await.expires_at = Some(Instant::now() + Duration::from_secs(60));
+        assert_eq!(cache.token().await, "token-2");
+        assert_eq!(cache.token().await, "token-2");
+
+        // A rejected token is renewed once, however many requests saw it
+        assert_eq!(cache.renew("token-2").await.unwrap(), "token-2");
+        assert_eq!(cache.renew("token-1").await.unwrap(), "token-2");
+        second.assert_async().await;
+
+        let fixed = TokenCache::fixed("static");
+        assert_eq!(fixed.token().await, "static");
+        assert!(fixed.renew("static").await.is_err());
+    }
+}
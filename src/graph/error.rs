@@ -0,0 +1,144 @@
+use serde_json::Value;
+use std::fmt;
+
+/// Header identifying a request on the client side, echoed by Graph.
+pub const CLIENT_REQUEST_ID: &str = "client-request-id";
+
+/// New `client-request-id` for a Graph request, to be quoted to Microsoft
+/// support along with the `request-id` of the response.
+pub fn new_client_request_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// `request-id` Graph gave to a request, from the response headers.
+pub fn response_request_id(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Error of a Graph request: the `error` object of a non-2xx response, the
+/// ids of the request, or the transport error when no response came back.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GraphError {
+    pub status: Option<u16>,
+    pub code: Option<String>,
+    pub message: Option<String>,
+    pub request_id: Option<String>,
+    pub client_request_id: String,
+    pub date: Option<String>,
+}
+
+impl GraphError {
+    /// Read the error of a non-2xx response, consuming its body. Ids missing
+    /// from the body are taken from the response headers.
+    pub async fn from_response(response: reqwest::Response, client_request_id: &str) -> GraphError {
+        let status = response.status().as_u16();
+        let request_id = response_request_id(&response);
+        let date = response
+            .headers()
+            .get("date")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let body = response.text().await.unwrap_or_default();
+        let mut error = GraphError::parse(status, &body, client_request_id);
+        error.request_id = error.request_id.or(request_id);
+        error.date = error.date.or(date);
+        error
+    }
+
+    /// Error of a response body, e.g.
+    /// `{"error": {"code": "...", "message": "...", "innerError": {"request-id": "...", "date": "..."}}}`.
+    /// Bodies that are not Graph errors keep only the status.
+    pub fn parse(status: u16, body: &str, client_request_id: &str) -> GraphError {
+        let json: Value = serde_json::from_str(body).unwrap_or_default();
+        let error = &json["error"];
+        let inner = &error["innerError"];
+        let text = |v: &Value| v.as_str().filter(|s| !s.is_empty()).map(str::to_string);
+        GraphError {
+            status: Some(status),
+            code: text(&error["code"]),
+            message: text(&error["message"]),
+            request_id: text(&inner["request-id"]),
+            client_request_id: text(&inner["client-request-id"])
+                .unwrap_or_else(|| client_request_id.to_string()),
+            date: text(&inner["date"]),
+        }
+    }
+
+    /// Error of a request that got no response.
+    pub fn transport(error: &reqwest::Error, client_request_id: &str) -> GraphError {
+        GraphError {
+            message: Some(error.to_string()),
+            client_request_id: client_request_id.to_string(),
+            ..GraphError::default()
+        }
+    }
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.code, &self.message) {
+            (Some(code), Some(message)) => write!(f, "{code}: {message}")?,
+            (Some(code), None) => write!(f, "{code}")?,
+            (None, Some(message)) => write!(f, "{message}")?,
+            (None, None) => write!(f, "no error details")?,
+        }
+        write!(f, " (client-request-id {}", self.client_request_id)?;
+        if let Some(request_id) = &self.request_id {
+            write!(f, ", request-id {request_id}")?;
+        }
+        if let Some(date) = &self.date {
+            write!(f, ", date {date}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_graph_error() {
+        let body = r#"{"error": {"code": "Request_BadRequest",
+            "message": "Another object with the same value for property userPrincipalName already exists.",
+            "innerError": {"date": "2024-06-01T22:10:03", "request-id": "4f1c7d9e", "client-request-id": "echoed"}}}"#;
+        let error = GraphError::parse(400, body, "sent");
+        assert_eq!(error.status, Some(400));
+        assert_eq!(error.code.as_deref(), Some("Request_BadRequest"));
+        assert_eq!(error.request_id.as_deref(), Some("4f1c7d9e"));
+        assert_eq!(error.client_request_id, "echoed");
+        assert_eq!(
+            error.to_string(),
+            "Request_BadRequest: Another object with the same value for property userPrincipalName already exists. (client-request-id echoed, request-id 4f1c7d9e, date 2024-06-01T22:10:03)"
+        );
+
+        // Not a Graph error
+        let error = GraphError::parse(502, "<html>Bad Gateway</html>", "sent");
+        assert_eq!(error.code, None);
+        assert_eq!(error.client_request_id, "sent");
+        assert_eq!(
+            error.to_string(),
+            "no error details (client-request-id sent)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ids_fall_back_to_headers() {
+        let response = reqwest::Response::from(
+            axum::http::Response::builder()
+                .status(429)
+                .header("request-id", "from-header")
+                .header("date", "Sat, 01 Jun 2024 22:10:03 GMT")
+                .body(r#"{"error": {"code": "TooManyRequests", "message": "Too many requests"}}"#)
+                .unwrap(),
+        );
+        let error = GraphError::from_response(response, "sent").await;
+        assert_eq!(error.code.as_deref(), Some("TooManyRequests"));
+        assert_eq!(error.request_id.as_deref(), Some("from-header"));
+        assert_eq!(error.date.as_deref(), Some("Sat, 01 Jun 2024 22:10:03 GMT"));
+    }
+}
@@ -1,5 +1,20 @@
 mod api;
+mod cloud;
+mod error;
+mod oath;
+mod password;
+mod phone;
+mod pool;
+mod ratelimit;
+mod throttle;
 mod user;
 
 pub use crate::graph::api::*;
+pub use crate::graph::cloud::*;
+pub use crate::graph::error::*;
+pub use crate::graph::password::*;
+pub use crate::graph::phone::*;
+pub use crate::graph::pool::*;
+pub use crate::graph::ratelimit::*;
+pub use crate::graph::throttle::*;
 pub use crate::graph::user::*;
@@ -0,0 +1,90 @@
+use crate::graph::RequestBody;
+
+/// Normalise a base32 TOTP seed as exported by most authenticator and IdP
+/// tools: spaces, dashes and padding are dropped and letters uppercased.
+///
+/// # Errors
+/// * empty secrets
+/// * characters outside the RFC 4648 base32 alphabet
+/// * lengths that cannot encode whole bytes
+pub fn normalize_secret_key(raw: &str) -> Result<String, String> {
+    let secret: String = raw
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .trim_end_matches('=')
+        .to_ascii_uppercase();
+    if secret.is_empty() {
+        return Err("empty secret key".to_string());
+    }
+    if let Some(c) = secret.chars().find(|c| !matches!(c, 'A'..='Z' | '2'..='7')) {
+        return Err(format!("{c:?} is not a base32 character"));
+    }
+    // Base32 groups of 8 characters encode 5 bytes, a trailing partial
+    // group can only hold 2, 4, 5 or 7 characters
+    if matches!(secret.len() % 8, 1 | 3 | 6) {
+        return Err(format!("invalid base32 length {}", secret.len()));
+    }
+    Ok(secret)
+}
+
+impl RequestBody {
+    /// Normalise the software OATH secret key of the row, if any.
+    pub fn normalize_software_oath(&mut self) -> Result<(), String> {
+        if let Some(secret) = &self.softwareOathSecretKey {
+            let normalized = normalize_secret_key(secret)
+                .map_err(|e| format!("invalid software OATH secret key: {e}"))?;
+            self.softwareOathSecretKey = Some(normalized);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_secret_key() {
+        assert_eq!(
+            normalize_secret_key("jbsw y3dp ehpk 3pxp").unwrap(),
+            "JBSWY3DPEHPK3PXP"
+        );
+        assert_eq!(
+            normalize_secret_key("JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP====").unwrap(),
+            "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP"
+        );
+        assert_eq!(normalize_secret_key("GEZDG-NBV").unwrap(), "GEZDGNBV");
+        assert!(normalize_secret_key("  ").is_err());
+        assert!(normalize_secret_key("JBSWY3DPEHPK3PX1").is_err());
+        assert!(normalize_secret_key("JBSWY3DPE").is_err());
+    }
+
+    #[test]
+    fn test_normalize_software_oath() {
+        let mut body: RequestBody = serde_json::from_str(
+            r#"{
+                "displayName": "Test User",
+                "passwordProfile": {"forceChangePasswordNextSignIn": false, "password": "Pass123!"},
+                "identities": [],
+                "softwareOathSecretKey": "jbsw y3dp ehpk 3pxp"
+            }"#,
+        )
+        .unwrap();
+        body.normalize_software_oath().unwrap();
+        assert_eq!(
+            body.softwareOathSecretKey.as_deref(),
+            Some("JBSWY3DPEHPK3PXP")
+        );
+        assert!(serde_json::to_value(&body)
+            .unwrap()
+            .get("softwareOathSecretKey")
+            .is_none());
+
+        body.softwareOathSecretKey = Some("not base32!".to_string());
+        assert!(body
+            .normalize_software_oath()
+            .unwrap_err()
+            .starts_with("invalid software OATH secret key"));
+    }
+}
@@ -0,0 +1,205 @@
+use rand::seq::{IndexedRandom, SliceRandom};
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+// Character classes accepted by the B2C password complexity policy.
+// Visually ambiguous characters are left out, since passwords may be read by humans.
+const LOWERCASE: &[u8] = b"abcdefghijkmnpqrstuvwxyz";
+const UPPERCASE: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ";
+const DIGITS: &[u8] = b"23456789";
+const SYMBOLS: &[u8] = b"@#$%^&*-_!+=[]{}|:',.?/`~();";
+
+// Length limits and number of character classes required by the B2C strong password policy
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_PASSWORD_LENGTH: usize = 64;
+const MIN_PASSWORD_CLASSES: usize = 3;
+
+/// A character class that generated passwords can draw from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CharClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+}
+
+impl CharClass {
+    fn alphabet(self) -> &'static [u8] {
+        match self {
+            CharClass::Lowercase => LOWERCASE,
+            CharClass::Uppercase => UPPERCASE,
+            CharClass::Digit => DIGITS,
+            CharClass::Symbol => SYMBOLS,
+        }
+    }
+}
+
+impl FromStr for CharClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "lower" | "lowercase" => Ok(CharClass::Lowercase),
+            "upper" | "uppercase" => Ok(CharClass::Uppercase),
+            "digit" | "digits" => Ok(CharClass::Digit),
+            "symbol" | "symbols" => Ok(CharClass::Symbol),
+            other => Err(format!("Unknown password character class: {other}")),
+        }
+    }
+}
+
+/// Length and character classes of generated passwords.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordPolicy {
+    length: usize,
+    classes: Vec<CharClass>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            length: 16,
+            classes: vec![
+                CharClass::Lowercase,
+                CharClass::Uppercase,
+                CharClass::Digit,
+                CharClass::Symbol,
+            ],
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Build a policy, checking it against the B2C complexity requirements:
+    /// 8 to 64 characters from at least 3 of the 4 character classes.
+    pub fn new(length: usize, mut classes: Vec<CharClass>) -> Result<PasswordPolicy, String> {
+        if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+            return Err(format!(
+                "Password length must be between {MIN_PASSWORD_LENGTH} and {MAX_PASSWORD_LENGTH}"
+            ));
+        }
+        classes.sort_by_key(|c| *c as u8);
+        classes.dedup();
+        if classes.len() < MIN_PASSWORD_CLASSES {
+            return Err(format!(
+                "Passwords need at least {MIN_PASSWORD_CLASSES} of the lower, upper, digit and symbol character classes"
+            ));
+        }
+        Ok(PasswordPolicy { length, classes })
+    }
+
+    /// Generate a random password with at least one character of every class.
+    pub fn generate(&self) -> String {
+        let mut rng = rand::rng();
+        let all: Vec<u8> = self
+            .classes
+            .iter()
+            .flat_map(|c| c.alphabet())
+            .copied()
+            .collect();
+
+        // One character from every class, then fill up from the whole alphabet
+        let mut password: Vec<u8> = self
+            .classes
+            .iter()
+            .map(|class| *class.alphabet().choose(&mut rng).unwrap())
+            .collect();
+        while password.len() < self.length {
+            password.push(*all.choose(&mut rng).unwrap());
+        }
+        password.shuffle(&mut rng);
+        String::from_utf8(password).unwrap()
+    }
+
+    /// Password profile with a generated password, to be changed at first sign-in.
+    pub fn password_profile(&self) -> Value {
+        json!({
+            "forceChangePasswordNextSignIn": true,
+            "password": self.generate(),
+        })
+    }
+}
+
+/// Whether the `passwordProfile` of a row is missing, empty or has no password.
+pub fn needs_password(row: &serde_json::Map<String, Value>) -> bool {
+    match row.get("passwordProfile") {
+        None | Some(Value::Null) => true,
+        Some(Value::String(s)) if s.trim().is_empty() => true,
+        Some(Value::String(s)) => serde_json::from_str::<Value>(s)
+            .map(|profile| missing_password(&profile))
+            .unwrap_or(false),
+        Some(profile) => missing_password(profile),
+    }
+}
+
+fn missing_password(profile: &Value) -> bool {
+    profile
+        .get("password")
+        .and_then(Value::as_str)
+        .is_none_or(|p| p.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_password_has_all_classes() {
+        let policy = PasswordPolicy::default();
+        for _ in 0..100 {
+            let password = policy.generate();
+            assert_eq!(password.len(), 16);
+            for class in [LOWERCASE, UPPERCASE, DIGITS, SYMBOLS] {
+                assert!(password.bytes().any(|b| class.contains(&b)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_password_with_three_classes() {
+        let policy = PasswordPolicy::new(
+            12,
+            vec![CharClass::Lowercase, CharClass::Uppercase, CharClass::Digit],
+        )
+        .unwrap();
+        let password = policy.generate();
+        assert_eq!(password.len(), 12);
+        assert!(password.bytes().all(|b| !SYMBOLS.contains(&b)));
+    }
+
+    #[test]
+    fn test_password_policy_rejects_weak_settings() {
+        assert!(PasswordPolicy::new(6, PasswordPolicy::default().classes).is_err());
+        assert!(PasswordPolicy::new(65, PasswordPolicy::default().classes).is_err());
+        assert!(PasswordPolicy::new(
+            16,
+            vec![CharClass::Lowercase, CharClass::Digit, CharClass::Digit]
+        )
+        .is_err());
+        assert_eq!("Symbols".parse::<CharClass>(), Ok(CharClass::Symbol));
+        assert!("emoji".parse::<CharClass>().is_err());
+    }
+
+    #[test]
+    fn test_generate_password_is_random() {
+        let policy = PasswordPolicy::default();
+        assert_ne!(policy.generate(), policy.generate());
+    }
+
+    #[test]
+    fn test_needs_password() {
+        let row =
+            |json: &str| serde_json::from_str::<serde_json::Map<String, Value>>(json).unwrap();
+        assert!(needs_password(&row(r#"{}"#)));
+        assert!(needs_password(&row(r#"{"passwordProfile": ""}"#)));
+        assert!(needs_password(&row(r#"{"passwordProfile": null}"#)));
+        assert!(needs_password(&row(
+            r#"{"passwordProfile": "{\"forceChangePasswordNextSignIn\": false, \"password\": \"\"}"}"#
+        )));
+        assert!(!needs_password(&row(
+            r#"{"passwordProfile": {"forceChangePasswordNextSignIn": false, "password": "pw"}}"#
+        )));
+        // Invalid JSON is left to the RequestBody validation
+        assert!(!needs_password(&row(r#"{"passwordProfile": "{oops"}"#)));
+    }
+}
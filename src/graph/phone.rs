@@ -0,0 +1,270 @@
+use crate::graph::RequestBody;
+use phonenumber::country;
+use serde_json::Value;
+
+/// Input columns holding the phone authentication methods. They are always
+/// read as text, so numbers keep their leading `+` or zeros.
+pub const PHONE_COLUMNS: [&str; 3] = [
+    "phoneAuthMethod",
+    "alternateMobilePhoneAuthMethod",
+    "officePhoneAuthMethod",
+];
+
+/// Types of the Graph phone authentication methods, one of each per user.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhoneType {
+    Mobile,
+    AlternateMobile,
+    Office,
+}
+
+impl PhoneType {
+    /// Value of `phoneType` in the Graph request.
+    pub fn as_graph(self) -> &'static str {
+        match self {
+            PhoneType::Mobile => "mobile",
+            PhoneType::AlternateMobile => "alternateMobile",
+            PhoneType::Office => "office",
+        }
+    }
+}
+
+impl RequestBody {
+    /// Phone methods of the user with a value, in creation order: the
+    /// alternate mobile can only be added once the mobile exists.
+    pub fn phone_auth_methods(&self) -> Vec<(PhoneType, &str)> {
+        [
+            (PhoneType::Mobile, &self.phoneAuthMethod),
+            (
+                PhoneType::AlternateMobile,
+                &self.alternateMobilePhoneAuthMethod,
+            ),
+            (PhoneType::Office, &self.officePhoneAuthMethod),
+        ]
+        .into_iter()
+        .filter_map(|(phone_type, number)| {
+            number
+                .as_deref()
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .map(|n| (phone_type, n))
+        })
+        .collect()
+    }
+}
+
+/// Format a phone number as Graph expects it: `+<country code> <number>`,
+/// with an optional `x<extension>`. Numbers without an international prefix
+/// are read as numbers of `region`, when given.
+///
+/// Numbers only need to be parseable, not assigned: Graph checks the format
+/// alone, and test ranges such as `+1 555...` must keep working.
+pub fn format_phone_number(raw: &str, region: Option<country::Id>) -> Result<String, String> {
+    let number = phonenumber::parse(region, raw).map_err(|e| e.to_string())?;
+    let mut formatted = format!("+{} {}", number.code().value(), number.national());
+    if let Some(extension) = number.extension() {
+        formatted.push('x');
+        formatted.push_str(extension);
+    }
+    Ok(formatted)
+}
+
+/// A phone number rewritten by `PhoneNormalizer`, kept for the logs.
+#[derive(Debug, PartialEq)]
+pub struct NormalizedPhone {
+    pub field: String,
+    pub original: String,
+    pub normalized: String,
+}
+
+/// Normalisation of the phone numbers of a user before it is sent: the
+/// phone authentication methods plus any other configured field (e.g.
+/// `mobilePhone` or `businessPhones`).
+#[derive(Debug, Clone, Default)]
+pub struct PhoneNormalizer {
+    region: Option<country::Id>,
+    fields: Vec<String>,
+}
+
+impl PhoneNormalizer {
+    /// `region` is the ISO 3166 code (e.g. `IT`) of numbers written without
+    /// an international prefix.
+    pub fn new(region: Option<&str>, fields: Vec<String>) -> Result<PhoneNormalizer, String> {
+        let region = match region {
+            Some(region) => Some(
+                region
+                    .trim()
+                    .to_ascii_uppercase()
+                    .parse::<country::Id>()
+                    .map_err(|_| format!("Unknown phone region: {region}"))?,
+            ),
+            None => None,
+        };
+        Ok(PhoneNormalizer { region, fields })
+    }
+
+    /// Custom fields holding phone numbers, to be read as text.
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Normalise the phone numbers of `body` in place, returning the values
+    /// that changed. Fails on the first number that cannot be parsed.
+    pub fn apply(&self, body: &mut RequestBody) -> Result<Vec<NormalizedPhone>, String> {
+        let mut changes = Vec::new();
+        let mut normalize = |field: &str, value: &mut String| -> Result<(), String> {
+            if value.trim().is_empty() {
+                return Ok(());
+            }
+            let normalized = format_phone_number(value, self.region)
+                .map_err(|e| format!("invalid phone number in {field}: {e}"))?;
+            if normalized != *value {
+                changes.push(NormalizedPhone {
+                    field: field.to_string(),
+                    original: std::mem::replace(value, normalized.clone()),
+                    normalized,
+                });
+            }
+            Ok(())
+        };
+
+        for (field, value) in [
+            ("phoneAuthMethod", &mut body.phoneAuthMethod),
+            (
+                "alternateMobilePhoneAuthMethod",
+                &mut body.alternateMobilePhoneAuthMethod,
+            ),
+            ("officePhoneAuthMethod", &mut body.officePhoneAuthMethod),
+        ] {
+            if let Some(value) = value {
+                normalize(field, value)?;
+            }
+        }
+        for field in &self.fields {
+            let Some(value) = body.custom_fields.get_mut(field) else {
+                continue;
+            };
+            // Single numbers (possibly inferred as integers) or lists like businessPhones
+            let values: Vec<&mut Value> = match value {
+                Value::Array(list) => list.iter_mut().collect(),
+                v => vec![v],
+            };
+            for value in values {
+                let mut text = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Number(n) => n.to_string(),
+                    _ => continue,
+                };
+                normalize(field, &mut text)?;
+                *value = Value::String(text);
+            }
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_phone_number() {
+        assert_eq!(
+            format_phone_number("+15555551234", None),
+            Ok("+1 5555551234".to_string())
+        );
+        assert_eq!(
+            format_phone_number("+1 (201) 555-0123", None),
+            Ok("+1 2015550123".to_string())
+        );
+        assert_eq!(
+            format_phone_number("+44 7700 900123", None),
+            Ok("+44 7700900123".to_string())
+        );
+        // Italian numbers keep their leading zero
+        assert_eq!(
+            format_phone_number("+39 06 6982 1234", None),
+            Ok("+39 0669821234".to_string())
+        );
+        assert_eq!(
+            format_phone_number("+1 201-555-0123 ext. 42", None),
+            Ok("+1 2015550123x42".to_string())
+        );
+        assert!(format_phone_number("555-0123", None).is_err());
+    }
+
+    #[test]
+    fn test_format_phone_number_with_region() {
+        let italy = Some(country::Id::IT);
+        assert_eq!(
+            format_phone_number("0039 347 1234567", italy),
+            Ok("+39 3471234567".to_string())
+        );
+        assert_eq!(
+            format_phone_number("347 1234567", italy),
+            Ok("+39 3471234567".to_string())
+        );
+        assert_eq!(
+            format_phone_number("(555) 123-4567", Some(country::Id::US)),
+            Ok("+1 5551234567".to_string())
+        );
+        assert_eq!(
+            format_phone_number("+44 7700 900123", italy),
+            Ok("+44 7700900123".to_string())
+        );
+    }
+
+    #[test]
+    fn test_phone_normalizer() {
+        let normalizer =
+            PhoneNormalizer::new(Some("it"), vec!["businessPhones".to_string()]).unwrap();
+        let mut body: RequestBody = serde_json::from_str(
+            r#"{"displayName": "Jane", "passwordProfile": "{\"forceChangePasswordNextSignIn\": true, \"password\": \"pw\"}",
+                "identities": "[]", "phoneAuthMethod": "0039 347 1234567", "officePhoneAuthMethod": "+39 0669821234",
+                "businessPhones": ["06 6982 1234"]}"#,
+        )
+        .unwrap();
+        let changes = normalizer.apply(&mut body).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                NormalizedPhone {
+                    field: "phoneAuthMethod".to_string(),
+                    original: "0039 347 1234567".to_string(),
+                    normalized: "+39 3471234567".to_string(),
+                },
+                NormalizedPhone {
+                    field: "businessPhones".to_string(),
+                    original: "06 6982 1234".to_string(),
+                    normalized: "+39 0669821234".to_string(),
+                },
+            ]
+        );
+        assert_eq!(body.phoneAuthMethod.as_deref(), Some("+39 3471234567"));
+        assert_eq!(body.custom_fields["businessPhones"][0], "+39 0669821234");
+
+        body.alternateMobilePhoneAuthMethod = Some("call me".to_string());
+        assert_eq!(
+            normalizer.apply(&mut body).unwrap_err(),
+            "invalid phone number in alternateMobilePhoneAuthMethod: not a number"
+        );
+        assert!(PhoneNormalizer::new(Some("XX"), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_phone_auth_methods_order() {
+        let body: RequestBody = serde_json::from_str(
+            r#"{"displayName": "Jane", "passwordProfile": "{\"forceChangePasswordNextSignIn\": true, \"password\": \"pw\"}",
+                "identities": "[]", "officePhoneAuthMethod": "+1 2015550100", "phoneAuthMethod": " ",
+                "alternateMobilePhoneAuthMethod": 12015550199}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            body.phone_auth_methods(),
+            vec![
+                (PhoneType::AlternateMobile, "12015550199"),
+                (PhoneType::Office, "+1 2015550100"),
+            ]
+        );
+    }
+}
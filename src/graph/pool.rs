@@ -0,0 +1,446 @@
+use crate::graph::{
+    back_off, limiter, rate_limit, record_outcome, wait_if_paused, ClientCredentials,
+    GraphEndpoint, RateLimits, RequestKind, TokenBucket, TokenCache,
+};
+use crate::run::run_context;
+use log::{info, warn};
+use serde::Deserialize;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{fs, path::Path};
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// Mirrors the keys that appear in the app pool TOML configuration file.
+///
+/// Example file:
+/// ```toml
+/// tenant_id = "contoso.onmicrosoft.com"
+///
+/// [[app]]
+/// name              = "migrator-1"
+/// client_id         = "00000000-0000-0000-0000-000000000001"
+/// client_secret_env = "MIGRATOR_1_SECRET"
+///
+/// [[app]]
+/// name          = "migrator-2"
+/// client_id     = "00000000-0000-0000-0000-000000000002"
+/// client_secret = "..."
+/// users_rps     = 10
+/// ```
+#[derive(Debug, Deserialize, Clone)]
+pub struct AppPoolConfig {
+    pub tenant_id: Option<String>,
+    #[serde(rename = "app")]
+    pub apps: Vec<AppConfig>,
+}
+
+/// One app registration of the pool.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AppConfig {
+    pub name: Option<String>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub client_secret_env: Option<String>,
+    pub tenant_id: Option<String>,
+    pub users_rps: Option<f64>,
+    pub methods_rps: Option<f64>,
+}
+
+/// Load and parse an app pool configuration file.
+///
+/// # Errors
+/// * I/O failures while reading the file
+/// * TOML-syntax or type mismatches while parsing
+/// * a pool without apps
+pub fn app_pool_load_config<P: AsRef<Path>>(path: P) -> Result<AppPoolConfig, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let config = toml::from_str::<AppPoolConfig>(&contents)?;
+    if config.apps.is_empty() {
+        return Err("The app pool has no [[app]] entries".into());
+    }
+    Ok(config)
+}
+
+impl AppConfig {
+    fn secret(&self) -> Result<String, String> {
+        match (&self.client_secret, &self.client_secret_env) {
+            (Some(secret), _) => Ok(secret.clone()),
+            (None, Some(var)) => std::env::var(var).map_err(|_| {
+                format!(
+                    "Environment variable {var} of {} is not set",
+                    self.client_id
+                )
+            }),
+            (None, None) => Err(format!(
+                "App {} has no client_secret or client_secret_env",
+                self.client_id
+            )),
+        }
+    }
+}
+
+/// Request counters of an app, for the final report.
+#[derive(Default)]
+pub struct AppStats {
+    pub requests: AtomicU64,
+    pub successes: AtomicU64,
+    pub throttled: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+/// App registration of the pool with its own token, budgets and throttling state.
+pub struct AppCredential {
+    pub name: String,
+    tokens: TokenCache,
+    limits: RateLimits,
+    paused_until: Mutex<Option<Instant>>,
+    pub stats: AppStats,
+}
+
+impl AppCredential {
+    pub fn new(
+        name: &str,
+        tokens: TokenCache,
+        users_rps: Option<f64>,
+        methods_rps: Option<f64>,
+    ) -> Result<AppCredential, String> {
+        Ok(AppCredential {
+            name: name.to_string(),
+            tokens,
+            limits: RateLimits {
+                users: TokenBucket::with_rate(users_rps)?,
+                methods: TokenBucket::with_rate(methods_rps)?,
+            },
+            paused_until: Mutex::new(None),
+            stats: AppStats::default(),
+        })
+    }
+
+    fn paused_until(&self) -> Option<Instant> {
+        self.paused_until
+            .lock()
+            .unwrap()
+            .filter(|until| *until > Instant::now())
+    }
+
+    /// Record a throttled request, pausing this app only.
+    fn throttled(&self, retry_after: Option<Duration>) {
+        self.stats.throttled.fetch_add(1, Ordering::Relaxed);
+        if let Some(retry_after) = retry_after {
+            let until = Instant::now() + retry_after;
+            let mut paused_until = self.paused_until.lock().unwrap();
+            if paused_until.is_none_or(|p| p < until) {
+                *paused_until = Some(until);
+                warn!(
+                    "App {} throttled, paused for {} seconds.",
+                    self.name,
+                    retry_after.as_secs()
+                );
+            }
+        }
+    }
+}
+
+/// App registrations sharing the load of a run, each with its own quota.
+pub struct AppPool {
+    apps: Vec<AppCredential>,
+    next: AtomicUsize,
+}
+
+impl AppPool {
+    pub fn new(apps: Vec<AppCredential>) -> AppPool {
+        AppPool {
+            apps,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Acquire a token for every app of the configuration, each renewed
+    /// before it expires. The rates of an app default to `users_rps` and
+    /// `methods_rps`.
+    pub async fn connect(
+        config: &AppPoolConfig,
+        client: &reqwest::Client,
+        authority: &str,
+        graph: &GraphEndpoint,
+        users_rps: Option<f64>,
+        methods_rps: Option<f64>,
+    ) -> Result<AppPool, Box<dyn Error>> {
+        let mut apps = Vec::new();
+        for app in &config.apps {
+            let name = app.name.as_deref().unwrap_or(&app.client_id);
+            let tenant_id = app
+                .tenant_id
+                .as_ref()
+                .or(config.tenant_id.as_ref())
+                .ok_or_else(|| format!("App {name} has no tenant_id"))?;
+            let credentials = ClientCredentials {
+                authority: authority.to_string(),
+                tenant_id: tenant_id.clone(),
+                client_id: app.client_id.clone(),
+                client_secret: app.secret()?,
+                graph: graph.clone(),
+            };
+            let tokens = TokenCache::connect(client, credentials).await?;
+            info!("Token acquired from {authority} for app {name}.");
+            apps.push(AppCredential::new(
+                name,
+                tokens,
+                app.users_rps.or(users_rps),
+                app.methods_rps.or(methods_rps),
+            )?);
+        }
+        Ok(AppPool::new(apps))
+    }
+
+    /// Next app in turn that is not paused, waiting for the first one to
+    /// resume when all of them are.
+    pub async fn select(&self) -> &AppCredential {
+        loop {
+            let start = self.next.fetch_add(1, Ordering::Relaxed);
+            let mut resume_at: Option<Instant> = None;
+            for offset in 0..self.apps.len() {
+                let app = &self.apps[(start + offset) % self.apps.len()];
+                match app.paused_until() {
+                    None => return app,
+                    Some(until) => resume_at = Some(resume_at.map_or(until, |r| r.min(until))),
+                }
+            }
+            if let Some(resume_at) = resume_at {
+                sleep_until(resume_at).await;
+            }
+        }
+    }
+
+    /// Set the rate of the requests of `kind` of every app.
+    pub fn set_rate(&self, kind: RequestKind, rate: Option<f64>) -> Result<(), String> {
+        for app in &self.apps {
+            app.limits.bucket(kind).set_rate(rate)?;
+        }
+        Ok(())
+    }
+
+    /// Rate of the requests of `kind` of the first app, which is the rate of
+    /// every app once set with `set_rate`.
+    pub fn rate(&self, kind: RequestKind) -> Option<f64> {
+        self.apps
+            .first()
+            .and_then(|app| app.limits.bucket(kind).rate())
+    }
+
+    /// Number of apps of the pool.
+    pub fn app_count(&self) -> usize {
+        self.apps.len()
+    }
+
+    fn all_paused(&self) -> bool {
+        self.apps.iter().all(|app| app.paused_until().is_some())
+    }
+
+    /// Log the request counters of every app.
+    pub fn log_stats(&self) {
+        for app in &self.apps {
+            let stats = &app.stats;
+            info!(
+                "App {}: {} requests, {} succeeded, {} throttled, {} failed.",
+                app.name,
+                stats.requests.load(Ordering::Relaxed),
+                stats.successes.load(Ordering::Relaxed),
+                stats.throttled.load(Ordering::Relaxed),
+                stats.errors.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+/// App pool of the run, if one was installed.
+pub fn app_pool() -> Option<&'static AppPool> {
+    run_context()?.app_pool.as_ref()
+}
+
+/// Credential of a single Graph request: an app of the pool, or the token
+/// of the run.
+pub struct RequestAuth<'a> {
+    token: String,
+    tokens: &'a TokenCache,
+    app: Option<&'a AppCredential>,
+}
+
+/// Pick the credential of the next request of `kind`, waiting for its pause
+/// and rate limit. `tokens` are those of the run, unused with an app pool.
+pub async fn authorize(tokens: &TokenCache, kind: RequestKind) -> RequestAuth<'_> {
+    match app_pool() {
+        Some(pool) => authorize_with(pool, kind).await,
+        None => {
+            wait_if_paused().await;
+            rate_limit(kind).await;
+            RequestAuth {
+                token: tokens.token().await,
+                tokens,
+                app: None,
+            }
+        }
+    }
+}
+
+async fn authorize_with(pool: &AppPool, kind: RequestKind) -> RequestAuth<'_> {
+    let app = pool.select().await;
+    app.limits.bucket(kind).acquire().await;
+    app.stats.requests.fetch_add(1, Ordering::Relaxed);
+    RequestAuth {
+        token: app.tokens.token().await,
+        tokens: &app.tokens,
+        app: Some(app),
+    }
+}
+
+impl RequestAuth<'_> {
+    /// Value of the `Authorization` header.
+    pub fn header(&self) -> String {
+        format!("Bearer {}", self.token)
+    }
+
+    /// Back off after a throttled request. An app of the pool is paused on
+    /// its own, the others keep going: the shared concurrency limit only
+    /// shrinks once every app is throttled.
+    pub async fn back_off(&self, retry_after: Option<Duration>) {
+        match (self.app, app_pool()) {
+            (Some(app), Some(pool)) => {
+                app.throttled(retry_after);
+                if pool.all_paused() {
+                    if let Some(limiter) = limiter() {
+                        limiter.throttled(None);
+                    }
+                }
+            }
+            _ => back_off(retry_after).await,
+        }
+    }
+
+    /// Renew the token after Graph rejected it with a 401, returning whether
+    /// the request can be retried. A token given as is cannot be renewed.
+    pub async fn renew(&self) -> bool {
+        match self.tokens.renew(&self.token).await {
+            Ok(_) => true,
+            Err(e) => {
+                match self.app {
+                    Some(app) => warn!("App {}: {e}", app.name),
+                    None => warn!("{e}"),
+                }
+                false
+            }
+        }
+    }
+
+    /// Record the outcome of a request that was not throttled.
+    pub fn record_outcome(&self, success: bool, latency: Duration) {
+        record_outcome(success, latency);
+        if let Some(app) = self.app {
+            let counter = if success {
+                &app.stats.successes
+            } else {
+                &app.stats.errors
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_select_skips_throttled_apps() {
+        let pool = AppPool::new(vec![
+            AppCredential::new("a", TokenCache::fixed("token-a"), None, None).unwrap(),
+            AppCredential::new("b", TokenCache::fixed("token-b"), None, None).unwrap(),
+        ]);
+        let first = authorize_with(&pool, RequestKind::UserCreation).await;
+        let second = authorize_with(&pool, RequestKind::UserCreation).await;
+        assert_eq!(first.header(), "Bearer token-a");
+        assert_eq!(second.header(), "Bearer token-b");
+
+        // Only the throttled app is paused
+        pool.apps[0].throttled(Some(Duration::from_secs(10)));
+        for _ in 0..3 {
+            let auth = authorize_with(&pool, RequestKind::AuthMethod).await;
+            assert_eq!(auth.header(), "Bearer token-b");
+        }
+        assert!(!pool.all_paused());
+
+        // With every app paused, the first one to resume is used
+        pool.apps[1].throttled(Some(Duration::from_secs(20)));
+        assert!(pool.all_paused());
+        let start = Instant::now();
+        let auth = authorize_with(&pool, RequestKind::UserCreation).await;
+        assert_eq!(auth.header(), "Bearer token-a");
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+
+        auth.record_outcome(true, Duration::from_millis(100));
+        let stats = &pool.apps[0].stats;
+        assert_eq!(stats.requests.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.successes.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.throttled.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_acquires_a_token_per_app() {
+        let mut server = mockito::Server::new_async().await;
+        let mock_a = server
+            .mock("POST", "/contoso/oauth2/v2.0/token")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "client_id".into(),
+                "client-a".into(),
+            ))
+            .with_body(r#"{"access_token": "token-a"}"#)
+            .create_async()
+            .await;
+        let mock_b = server
+            .mock("POST", "/fabrikam/oauth2/v2.0/token")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "client_id".into(),
+                "client-b".into(),
+            ))
+            .with_body(r#"{"access_token": "token-b"}"#)
+            .create_async()
+            .await;
+        let config: AppPoolConfig = toml::from_str(
+            r#"
+            tenant_id = "contoso"
+
+            [[app]]
+            name = "a"
+            client_id = "client-a"
+            client_secret = "secret-a"
+
+            [[app]]
+            client_id = "client-b"
+            client_secret = "secret-b"
+            tenant_id = "fabrikam"
+            users_rps = 2.5
+            "#,
+        )
+        .unwrap();
+        let pool = AppPool::connect(
+            &config,
+            &reqwest::Client::new(),
+            &server.url(),
+            &GraphEndpoint::new("https://graph.microsoft.com", "v1.0"),
+            Some(10.0),
+            None,
+        )
+        .await
+        .unwrap();
+        mock_a.assert_async().await;
+        mock_b.assert_async().await;
+
+        assert_eq!(pool.apps[0].name, "a");
+        assert_eq!(pool.apps[0].tokens.token().await, "token-a");
+        assert_eq!(pool.apps[0].limits.users.rate(), Some(10.0));
+        assert_eq!(pool.apps[1].name, "client-b");
+        assert_eq!(pool.apps[1].limits.users.rate(), Some(2.5));
+        assert_eq!(pool.apps[1].limits.methods.rate(), None);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::run::run_context;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+/// Token bucket refilled at a steady rate, allowing bursts of up to one
+/// second of requests.
+///
+/// Callers reserve their token up front, so the bucket may go negative:
+/// each one then sleeps until its own token is due, in arrival order.
+///
+/// The rate can be changed, or removed, while the run goes on.
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /// Requests per second, `None` meaning unlimited
+    rate: Option<f64>,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+fn check_rate(rate: f64) -> Result<f64, String> {
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(format!("Invalid rate {rate}, it must be a positive number"));
+    }
+    Ok(rate)
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        TokenBucket::unlimited()
+    }
+}
+
+impl TokenBucket {
+    /// Bucket allowing `rate` requests per second.
+    pub fn new(rate: f64) -> Result<TokenBucket, String> {
+        let rate = check_rate(rate)?;
+        Ok(TokenBucket {
+            state: Mutex::new(BucketState {
+                rate: Some(rate),
+                tokens: rate.max(1.0),
+                refilled_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Bucket letting every request through.
+    pub fn unlimited() -> TokenBucket {
+        TokenBucket {
+            state: Mutex::new(BucketState {
+                rate: None,
+                tokens: 0.0,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Bucket allowing `rate` requests per second, if any.
+    pub fn with_rate(rate: Option<f64>) -> Result<TokenBucket, String> {
+        rate.map_or(Ok(TokenBucket::unlimited()), TokenBucket::new)
+    }
+
+    /// Requests per second allowed, `None` meaning unlimited.
+    pub fn rate(&self) -> Option<f64> {
+        self.state.lock().unwrap().rate
+    }
+
+    /// Change the rate. A new limit starts with a full burst.
+    pub fn set_rate(&self, rate: Option<f64>) -> Result<(), String> {
+        let rate = rate.map(check_rate).transpose()?;
+        let mut state = self.state.lock().unwrap();
+        if state.rate.is_none() {
+            state.tokens = rate.map_or(0.0, |rate| rate.max(1.0));
+            state.refilled_at = Instant::now();
+        }
+        state.rate = rate;
+        Ok(())
+    }
+
+    /// Wait until a request may be sent.
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let Some(rate) = state.rate else {
+                return;
+            };
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate).min(rate.max(1.0));
+            state.refilled_at = now;
+            state.tokens -= 1.0;
+            (state.tokens < 0.0).then(|| Duration::from_secs_f64(-state.tokens / rate))
+        };
+        if let Some(wait) = wait {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Kinds of Graph writes with their own budget.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestKind {
+    /// `POST /users`
+    UserCreation,
+    /// `POST /users/{id}/authentication/...`
+    AuthMethod,
+}
+
+impl RequestKind {
+    /// Name of the budget of the kind, as parsed.
+    pub fn label(&self) -> &'static str {
+        match self {
+            RequestKind::UserCreation => "users",
+            RequestKind::AuthMethod => "methods",
+        }
+    }
+}
+
+impl FromStr for RequestKind {
+    type Err = String;
+
+    /// Kind named after its budget option: `users` or `methods`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "users" => Ok(RequestKind::UserCreation),
+            "methods" => Ok(RequestKind::AuthMethod),
+            other => Err(format!(
+                "Unknown request kind {other}: expected users or methods"
+            )),
+        }
+    }
+}
+
+/// Request-per-second budgets of a run, unlimited by default.
+#[derive(Default)]
+pub struct RateLimits {
+    pub users: TokenBucket,
+    pub methods: TokenBucket,
+}
+
+impl RateLimits {
+    /// Budget of the requests of `kind`.
+    pub fn bucket(&self, kind: RequestKind) -> &TokenBucket {
+        match kind {
+            RequestKind::UserCreation => &self.users,
+            RequestKind::AuthMethod => &self.methods,
+        }
+    }
+}
+
+/// Rate limits of the run, if they were installed.
+pub fn rate_limits() -> Option<&'static RateLimits> {
+    run_context()?.rate_limits.as_ref()
+}
+
+/// Wait for the budget of `kind` before sending a request.
+pub async fn rate_limit(kind: RequestKind) {
+    if let Some(limits) = rate_limits() {
+        limits.bucket(kind).acquire().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket_spaces_requests() {
+        let bucket = TokenBucket::new(2.0).unwrap();
+        let start = Instant::now();
+        let mut sent = Vec::new();
+        for _ in 0..5 {
+            bucket.acquire().await;
+            sent.push(start.elapsed());
+        }
+        // Two requests of burst, then one every half second
+        assert_eq!(
+            sent,
+            [0, 0, 500, 1000, 1500].map(Duration::from_millis).to_vec()
+        );
+
+        // An idle bucket refills up to its burst only
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let start = Instant::now();
+        for _ in 0..3 {
+            bucket.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket_below_one_per_second() {
+        let bucket = TokenBucket::new(0.5).unwrap();
+        let start = Instant::now();
+        bucket.acquire().await;
+        bucket.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert!(TokenBucket::new(0.0).is_err());
+        assert!(TokenBucket::new(f64::NAN).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_changes_while_running() {
+        let bucket = TokenBucket::unlimited();
+        let start = Instant::now();
+        for _ in 0..10 {
+            bucket.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        bucket.set_rate(Some(1.0)).unwrap();
+        assert_eq!(bucket.rate(), Some(1.0));
+        let start = Instant::now();
+        for _ in 0..3 {
+            bucket.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        assert!(bucket.set_rate(Some(-1.0)).is_err());
+        bucket.set_rate(None).unwrap();
+        let start = Instant::now();
+        bucket.acquire().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,427 @@
+use crate::run::run_context;
+use log::{info, warn};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+/// Throttling responses arriving this soon after a decrease come from the
+/// same burst of requests and do not shrink the limit again.
+const DECREASE_COOLDOWN: Duration = Duration::from_secs(1);
+
+/// Latency above this multiple of the best average seen is treated as
+/// congestion, and stops the limit from growing.
+const LATENCY_TOLERANCE: u32 = 2;
+
+/// AIMD concurrency limiter shared by every worker of a run.
+///
+/// * the limit grows by one after a full round of healthy requests (as many
+///   successes as the current limit, with no errors and normal latency)
+/// * it is halved on throttling (429/503), at most once per burst
+/// * a `Retry-After` pauses every worker until it expires, instead of each
+///   task sleeping on its own
+///
+/// The dispatch of new tasks can also be held (by hand, or outside the
+/// migration window), and the ceiling of the limit lowered, while the run
+/// goes on. Once closed, no task is dispatched anymore.
+pub struct AdaptiveLimiter {
+    min: usize,
+    max: usize,
+    state: Mutex<LimiterState>,
+    notify: Notify,
+}
+
+struct LimiterState {
+    limit: usize,
+    ceiling: usize,
+    held: bool,
+    outside_window: bool,
+    closed: bool,
+    in_flight: usize,
+    successes: usize,
+    latency: Option<Duration>,
+    best_latency: Option<Duration>,
+    paused_until: Option<Instant>,
+    decreased_at: Option<Instant>,
+}
+
+/// Slot of a running task, released on drop.
+pub struct LimiterPermit<'a> {
+    limiter: &'a AdaptiveLimiter,
+}
+
+impl Drop for LimiterPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.notify.notify_waiters();
+    }
+}
+
+impl AdaptiveLimiter {
+    /// Start at `initial` concurrent tasks, moving between `min` and `max`.
+    pub fn new(initial: usize, min: usize, max: usize) -> AdaptiveLimiter {
+        let min = min.max(1);
+        let max = max.max(min);
+        AdaptiveLimiter {
+            min,
+            max,
+            state: Mutex::new(LimiterState {
+                limit: initial.clamp(min, max),
+                ceiling: max,
+                held: false,
+                outside_window: false,
+                closed: false,
+                in_flight: 0,
+                successes: 0,
+                latency: None,
+                best_latency: None,
+                paused_until: None,
+                decreased_at: None,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Current concurrency limit.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Number of running tasks.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    /// Highest value the limit can grow to.
+    pub fn ceiling(&self) -> usize {
+        self.state.lock().unwrap().ceiling
+    }
+
+    /// Set the highest value the limit can grow to, between the minimum and
+    /// the maximum, lowering the limit at once if needed. Running tasks are
+    /// not interrupted.
+    pub fn set_ceiling(&self, ceiling: usize) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.ceiling = ceiling.clamp(self.min, self.max);
+        state.limit = state.limit.min(state.ceiling);
+        state.successes = 0;
+        info!(
+            "Concurrency limit capped at {} (currently {}).",
+            state.ceiling, state.limit
+        );
+        let ceiling = state.ceiling;
+        drop(state);
+        self.notify.notify_waiters();
+        ceiling
+    }
+
+    /// Hold or release the dispatch of new tasks. Running tasks go on.
+    pub fn hold(&self, held: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.held == held {
+            return;
+        }
+        state.held = held;
+        drop(state);
+        if held {
+            warn!("Dispatch of new users paused.");
+        } else {
+            info!("Dispatch of new users resumed.");
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Whether the dispatch of new tasks is held.
+    pub fn is_held(&self) -> bool {
+        self.state.lock().unwrap().held
+    }
+
+    /// Hold the dispatch of new tasks while the migration window is closed,
+    /// apart from the holds by hand.
+    pub fn set_outside_window(&self, outside: bool) {
+        self.state.lock().unwrap().outside_window = outside;
+        self.notify.notify_waiters();
+    }
+
+    /// Whether the dispatch waits for the migration window to open.
+    pub fn is_outside_window(&self) -> bool {
+        self.state.lock().unwrap().outside_window
+    }
+
+    /// Stop dispatching tasks: waiting and later calls to `acquire` get no
+    /// permit. Running tasks go on.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_waiters();
+    }
+
+    /// Whether the limiter was closed.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Time left before the end of the current throttling pause, if any.
+    pub fn paused_for(&self) -> Option<Duration> {
+        let paused_until = self.state.lock().unwrap().paused_until?;
+        let now = Instant::now();
+        (paused_until > now).then(|| paused_until - now)
+    }
+
+    /// Wait for a free slot, and for the end of any pause. `None` once the
+    /// limiter is closed.
+    pub async fn acquire(&self) -> Option<LimiterPermit<'_>> {
+        loop {
+            // Registered before the check, so a release in between is not missed
+            let notified = self.notify.notified();
+            let paused_until = {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                match state.paused_until {
+                    Some(until) if until > Instant::now() => Some(until),
+                    _ if state.held || state.outside_window => None,
+                    _ if state.in_flight < state.limit => {
+                        state.in_flight += 1;
+                        return Some(LimiterPermit { limiter: self });
+                    }
+                    _ => None,
+                }
+            };
+            match paused_until {
+                Some(until) => sleep_until(until).await,
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Wait for the end of the current pause, if any.
+    pub async fn wait_if_paused(&self) {
+        let paused_until = self.state.lock().unwrap().paused_until;
+        if let Some(until) = paused_until {
+            sleep_until(until).await;
+        }
+    }
+
+    /// Record a successful request and its latency.
+    pub fn success(&self, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        // Exponentially weighted average, smoothing single slow responses
+        let average = match state.latency {
+            Some(average) => (average * 7 + latency) / 8,
+            None => latency,
+        };
+        state.latency = Some(average);
+        let best = state.best_latency.map_or(average, |b| b.min(average));
+        state.best_latency = Some(best);
+        if average > best * LATENCY_TOLERANCE {
+            state.successes = 0;
+            return;
+        }
+        state.successes += 1;
+        if state.successes >= state.limit && state.limit < state.ceiling {
+            state.limit += 1;
+            state.successes = 0;
+            info!("Concurrency limit raised to {}.", state.limit);
+            drop(state);
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Record a failed request: the current round is not healthy.
+    pub fn error(&self) {
+        self.state.lock().unwrap().successes = 0;
+    }
+
+    /// Record a throttled request: halve the limit and, with a `Retry-After`,
+    /// pause every worker.
+    pub fn throttled(&self, retry_after: Option<Duration>) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.successes = 0;
+        let same_burst = state.paused_until.is_some_and(|p| p > now)
+            || state
+                .decreased_at
+                .is_some_and(|at| now.duration_since(at) < DECREASE_COOLDOWN);
+        if let Some(retry_after) = retry_after {
+            let until = now + retry_after;
+            if state.paused_until.is_none_or(|p| p < until) {
+                state.paused_until = Some(until);
+            }
+        }
+        if same_burst || state.limit == self.min {
+            return;
+        }
+        state.limit = (state.limit / 2).max(self.min);
+        state.decreased_at = Some(now);
+        match retry_after {
+            Some(retry_after) => warn!(
+                "Throttled, concurrency limit lowered to {}. All requests paused for {} seconds.",
+                state.limit,
+                retry_after.as_secs()
+            ),
+            None => warn!("Throttled, concurrency limit lowered to {}.", state.limit),
+        }
+    }
+}
+
+/// Limiter of the run, if one was installed.
+pub fn limiter() -> Option<&'static AdaptiveLimiter> {
+    run_context()?.limiter.as_ref()
+}
+
+/// Wait for the end of a global pause before sending a request.
+pub async fn wait_if_paused() {
+    if let Some(limiter) = limiter() {
+        limiter.wait_if_paused().await;
+    }
+}
+
+/// Back off after a throttled request, until it can be retried. Without a
+/// limiter the task simply sleeps for `retry_after`.
+pub async fn back_off(retry_after: Option<Duration>) {
+    match limiter() {
+        Some(limiter) => {
+            limiter.throttled(retry_after);
+            limiter.wait_if_paused().await;
+        }
+        None => {
+            if let Some(retry_after) = retry_after {
+                sleep(retry_after).await;
+            }
+        }
+    }
+}
+
+/// Record the outcome of a request that was not throttled.
+pub fn record_outcome(success: bool, latency: Duration) {
+    if let Some(limiter) = limiter() {
+        if success {
+            limiter.success(latency);
+        } else {
+            limiter.error();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_grows_after_healthy_rounds() {
+        let limiter = AdaptiveLimiter::new(2, 1, 3);
+        let latency = Duration::from_millis(100);
+        limiter.success(latency);
+        assert_eq!(limiter.limit(), 2);
+        limiter.success(latency);
+        assert_eq!(limiter.limit(), 3);
+        // An error restarts the round
+        limiter.success(latency);
+        limiter.error();
+        limiter.success(latency);
+        limiter.success(latency);
+        assert_eq!(limiter.limit(), 3);
+        // Capped at the maximum
+        limiter.success(latency);
+        assert_eq!(limiter.limit(), 3);
+    }
+
+    #[test]
+    fn test_slow_responses_stop_growth() {
+        let limiter = AdaptiveLimiter::new(1, 1, 10);
+        limiter.success(Duration::from_millis(100));
+        assert_eq!(limiter.limit(), 2);
+        for _ in 0..4 {
+            limiter.success(Duration::from_secs(5));
+        }
+        assert_eq!(limiter.limit(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttling_halves_once_per_burst_and_pauses() {
+        let limiter = AdaptiveLimiter::new(16, 2, 32);
+        limiter.throttled(Some(Duration::from_secs(5)));
+        assert_eq!(limiter.limit(), 8);
+        // The rest of the burst is ignored
+        limiter.throttled(Some(Duration::from_secs(5)));
+        limiter.throttled(None);
+        assert_eq!(limiter.limit(), 8);
+
+        let start = Instant::now();
+        let _permit = limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_secs(5));
+
+        limiter.throttled(None);
+        assert_eq!(limiter.limit(), 4);
+        tokio::time::advance(DECREASE_COOLDOWN).await;
+        limiter.throttled(None);
+        tokio::time::advance(DECREASE_COOLDOWN).await;
+        limiter.throttled(None);
+        assert_eq!(limiter.limit(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_hold_window_ceiling_and_close() {
+        let limiter = std::sync::Arc::new(AdaptiveLimiter::new(4, 1, 8));
+        assert_eq!(limiter.set_ceiling(2), 2);
+        assert_eq!(limiter.limit(), 2);
+        // The limit no longer grows past the ceiling
+        limiter.success(Duration::from_millis(100));
+        limiter.success(Duration::from_millis(100));
+        assert_eq!(limiter.limit(), 2);
+        assert_eq!(limiter.set_ceiling(100), 8);
+
+        limiter.hold(true);
+        let waiting = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                let _permit = limiter.acquire().await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        limiter.hold(false);
+        waiting.await.unwrap();
+
+        // Outside the window, resuming by hand does not dispatch
+        limiter.set_outside_window(true);
+        let waiting = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                let _permit = limiter.acquire().await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        limiter.hold(false);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        limiter.set_outside_window(false);
+        waiting.await.unwrap();
+
+        // Closing stops the dispatch, even while held
+        limiter.hold(true);
+        let waiting = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire().await.is_none() })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        limiter.close();
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_a_free_slot() {
+        let limiter = std::sync::Arc::new(AdaptiveLimiter::new(1, 1, 1));
+        let permit = limiter.acquire().await;
+        let waiting = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                let _permit = limiter.acquire().await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        drop(permit);
+        waiting.await.unwrap();
+    }
+}
@@ -1,8 +1,10 @@
 #![allow(non_snake_case)]
 
+use crate::migration::LegacyPassword;
 use serde::de::Error as DeError;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
+use std::fmt;
 
 // Object to represent identities
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -15,10 +17,52 @@ pub struct RequestBody {
     pub identities: Vec<Identity>,
 
     // Optional fields for the authentication methods
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_text"
+    )]
     pub phoneAuthMethod: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_text"
+    )]
+    pub alternateMobilePhoneAuthMethod: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_text"
+    )]
+    pub officePhoneAuthMethod: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub emailAuthMethod: Option<String>,
+    // Base32 TOTP seed, created as a software OATH token method and never
+    // sent in the user body
+    #[serde(
+        default,
+        skip_serializing,
+        deserialize_with = "deserialize_optional_text"
+    )]
+    pub softwareOathSecretKey: Option<String>,
+
+    // Identifier of the user in the source system, written to the mapping file
+    // and never sent to Graph
+    #[serde(
+        default,
+        skip_serializing,
+        deserialize_with = "deserialize_optional_text"
+    )]
+    pub legacyId: Option<String>,
+
+    // Legacy password hash, written to the hash store in seamless migration
+    // mode and never sent to Graph
+    #[serde(
+        default,
+        skip_serializing,
+        deserialize_with = "deserialize_legacy_password"
+    )]
+    pub legacyPassword: Option<LegacyPassword>,
 
     // Optional fields (based on user object properties) and extension attributes
     #[serde(flatten)]
@@ -26,12 +70,25 @@ pub struct RequestBody {
 }
 
 // Struct for the Password Profile element
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct PasswordProfile {
     pub forceChangePasswordNextSignIn: bool,
     pub password: String,
 }
 
+// The password must never end up in logs, even through a Debug of the RequestBody
+impl fmt::Debug for PasswordProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordProfile")
+            .field(
+                "forceChangePasswordNextSignIn",
+                &self.forceChangePasswordNextSignIn,
+            )
+            .field("password", &"[REDACTED]")
+            .finish()
+    }
+}
+
 // Struct for the Identity element
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Identity {
@@ -40,26 +97,75 @@ pub struct Identity {
     pub issuerAssignedId: String,
 }
 
-// Custom deserializer for the passwordProfile field. We expect a JSON string here.
+// Custom deserializer for the passwordProfile field. We expect a JSON string here,
+// or an already structured object when the row comes from a script.
 fn deserialize_password_profile<'de, D>(deserializer: D) -> Result<PasswordProfile, D::Error>
 where
     D: Deserializer<'de>,
 {
-    // Deserialize the value as a string
-    let s = String::deserialize(deserializer)?;
-    serde_json::from_str(&s).map_err(serde::de::Error::custom)
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => serde_json::from_str(&s).map_err(D::Error::custom),
+        v => serde_json::from_value(v).map_err(D::Error::custom),
+    }
 }
 
-// Custom deserializer for the identities field. We expect a JSON string here.
+// Custom deserializer for the identities field. We expect a JSON string here,
+// or an already structured array when the row comes from a script.
 pub fn deserialize_identities<'de, D>(deserializer: D) -> Result<Vec<Identity>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    // Attempt to deserialize the string as Option<String>
-    let opt = Option::<String>::deserialize(deserializer)?;
-    match opt {
-        Some(s) if !s.trim().is_empty() => serde_json::from_str(&s).map_err(D::Error::custom),
-        _ => Ok(Vec::new()),
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) if !s.trim().is_empty() => {
+            serde_json::from_str(&s).map_err(D::Error::custom)
+        }
+        serde_json::Value::String(_) | serde_json::Value::Null => Ok(Vec::new()),
+        v => serde_json::from_value(v).map_err(D::Error::custom),
+    }
+}
+
+// Custom deserializer for optional text fields. Numbers are accepted as text
+// (e.g. numeric ids inferred by the CSV reader) and empty values become None.
+fn deserialize_optional_text<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) if s.trim().is_empty() => Ok(None),
+        serde_json::Value::String(s) => Ok(Some(s)),
+        serde_json::Value::Number(n) => Ok(Some(n.to_string())),
+        serde_json::Value::Null => Ok(None),
+        v => Err(D::Error::custom(format!("expected text, found {v}"))),
+    }
+}
+
+// Custom deserializer for the legacyPassword field. We expect a JSON string
+// (or an already structured object), empty values mean no legacy password.
+fn deserialize_legacy_password<'de, D>(deserializer: D) -> Result<Option<LegacyPassword>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) if s.trim().is_empty() => Ok(None),
+        serde_json::Value::String(s) => serde_json::from_str(&s).map_err(D::Error::custom),
+        serde_json::Value::Null => Ok(None),
+        v => serde_json::from_value(v).map_err(D::Error::custom),
+    }
+}
+
+// Full name of an extension attribute, e.g. extension_<appId without dashes>_loyaltyId
+pub fn extension_attribute(app_id: &str, name: &str) -> String {
+    format!("extension_{}_{name}", app_id.replace('-', ""))
+}
+
+// Generic representation of an input row, before it becomes a RequestBody
+pub type Row = serde_json::Map<String, serde_json::Value>;
+
+impl RequestBody {
+    /// Build the request body from a generic row, applying the same custom
+    /// deserializers used for CSV records.
+    pub fn from_row(row: Row) -> Result<RequestBody, serde_json::Error> {
+        serde_json::from_value(serde_json::Value::Object(row))
     }
 }
 
@@ -78,6 +184,13 @@ pub struct EmailAuthMethodRequestBody {
     pub emailAddress: String,
 }
 
+// Object that represents a software OATH token authentication method
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SoftwareOathMethodRequestBody {
+    // Mandatory fields for creating the authentication method
+    pub secretKey: String,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -214,4 +327,53 @@ mod tests {
         assert_eq!(body.identities.len(), 1);
         assert_eq!(body.identities[0].issuerAssignedId, "test@example.com");
     }
+
+    #[test]
+    fn test_request_body_from_row_with_structured_values() {
+        let row: Row = serde_json::from_str(
+            r#"{
+                "displayName": "Test User",
+                "passwordProfile": {"forceChangePasswordNextSignIn": false, "password": "Pass123!"},
+                "identities": [{"signInType": "userName", "issuer": "example.com", "issuerAssignedId": "tuser"}],
+                "accountEnabled": true
+            }"#,
+        )
+        .unwrap();
+        let body = RequestBody::from_row(row).unwrap();
+        assert!(!body.passwordProfile.forceChangePasswordNextSignIn);
+        assert_eq!(body.identities[0].issuerAssignedId, "tuser");
+        assert_eq!(
+            body.custom_fields.get("accountEnabled"),
+            Some(&serde_json::Value::Bool(true))
+        );
+        assert!(body.legacyId.is_none());
+    }
+
+    #[test]
+    fn test_legacy_id_is_not_serialized() {
+        let row: Row = serde_json::from_str(
+            r#"{
+                "displayName": "Test User",
+                "passwordProfile": {"forceChangePasswordNextSignIn": false, "password": "Pass123!"},
+                "identities": [],
+                "legacyId": 12345
+            }"#,
+        )
+        .unwrap();
+        let body = RequestBody::from_row(row).unwrap();
+        assert_eq!(body.legacyId.as_deref(), Some("12345"));
+        let json = serde_json::to_value(&body).unwrap();
+        assert!(json.get("legacyId").is_none());
+    }
+
+    #[test]
+    fn test_password_is_not_in_debug_output() {
+        let profile = PasswordProfile {
+            forceChangePasswordNextSignIn: true,
+            password: "Pass123!".to_string(),
+        };
+        let debug = format!("{profile:?}");
+        assert!(!debug.contains("Pass123!"));
+        assert!(debug.contains("[REDACTED]"));
+    }
 }
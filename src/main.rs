@@ -1,24 +1,58 @@
 #![allow(clippy::io_other_error)]
-use clap::{Arg, ArgAction, Command};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use db::*;
 use graph::*;
-use indicatif::{ProgressBar, ProgressStyle};
-use log::info;
+use indicatif::{HumanDuration, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{error, info, warn};
 use std::error::Error;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Semaphore;
 
+use crate::control::*;
 use crate::customizations::prj1::*;
-
+use crate::customizations::smtp::*;
+use crate::dashboard::*;
+use crate::distributed::*;
+use crate::metrics::*;
+use crate::migration::*;
+use crate::output::*;
+use crate::run::*;
+use crate::schedule::*;
+use crate::source::*;
+use crate::telemetry::*;
+use crate::transform::*;
+
+mod control;
 mod customizations;
+mod dashboard;
 mod db;
+mod distributed;
 mod graph;
+mod metrics;
+mod migration;
+mod output;
+mod run;
+mod schedule;
+mod source;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod transform;
 
 /// Customizations struct for triggers and configs
-#[derive(Clone)]
+#[derive(Clone, Default)]
 struct Customizations {
     prj1: bool,
     prj1_config: Option<Prj1AppConfig>,
+    smtp: bool,
+    smtp_notifier: Option<SmtpNotifier>,
+    mapping_file: Option<MappingFile>,
+    credentials_file: Option<CredentialsFile>,
+    hash_store: Option<HashStore>,
+    oath_endpoint: Option<String>,
+    // Rows of a lease taken over, which its previous owner may have created
+    taken_over: bool,
 }
 
 #[tokio::main]
@@ -33,26 +67,86 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .short('t')
                 .long("token")
                 .help("Sets the bearer token used for authentication")
-                .required(true)
+                .required_unless_present_any(["clientid", "apppool"])
                 .num_args(1),
         )
         .arg(
             Arg::new("file")
                 .short('f')
                 .long("file")
-                .help("Sets the path to the CSV data file")
-                .required(true)
+                .help("Sets the path to the input data file")
+                .required_unless_present("source")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("source")
+                .long("source")
+                .help("Reads the users from a database instead of a file: sqlite://<path> or postgres://<connection>")
+                .required(false)
+                .conflicts_with("file")
+                .requires("query")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("query")
+                .long("query")
+                .help("Sets the SQL query run against the --source database, one user per row")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("inputformat")
+                .long("input-format")
+                .help("Sets the input file format: csv, json or jsonl (detected from the extension by default)")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("adapter")
+                .long("adapter")
+                .help("Reads the input file as a user export of another identity provider: auth0, cognito or keycloak")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("adapterconfig")
+                .long("adapter-config")
+                .help("Sets the path to the source adapter configuration file")
+                .required(false)
+                .default_value("adapterconfig.toml")
                 .num_args(1),
         )
         .arg(
             Arg::new("nreqs")
                 .short('n')
                 .long("nreqs")
-                .help("Sets the number of concurrent requests to use")
+                .help("Sets the initial number of concurrent requests to use")
                 .required(false)
                 .default_value("4")
                 .num_args(1),
         )
+        .arg(
+            Arg::new("maxnreqs")
+                .long("max-nreqs")
+                .help("Sets the maximum number of concurrent requests the adaptive limit can grow to")
+                .required(false)
+                .default_value("32")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("usersrps")
+                .long("users-rps")
+                .help("Sets the maximum number of user creation requests per second")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("methodsrps")
+                .long("methods-rps")
+                .help("Sets the maximum number of authentication method requests per second")
+                .required(false)
+                .num_args(1),
+        )
         .arg(
             Arg::new("logfile")
                 .short('l')
@@ -60,6 +154,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .help("Sets the path to the log file")
                 .required(false)
                 .default_value("output.log")
+                .global(true)
                 .num_args(1),
         )
         .arg(
@@ -69,15 +164,75 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .help("Sets the path to the sqlite database file")
                 .required(false)
                 .default_value("output.db")
+                .global(true)
                 .num_args(1),
         )
         .arg(
             Arg::new("url")
                 .short('u')
                 .long("url")
-                .help("Sets the URL for the REST endpoint")
+                .help("Sets the Graph base URL, overriding the one of the cloud profile")
+                .required(false)
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("cloud")
+                .long("cloud")
+                .help("Sets the cloud profile for the Graph URL and token authority: global, usgov, china or custom")
+                .required(false)
+                .default_value("global")
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("graphversion")
+                .long("graph-version")
+                .help("Sets the Graph API version used by every call")
                 .required(false)
-                .default_value("https://graph.microsoft.com")
+                .value_parser(["v1.0", "beta"])
+                .default_value("v1.0")
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("authority")
+                .long("authority")
+                .help("Sets the token authority, overriding the one of the cloud profile")
+                .required(false)
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("tenantid")
+                .long("tenant-id")
+                .help("Sets the tenant used to acquire a token with the client credentials")
+                .required(false)
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("clientid")
+                .long("client-id")
+                .help("Sets the application used to acquire a token instead of --token")
+                .required(false)
+                .requires("tenantid")
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("apppool")
+                .long("app-pool")
+                .help("Sets the path to a TOML file of app registrations sharing the load, instead of --token")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("clientsecret")
+                .long("client-secret")
+                .help("Sets the secret of the application (or set B2C_MIGRATOR_CLIENT_SECRET)")
+                .required(false)
+                .global(true)
                 .num_args(1),
         )
         .arg(
@@ -86,19 +241,388 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .help("Turn on Prj1 customization")
                 .action(ArgAction::SetTrue), // 0-arity flag
         )
+        .arg(
+            Arg::new("mappingfile")
+                .long("mapping-file")
+                .help("Sets the path to the CSV file mapping legacy ids to the new object ids")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("generatepasswords")
+                .long("generate-passwords")
+                .help("Generate a random password for rows without one (always on with --adapter)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("passwordlength")
+                .long("password-length")
+                .help("Sets the length of generated passwords (8 to 64)")
+                .required(false)
+                .default_value("16")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("passwordclasses")
+                .long("password-classes")
+                .help("Sets the character classes of generated passwords, at least 3 of: lower,upper,digit,symbol")
+                .required(false)
+                .default_value("lower,upper,digit,symbol")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("credentialsfile")
+                .long("credentials-file")
+                .help("Sets the path to the encrypted CSV file receiving the generated passwords")
+                .required(false)
+                .requires("recipient")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("recipient")
+                .long("recipient")
+                .help("Sets the age public key (age1...) used to encrypt output files")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("identity")
+                .long("identity")
+                .help("Sets the age identity file used to decrypt the input (or set B2C_MIGRATOR_IDENTITY)")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("passphrasefile")
+                .long("passphrase-file")
+                .help("Sets the file holding the passphrase used to decrypt the input (or set B2C_MIGRATOR_PASSPHRASE)")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("piimode")
+                .long("pii-mode")
+                .help("Sets how user identifiers appear in the logs: plain, hash or mask")
+                .required(false)
+                .default_value("plain")
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("logformat")
+                .long("log-format")
+                .help("Sets the format of the console and file logs: text or json")
+                .required(false)
+                .default_value("text")
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("loglevel")
+                .long("log-level")
+                .help("Sets the log level, with per-module overrides, e.g. warn,b2c_migrator::graph=debug")
+                .required(false)
+                .default_value("info")
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("phoneregion")
+                .long("phone-region")
+                .help("Sets the ISO country code (e.g. IT) of phone numbers without an international prefix")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("phonefields")
+                .long("phone-fields")
+                .help("Sets other comma separated fields to normalise as phone numbers, e.g. mobilePhone,businessPhones")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("oathapiversion")
+                .long("oath-api-version")
+                .help("Sets the Graph API version used to create software OATH token methods")
+                .required(false)
+                .num_args(1)
+                .default_value("beta"),
+        )
+        .arg(
+            Arg::new("seamlessmigration")
+                .long("seamless-migration")
+                .help("Flags users with a legacyPassword for just-in-time migration and stores their hashes")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("extensionappid")
+                .long("extension-app-id")
+                .help("Sets the b2c-extensions-app client id of the migration flag (defaults to the adapter config one)")
+                .required(false)
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("migrationattribute")
+                .long("migration-attribute")
+                .help("Sets the name of the extension attribute flagging users to migrate")
+                .required(false)
+                .default_value("requiresMigration")
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("hashstore")
+                .long("hash-store")
+                .help("Sets the path to the sqlite file holding the legacy password hashes")
+                .required(false)
+                .default_value("hashstore.db")
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("smtp")
+                .long("smtp")
+                .help("Send an SMTP notification email to each created user")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("smtpconfig")
+                .long("smtp-config")
+                .help("Sets the path to the SMTP configuration file")
+                .required(false)
+                .default_value("smtpconfig.toml")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("script")
+                .long("script")
+                .help("Sets the path to a Rhai script run on each row before migration")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("dashboard")
+                .long("dashboard")
+                .help("Shows a live dashboard of the run instead of the console logs, with keys to pause and tune it")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("metricsaddr")
+                .long("metrics-addr")
+                .help("Serves Prometheus metrics of the run at http://<address>/metrics, e.g. 127.0.0.1:9100")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("controladdr")
+                .long("control-addr")
+                .help("Serves an endpoint to pause, tune and stop the run at http://<address>, e.g. 127.0.0.1:9200; also where ctl sends its commands")
+                .required(false)
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("otlpendpoint")
+                .long("otlp-endpoint")
+                .help("Exports a trace of every row to an OTLP/HTTP collector, e.g. http://localhost:4318")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("tracefile")
+                .long("trace-file")
+                .help("Appends a trace of every row to a file, in the OTLP/JSON format")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("window")
+                .long("window")
+                .help("Only writes to the tenant within a window: [DAYS] HH:MM-HH:MM[,...] [ZONE], e.g. \"Mon-Fri 22:00-06:00 Europe/Rome\"; repeat for several windows")
+                .required(false)
+                .action(ArgAction::Append)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("windowclose")
+                .long("window-close")
+                .help("Sets what the run does when its window closes: sleep until the next one, or exit once drained (resumable with --state)")
+                .required(false)
+                .requires("window")
+                .value_parser(["sleep", "exit"])
+                .default_value("sleep")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("shard")
+                .long("shard")
+                .help("Migrates only the users of shard i/n, by hash of their sign-in name")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("state")
+                .long("state")
+                .help("Sets the state database shared by the instances of a distributed run: sqlite://<path> or postgres://<connection>")
+                .required(false)
+                .requires("runid")
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("runid")
+                .long("run-id")
+                .help("Sets the id of the distributed run in the state database")
+                .required(false)
+                .global(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("workerid")
+                .long("worker-id")
+                .help("Sets the name of this instance in the state database (default: host name and process id)")
+                .required(false)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("leasesize")
+                .long("lease-size")
+                .help("Sets the number of rows leased at once from the state database")
+                .required(false)
+                .default_value("1000")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("leasetimeout")
+                .long("lease-timeout")
+                .help("Sets the seconds without heartbeat after which a lease is taken over by another instance")
+                .required(false)
+                .default_value("300")
+                .num_args(1),
+        )
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("jit-server")
+                .about("Serves the REST API verifying legacy passwords at first sign-in")
+                .arg(
+                    Arg::new("token")
+                        .short('t')
+                        .long("token")
+                        .help("Sets a bearer token used as is to clear the migration flag, overriding the renewed token of --client-id")
+                        .required_unless_present("clientid")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .help("Sets the address the server listens on")
+                        .required(false)
+                        .default_value("127.0.0.1:8080")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("apikey")
+                        .long("api-key")
+                        .help("Sets the key expected in the X-Api-Key header (or set B2C_MIGRATOR_API_KEY)")
+                        .required(false)
+                        .num_args(1),
+                ),
+        )
+        .subcommand(
+            Command::new("report")
+                .about("Prints the merged outcome of every instance of a distributed run"),
+        )
+        .subcommand(
+            Command::new("ctl")
+                .about("Sends a command to the control endpoint of a running migration (see --control-addr)")
+                .subcommand_required(true)
+                .subcommand(Command::new("summary").about("Prints the state of the run"))
+                .subcommand(Command::new("pause").about("Pauses the dispatch of new users, running users finish"))
+                .subcommand(Command::new("resume").about("Resumes the dispatch of new users"))
+                .subcommand(
+                    Command::new("concurrency")
+                        .about("Caps the number of concurrent requests")
+                        .arg(Arg::new("limit").required(true).num_args(1)),
+                )
+                .subcommand(
+                    Command::new("rate")
+                        .about("Sets the requests per second of user creations or authentication methods, or off")
+                        .arg(
+                            Arg::new("kind")
+                                .required(true)
+                                .value_parser(["users", "methods"])
+                                .num_args(1),
+                        )
+                        .arg(Arg::new("rps").required(true).num_args(1)),
+                )
+                .subcommand(Command::new("stop").about("Stops the run once running users finish, leaving the rest for a later run")),
+        )
         .get_matches();
 
-    // Bearer token for authentication
-    let bearer_token = matches
-        .get_one::<String>("token")
-        .expect("Bearer token is required")
-        .clone();
+    match matches.subcommand() {
+        Some(("jit-server", jit_matches)) => return jit_server(jit_matches).await,
+        Some(("report", report_matches)) => return run_report(report_matches),
+        Some(("ctl", ctl_matches)) => return run_ctl(ctl_matches).await,
+        _ => {}
+    }
 
-    // File path to the CSV data file
+    // File path to the input data file (empty when reading from a database)
     let file_path = matches
         .get_one::<String>("file")
-        .expect("CSV data file path is required")
-        .clone();
+        .cloned()
+        .unwrap_or_default();
+
+    // Optional database source, with the query returning the users
+    let sql_source = match matches.get_one::<String>("source") {
+        Some(source) => {
+            let query = matches
+                .get_one::<String>("query")
+                .expect("SQL query is required")
+                .clone();
+            Some((source.parse::<SqlSource>()?, query))
+        }
+        None => None,
+    };
+
+    // Input format, explicit or detected from the file extension
+    let input_format = match matches.get_one::<String>("inputformat") {
+        Some(format) => format.parse::<InputFormat>()?,
+        None => InputFormat::from_path(&file_path),
+    };
+
+    // Optional source adapter, with its configuration
+    let adapter = match matches.get_one::<String>("adapter") {
+        Some(name) => {
+            let config_file = matches
+                .get_one::<String>("adapterconfig")
+                .expect("Adapter config file path is required");
+            Some((name.parse::<Adapter>()?, adapter_load_config(config_file)?))
+        }
+        None => None,
+    };
+
+    // Keys for age-encrypted input files, from files or from the environment
+    let identity = match matches.get_one::<String>("identity") {
+        Some(path) => Some(std::fs::read_to_string(path)?),
+        None => std::env::var("B2C_MIGRATOR_IDENTITY").ok(),
+    };
+    let passphrase = match matches.get_one::<String>("passphrasefile") {
+        Some(path) => Some(std::fs::read_to_string(path)?),
+        None => std::env::var("B2C_MIGRATOR_PASSPHRASE").ok(),
+    };
+    // State shared by the tasks of the run, installed before the input is read
+    let mut context = RunContext {
+        decryption: DecryptionKeys::new(identity, passphrase)?,
+        ..RunContext::default()
+    };
+
+    // Age recipient of the output files
+    let recipient = match matches.get_one::<String>("recipient") {
+        Some(recipient) => Some(parse_recipient(recipient)?),
+        None => None,
+    };
 
     // Maximum number of concurrent requests (controls concurrency)
     let max_concurrent_requests_string = matches
@@ -106,7 +630,27 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .expect("Number of concurrent requests is required")
         .clone();
     let max_concurrent_requests: usize = max_concurrent_requests_string.parse::<usize>().unwrap();
-    let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
+    // The limit adapts to throttling, between 1 and --max-nreqs
+    let max_nreqs = matches
+        .get_one::<String>("maxnreqs")
+        .expect("Maximum number of concurrent requests is required")
+        .parse::<usize>()?
+        .max(max_concurrent_requests);
+    context.limiter = Some(AdaptiveLimiter::new(max_concurrent_requests, 1, max_nreqs));
+
+    // Request-per-second budgets, shared by every task (or by the tasks
+    // using the same app of the pool)
+    let rate_arg = |name: &str| -> Result<Option<f64>, Box<dyn Error>> {
+        match matches.get_one::<String>(name) {
+            Some(rate) => Ok(Some(
+                rate.parse::<f64>()
+                    .map_err(|e| format!("Invalid rate {rate}: {e}"))?,
+            )),
+            None => Ok(None),
+        }
+    };
+    let users_rps = rate_arg("usersrps")?;
+    let methods_rps = rate_arg("methodsrps")?;
 
     // File path for the log file
     let log_file = matches
@@ -120,101 +664,976 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .expect("DB file path is required")
         .clone();
 
-    // REST endpoint
-    let endpoint = matches
-        .get_one::<String>("url")
-        .expect("REST endpoint is required")
-        .clone();
+    // Graph endpoint of the selected cloud
+    let graph = graph_endpoint(&matches)?;
     let client = reqwest::Client::new();
 
     // Customization handler
     let prj1 = matches.get_one::<bool>("prj1").copied().unwrap_or(false);
-    let customizations_handler = Customizations {
-        prj1: prj1,
+    let smtp = matches.get_one::<bool>("smtp").copied().unwrap_or(false);
+    let smtp_config_file = matches
+        .get_one::<String>("smtpconfig")
+        .expect("SMTP config file path is required")
+        .clone();
+    // Output files are finished on every way out of the run from here on,
+    // otherwise an encrypted one cannot be decrypted
+    let _outputs = OutputsGuard;
+    let mapping_file = match matches.get_one::<String>("mappingfile") {
+        Some(path) => Some(MappingFile::create(path, recipient.as_ref())?),
+        None => None,
+    };
+    if let Some(mapping_file) = &mapping_file {
+        finish_at_exit(mapping_file.clone());
+    }
+    let credentials_file = match matches.get_one::<String>("credentialsfile") {
+        Some(path) => Some(CredentialsFile::create(
+            path,
+            recipient
+                .as_ref()
+                .expect("Recipient is required to encrypt the credentials file"),
+        )?),
+        None => None,
+    };
+    if let Some(credentials_file) = &credentials_file {
+        finish_at_exit(credentials_file.clone());
+    }
+    if mapping_file.is_some() || credentials_file.is_some() {
+        // An interrupted run still finishes them
+        tokio::spawn(async {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                warn!("Run interrupted.");
+                exit_process(130);
+            }
+        });
+    }
+
+    let mut customizations_handler = Customizations {
+        prj1,
         prj1_config: if prj1 {
             Some(prj1_load_config("prj1config.toml").unwrap())
         } else {
             None
         },
+        smtp,
+        smtp_notifier: if smtp {
+            Some(SmtpNotifier::new(smtp_load_config(smtp_config_file)?)?)
+        } else {
+            None
+        },
+        mapping_file,
+        credentials_file,
+        hash_store: None,
+        oath_endpoint: matches
+            .get_one::<String>("oathapiversion")
+            .map(|version| graph.with_version(version).users()),
+        taken_over: false,
+    };
+
+    // Password generation for rows without a password. Adapters never carry
+    // passwords, so it is always on for them.
+    let password_policy = if matches.get_flag("generatepasswords") || adapter.is_some() {
+        let length = matches
+            .get_one::<String>("passwordlength")
+            .expect("Password length is required")
+            .parse::<usize>()?;
+        let classes = matches
+            .get_one::<String>("passwordclasses")
+            .expect("Password classes are required")
+            .split(',')
+            .map(str::parse::<CharClass>)
+            .collect::<Result<Vec<_>, _>>()?;
+        Some(PasswordPolicy::new(length, classes)?)
+    } else {
+        None
+    };
+
+    // Seamless migration: extension attribute flagging the users, and the
+    // store receiving their legacy password hashes
+    let seamless_migration = if matches.get_flag("seamlessmigration") {
+        let app_id = matches
+            .get_one::<String>("extensionappid")
+            .cloned()
+            .or_else(|| {
+                adapter
+                    .as_ref()
+                    .and_then(|(_, config)| config.extension_app_id.clone())
+            })
+            .ok_or("--extension-app-id is required for the seamless migration")?;
+        let attribute = matches
+            .get_one::<String>("migrationattribute")
+            .expect("Migration attribute is required")
+            .clone();
+        customizations_handler.hash_store = Some(HashStore::open(
+            matches
+                .get_one::<String>("hashstore")
+                .expect("Hash store path is required"),
+        )?);
+        Some((
+            app_id,
+            attribute,
+            password_policy.clone().unwrap_or_default(),
+        ))
+    } else {
+        None
     };
 
-    // Configure the logger
-    setup_logger(log_file, db_file)?;
+    // Phone number normalisation
+    let phone_normalizer = PhoneNormalizer::new(
+        matches.get_one::<String>("phoneregion").map(String::as_str),
+        matches
+            .get_one::<String>("phonefields")
+            .map(|fields| fields.split(',').map(|f| f.trim().to_string()).collect())
+            .unwrap_or_default(),
+    )?;
+
+    // Optional per-row transform script
+    let script = match matches.get_one::<String>("script") {
+        Some(path) => Some(RowScript::load(path)?),
+        None => None,
+    };
+
+    // Configure the logger, redacting user identifiers as requested
+    let pii_mode = matches
+        .get_one::<String>("piimode")
+        .expect("PII mode is required")
+        .parse::<PiiMode>()?;
+    init_redaction(pii_mode);
+    // The dashboard takes the console over, the logs still go to the file
+    // and the database
+    let dashboard = matches.get_flag("dashboard");
+    if dashboard && !std::io::stdout().is_terminal() {
+        return Err("The dashboard needs a terminal".into());
+    }
+    let control_addr = matches.get_one::<String>("controladdr");
+    context.stats = (dashboard || control_addr.is_some()).then(RunStats::new);
+    let mut settings = log_settings(&matches)?;
+    settings.console = !dashboard;
+    context.outcome_log = Some(setup_logger(log_file, db_file, settings)?);
+
+    // Optional migration windows, outside which nothing is written to the tenant
+    let window_close = matches
+        .get_one::<String>("windowclose")
+        .expect("Window close action is required")
+        .parse::<WindowClose>()?;
+    let schedule = match matches.get_many::<String>("window") {
+        Some(windows) => Some(Schedule::new(
+            windows
+                .map(|window| window.parse::<Window>())
+                .collect::<Result<_, _>>()?,
+        )),
+        None => None,
+    };
+    if let Some(schedule) = &schedule {
+        if window_close == WindowClose::Exit {
+            // Only the lease queue checkpoints a stopped run
+            if !matches.contains_id("state") || matches.contains_id("shard") {
+                return Err(
+                    "--window-close exit needs --state without --shard, to resume the run later"
+                        .into(),
+                );
+            }
+            let now = chrono::Utc::now();
+            if !schedule.is_open(now) {
+                info!(
+                    "[END] Outside the migration window ({schedule}), nothing is migrated before {}.",
+                    format_change(schedule.next_change(now))
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    // Optional metrics endpoint, serving until the end of the run
+    let metrics_addr = matches.get_one::<String>("metricsaddr");
+    context.metrics = metrics_addr.map(|_| Metrics::new());
 
-    // Open the CSV file.
-    let mut rdr = csv::Reader::from_path(file_path.clone())?;
+    // Optional tracing of every row, to a collector and/or a file
+    let mut trace_sinks = vec![];
+    if let Some(endpoint) = matches.get_one::<String>("otlpendpoint") {
+        trace_sinks.push(TraceSink::otlp(endpoint));
+    }
+    if let Some(path) = matches.get_one::<String>("tracefile") {
+        trace_sinks.push(TraceSink::file(path)?);
+    }
+    context.tracer = (!trace_sinks.is_empty()).then(|| Tracer::new(trace_sinks));
+
+    // Tokens of the run, or a token for every app of the pool
+    let tokens = match matches.get_one::<String>("apppool") {
+        Some(path) => {
+            let mut config = app_pool_load_config(path)?;
+            config.tenant_id = config
+                .tenant_id
+                .or_else(|| matches.get_one::<String>("tenantid").cloned());
+            let pool = AppPool::connect(
+                &config,
+                &client,
+                &token_authority(&matches)?,
+                &graph,
+                users_rps,
+                methods_rps,
+            )
+            .await?;
+            info!(
+                "Requests are distributed across {} app registrations.",
+                config.apps.len()
+            );
+            context.app_pool = Some(pool);
+            // Every request uses the token of an app of the pool instead
+            TokenCache::fixed("")
+        }
+        None => {
+            context.rate_limits = Some(RateLimits {
+                users: TokenBucket::with_rate(users_rps)?,
+                methods: TokenBucket::with_rate(methods_rps)?,
+            });
+            run_tokens(&matches, &client, &graph).await?
+        }
+    };
+    let tokens = Arc::new(tokens);
 
-    // Check for authentication methods in the CSV columns
-    let headers = rdr.headers()?;
-    let has_phone_auth_method = headers.iter().any(|h| h == "phoneAuthMethod");
-    let has_email_auth_method = headers.iter().any(|h| h == "emailAuthMethod");
+    init_run_context(context)?;
+    let limiter = limiter().expect("Concurrency limiter is installed");
+    if let (Some(address), Some(metrics)) = (metrics_addr, metrics()) {
+        serve_metrics(address, metrics).await?;
+    }
 
-    // Determine the number of records in the CSV file.
-    let records: Vec<_> = csv::Reader::from_path(file_path.clone())?
-        .records()
-        .filter_map(Result::ok)
-        .collect();
-    let total_rows = records.len() as u64;
+    // Determine the number of records in the input file.
+    let total_rows = match (&sql_source, &adapter) {
+        (Some((source, query)), _) => source.count_rows(query)?,
+        (None, Some((adapter, _))) => adapter.count_rows(&file_path)?,
+        (None, None) => count_rows(&file_path, input_format)?,
+    };
+
+    // Distributed run: a shard of the users, or ranges of rows leased from
+    // the state database until the whole input is done
+    let shard = matches
+        .get_one::<String>("shard")
+        .map(|shard| shard.parse::<Shard>())
+        .transpose()?;
+    let state = match matches.get_one::<String>("state") {
+        Some(url) => {
+            let store = url.parse::<StateStore>()?;
+            store.init()?;
+            Some(store)
+        }
+        None => None,
+    };
+    let run_id = matches
+        .get_one::<String>("runid")
+        .cloned()
+        .unwrap_or_default();
+    let worker = worker_id(&matches);
+    let lease_timeout = std::time::Duration::from_secs(
+        matches
+            .get_one::<String>("leasetimeout")
+            .expect("Lease timeout is required")
+            .parse::<u64>()?
+            .max(1),
+    );
+    // Leases are ranges of row positions, only stable with an ordered query
+    if let (Some(_), None, Some((_, query))) = (&state, shard, &sql_source) {
+        if !is_ordered(query) {
+            return Err("Leases of a --source need a deterministic row order: add an ORDER BY on a unique key to the --query".into());
+        }
+    }
+    let lease_queue = match &state {
+        Some(store) => {
+            let lease_size = matches
+                .get_one::<String>("leasesize")
+                .expect("Lease size is required")
+                .parse::<u64>()?
+                .max(1);
+            let (run_rows, lease_size) =
+                store.register_run(&run_id, total_rows, lease_size, now())?;
+            if run_rows != total_rows {
+                warn!("Run {run_id} was started with {run_rows} rows, the input now has {total_rows}.");
+            }
+            match shard {
+                Some(shard) => {
+                    info!("Worker {worker} joined run {run_id} with shard {shard}.");
+                    None
+                }
+                None => {
+                    info!(
+                        "Worker {worker} joined run {run_id}, leasing {lease_size} rows at a time."
+                    );
+                    Some(store)
+                }
+            }
+        }
+        None => None,
+    };
 
     // Create the progress bar with the total number of rows.
-    let pb = Arc::new(ProgressBar::new(total_rows));
+    // Leased rows are added to the bar as they are claimed
+    let pb = Arc::new(ProgressBar::new(if lease_queue.is_some() {
+        0
+    } else {
+        total_rows
+    }));
     let style = ProgressStyle::default_bar()
         .template(
-            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({percent}%)",
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({percent}%) {msg}",
         )
         .unwrap()
         .progress_chars("#>-");
     pb.set_style(style);
+    if let Some(metrics) = metrics() {
+        metrics.set_progress(ProgressBar::clone(&pb));
+    }
+    let dashboard = dashboard.then(|| {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+        Dashboard::start(ProgressBar::clone(&pb))
+    });
+    // Optional control endpoint, serving until the end of the run
+    if let Some(address) = control_addr {
+        let control = Control::new(limiter, rate_limits(), app_pool(), ProgressBar::clone(&pb));
+        serve_control(address, Arc::new(control)).await?;
+    }
+
+    let input_name = match &sql_source {
+        Some((source, _)) => source.to_string(),
+        None => format!("file {file_path}"),
+    };
+    info!(
+        "Starting migration process. Using {input_name} with {max_concurrent_requests} threads (adaptive, up to {max_nreqs})."
+    );
+    if let Some(shard) = shard {
+        info!("Migrating the users of shard {shard}.");
+    }
+    let per_app = if app_pool().is_some() { " per app" } else { "" };
+    for (name, rate) in [
+        ("user creation", users_rps),
+        ("authentication method", methods_rps),
+    ] {
+        if let Some(rate) = rate {
+            info!("Rate limit of {name} requests: {rate} per second{per_app}.");
+        }
+    }
+    // Dispatch only within the migration windows
+    if let Some(schedule) = schedule {
+        let between = match window_close {
+            WindowClose::Sleep => "sleeping until the next one",
+            WindowClose::Exit => "stopping when it closes",
+        };
+        info!("Migrating within the windows {schedule} only, {between}.");
+        limiter.set_outside_window(!schedule.is_open(chrono::Utc::now()));
+        tokio::spawn(keep_to_schedule(schedule, window_close, limiter));
+    }
+    // Input of the run, opened once and read forward across the leases
+    let open_rows = || match (&sql_source, &adapter) {
+        (Some((source, query)), _) => source.read_rows(query),
+        (None, Some((adapter, _))) => adapter.read_rows(&file_path),
+        (None, None) => read_rows(&file_path, input_format, phone_normalizer.fields()),
+    };
+    let mut input = RowCursor::new(open_rows);
+    // Units of work: the whole input (or shard) once, or each leased range
+    loop {
+        // After a stop, no more rows are leased
+        if limiter.is_closed() {
+            break;
+        }
+        let lease = match lease_queue {
+            Some(store) => match store.claim(&run_id, &worker, now(), lease_timeout)? {
+                Some(lease) => {
+                    let range = lease.range;
+                    pb.inc_length(range.end - range.start);
+                    if lease.taken_over {
+                        info!("Took over {range} of run {run_id}, after the rows checkpointed by its previous owner.");
+                    } else {
+                        info!("Leased {range} of run {run_id}.");
+                    }
+                    Some(lease)
+                }
+                None => break,
+            },
+            None => None,
+        };
+        let range = lease.map(|lease| lease.range);
+        let taken_over = lease.is_some_and(|lease| lease.taken_over);
+        let scope = match (range, shard) {
+            (Some(range), _) => range.to_string(),
+            (None, Some(shard)) => format!("shard {shard}"),
+            (None, None) => "all rows".to_string(),
+        };
+        // Renew the lease while its rows are processed, checkpointing them,
+        // until another worker takes it over
+        let first_row = range.map_or(0, |range| range.start);
+        let checkpoint = Checkpoint::new(first_row);
+        let lease_lost = Arc::new(AtomicBool::new(false));
+        let heartbeat = match (lease_queue, range) {
+            (Some(store), Some(range)) => Some(tokio::spawn(renew_lease(
+                store.clone(),
+                run_id.clone(),
+                range,
+                worker.clone(),
+                lease_timeout,
+                checkpoint.clone(),
+                lease_lost.clone(),
+            ))),
+            _ => None,
+        };
+
+        let mut handles = vec![];
+        let mut in_scope: u64 = 0;
+        // Rows of the unit gone through, up to a stop
+        let mut taken: u64 = 0;
+
+        // Iterate over each row of the input, deserializing it into RequestBody
+        let rows: Box<dyn Iterator<Item = NumberedRow>> = match range {
+            Some(range) => Box::new(input.range(range)?),
+            None => open_rows()?,
+        };
+        for result in rows {
+            // After a stop, the rest of the rows is left to a later run, and
+            // the rest of a lease taken over to its new owner
+            if limiter.is_closed() || lease_lost.load(Ordering::Relaxed) {
+                break;
+            }
+            taken += 1;
+            // Confirmed once migrated, or at once when skipped
+            let row_done = checkpoint.row(first_row + taken - 1);
+            let (line, mut row) = result?;
+            if let Some((adapter, adapter_config)) = &adapter {
+                match adapter.convert(adapter_config, row) {
+                    Ok(converted) => row = converted,
+                    Err(e) => {
+                        // Reported by a single shard
+                        if shard.is_none_or(|shard| shard.owns_line(line)) {
+                            in_scope += 1;
+                            error!(line = line; "Row at line {line} skipped: {e}");
+                            let mut root = start_trace("migrate user");
+                            root.set("line", line as i64);
+                            root.child("validation").fail(&e.to_string());
+                            observe_row(RowResult::Skipped);
+                        }
+                        pb.inc(1);
+                        continue;
+                    }
+                }
+            }
+            if shard.is_some_and(|shard| !shard.owns(&row, line)) {
+                pb.inc(1);
+                continue;
+            }
+            in_scope += 1;
+            // One trace per row, from its validation to the last post-create hook
+            let mut root = start_trace("migrate user");
+            root.set("line", line as i64);
+            let mut validation = root.child("validation");
+            if let Some(script) = &script {
+                match script.apply(row, line) {
+                    Ok(ScriptOutcome::Keep(transformed)) => row = transformed,
+                    Ok(ScriptOutcome::Skip(reason)) => {
+                        info!(line = line; "Row at line {line} skipped by script: {reason}");
+                        validation.set("skipped", reason);
+                        skip_row(&pb);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(line = line; "Row at line {line} skipped, script error: {e}");
+                        validation.fail(&format!("Script error: {e}"));
+                        skip_row(&pb);
+                        continue;
+                    }
+                }
+            }
+            // Users with a legacy password get a random one, never handed out
+            let flagged = match &seamless_migration {
+                Some((app_id, attribute, policy)) => {
+                    flag_for_migration(&mut row, app_id, attribute, policy)
+                }
+                None => false,
+            };
+            let generated_password = match &password_policy {
+                Some(policy) if !flagged && needs_password(&row) => {
+                    row.insert("passwordProfile".into(), policy.password_profile());
+                    true
+                }
+                _ => false,
+            };
+            let mut record = match RequestBody::from_row(row) {
+                Ok(record) if !record.identities.is_empty() => record,
+                Ok(_) => {
+                    error!(line = line; "Row at line {line} skipped: no identities found.");
+                    validation.fail("No identities found");
+                    skip_row(&pb);
+                    continue;
+                }
+                Err(e) => {
+                    error!(line = line; "Row at line {line} skipped, invalid data: {e}");
+                    validation.fail(&format!("Invalid data: {e}"));
+                    skip_row(&pb);
+                    continue;
+                }
+            };
+            match phone_normalizer.apply(&mut record) {
+                Ok(changes) => {
+                    for change in changes {
+                        info!(
+                            "[{:?}] Phone number in {} normalised from {:?} to {:?}.",
+                            pii(&record.identities[0].issuerAssignedId),
+                            change.field,
+                            pii(&change.original),
+                            pii(&change.normalized)
+                        );
+                    }
+                }
+                Err(e) => {
+                    error!(line = line; "Row at line {line} skipped, {e}.");
+                    validation.fail(&e.to_string());
+                    skip_row(&pb);
+                    continue;
+                }
+            }
+            if let Err(e) = record.normalize_software_oath() {
+                error!(line = line; "Row at line {line} skipped, {e}.");
+                validation.fail(&e);
+                skip_row(&pb);
+                continue;
+            }
+            drop(validation);
+            root.set(
+                "user",
+                pii(&record.identities[0].issuerAssignedId).to_string(),
+            );
+            // Authentication methods are only created when the row has a value for them
+            let has_phone_auth_method = !record.phone_auth_methods().is_empty();
+            let has_email_auth_method = record
+                .emailAuthMethod
+                .as_deref()
+                .is_some_and(|e| !e.trim().is_empty());
+            let client = client.clone();
+            let endpoint = graph.users();
+            let tokens = tokens.clone();
+            let mut customizations = customizations_handler.clone();
+            if !generated_password {
+                customizations.credentials_file = None;
+            }
+            customizations.taken_over = taken_over;
+            // Acquire permission to respect the concurrency limit
+            let Some(permit) = limiter.acquire().await else {
+                // Stopped while waiting: the row was not sent
+                root.set("stopped", true);
+                row_done.cancel();
+                in_scope -= 1;
+                taken -= 1;
+                break;
+            };
+            pb.set_message(format!("limit {}", limiter.limit()));
+            let pb = pb.clone();
+            let handle = tokio::spawn(in_row(line, async move {
+                info!(
+                    "[{:?}] Starting migration process for user.",
+                    pii(&record.identities[0].issuerAssignedId)
+                );
+                let UserOutcome { created, failure } = in_span(
+                    &root,
+                    create_user_api_call(
+                        &client,
+                        &endpoint,
+                        record,
+                        &tokens,
+                        has_phone_auth_method,
+                        has_email_auth_method,
+                        customizations,
+                    ),
+                )
+                .await;
+                root.set("created", created);
+                drop(root);
+                observe_user(created, failure);
+                observe_row(if created {
+                    RowResult::Created
+                } else {
+                    RowResult::Failed
+                });
+                pb.inc(1);
+                // The permit is automatically released at the end of the task (thanks to drop)
+                drop(permit);
+                drop(row_done);
+                created
+            }));
+            handles.push(handle);
+        }
 
-    let mut handles = vec![];
-
-    info!("Starting migration process. Using file {file_path} with {max_concurrent_requests} threads.");
-    // Iterate over each row of the CSV, deserializing it into RequestBody
-    for result in rdr.deserialize() {
-        let record: RequestBody = result?;
-        let client = client.clone();
-        let endpoint = format!("{endpoint}/v1.0/users");
-        let bearer_token = bearer_token.to_string();
-        let semaphore_clone = semaphore.clone();
-        let customizations = customizations_handler.clone();
-        // Acquire permission to respect the concurrency limit
-        let permit = semaphore_clone.acquire_owned().await?;
-        let pb = pb.clone();
-        let handle = tokio::spawn(async move {
+        // Wait for all tasks of the unit to complete
+        let spawned = handles.len() as u64;
+        let mut created = 0;
+        for handle in handles {
+            if handle.await? {
+                created += 1;
+            }
+        }
+        let outcome = UnitOutcome {
+            created,
+            failed: spawned - created,
+            skipped: in_scope - spawned,
+        };
+        let stopped = limiter.is_closed();
+        let lost = lease_lost.load(Ordering::Relaxed);
+        if lost {
+            warn!(
+                "Left {scope} to its new owner after {taken} rows: {} created, {} failed, {} skipped.",
+                outcome.created, outcome.failed, outcome.skipped
+            );
+        } else if stopped {
+            warn!(
+                "Stopped in {scope} after {taken} rows: {} created, {} failed, {} skipped.",
+                outcome.created, outcome.failed, outcome.skipped
+            );
+        } else {
             info!(
-                "[{:?}] Starting migration process for user.",
-                record.identities[0].issuerAssignedId
+                "Finished {scope}: {} created, {} failed, {} skipped.",
+                outcome.created, outcome.failed, outcome.skipped
             );
-            create_user_api_call(
-                &client,
-                &endpoint,
-                record,
-                &bearer_token,
-                has_phone_auth_method,
-                has_email_auth_method,
-                customizations,
-            )
-            .await;
-            pb.inc(1);
-            // The permit is automatically released at the end of the task (thanks to drop)
-            drop(permit);
-        });
-        handles.push(handle);
+        }
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.abort();
+        }
+        if let Some(store) = &state {
+            match range {
+                // Only the rows gone through are done: the rest is leased
+                // again, or migrated by the new owner of a lease taken over
+                Some(range) if stopped || lost => {
+                    let done = WorkRange {
+                        start: range.start,
+                        end: range.start + taken,
+                    };
+                    if taken > 0 {
+                        store.record_outcome(
+                            &run_id,
+                            &done.to_string(),
+                            &worker,
+                            outcome,
+                            now(),
+                        )?;
+                    }
+                    if !lost {
+                        store.release(&run_id, range, done.end, now())?;
+                    }
+                }
+                Some(range) => {
+                    store.record_outcome(&run_id, &scope, &worker, outcome, now())?;
+                    store.complete(&run_id, range, now())?;
+                }
+                None => store.record_outcome(&run_id, &scope, &worker, outcome, now())?,
+            }
+        }
+        if range.is_none() || stopped {
+            break;
+        }
     }
 
-    // Wait for all tasks to complete
-    for handle in handles {
-        handle.await?;
+    finish_outputs()?;
+
+    if let Some(pool) = app_pool() {
+        pool.log_stats();
+    }
+    if let Some(tracer) = tracer() {
+        tracer.flush().await;
     }
 
-    pb.finish_with_message("CSV processing complete");
-    info!("[END] All operations for the CSV have been completed.");
+    pb.finish_with_message("Input processing complete");
+    if let Some(dashboard) = dashboard {
+        dashboard.finish();
+        if let Some(stats) = run_stats() {
+            let counts = stats.counts();
+            println!(
+                "{} created, {} failed, {} skipped in {}.",
+                counts.created,
+                counts.failed,
+                counts.skipped,
+                HumanDuration(pb.elapsed())
+            );
+        }
+    }
+    if limiter.is_closed() {
+        info!("[END] Run stopped, the remaining rows are left for a later run.");
+    } else {
+        info!("[END] All operations for the input have been completed.");
+    }
     Ok(())
 }
 
+// Count a row of the scope that is not sent to Graph
+fn skip_row(pb: &ProgressBar) {
+    pb.inc(1);
+    observe_row(RowResult::Skipped);
+}
+
+// Logging options of the run, shared by the migration and the JIT server
+fn log_settings(matches: &ArgMatches) -> Result<LogSettings, Box<dyn Error>> {
+    Ok(LogSettings {
+        format: matches
+            .get_one::<String>("logformat")
+            .expect("Log format is required")
+            .parse()?,
+        filter: matches
+            .get_one::<String>("loglevel")
+            .expect("Log level is required")
+            .parse()?,
+        run_id: matches.get_one::<String>("runid").cloned(),
+        console: true,
+    })
+}
+
+// Run the just-in-time migration API called by the B2C custom policy
+async fn jit_server(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let pii_mode = matches
+        .get_one::<String>("piimode")
+        .expect("PII mode is required")
+        .parse::<PiiMode>()?;
+    init_redaction(pii_mode);
+    setup_logger(
+        matches
+            .get_one::<String>("logfile")
+            .expect("Log file path is required")
+            .clone(),
+        matches
+            .get_one::<String>("dbfile")
+            .expect("DB file path is required")
+            .clone(),
+        log_settings(matches)?,
+    )?;
+
+    let app_id = matches
+        .get_one::<String>("extensionappid")
+        .ok_or("--extension-app-id is required by the JIT migration server")?;
+    let attribute = matches
+        .get_one::<String>("migrationattribute")
+        .expect("Migration attribute is required");
+    let graph = graph_endpoint(matches)?;
+    let client = reqwest::Client::new();
+    // The token is renewed with the client credentials, a --token is used as is
+    if matches.get_one::<String>("token").is_some() {
+        warn!("The --token given is never renewed: migration flags are no longer cleared once it expires.");
+    }
+    let tokens = run_tokens(matches, &client, &graph).await?;
+    let state = JitState {
+        store: HashStore::open(
+            matches
+                .get_one::<String>("hashstore")
+                .expect("Hash store path is required"),
+        )?,
+        client,
+        users_endpoint: graph.users(),
+        tokens: Arc::new(tokens),
+        attribute: extension_attribute(app_id, attribute),
+        api_key: matches
+            .get_one::<String>("apikey")
+            .cloned()
+            .or_else(|| std::env::var("B2C_MIGRATOR_API_KEY").ok()),
+    };
+    if state.api_key.is_none() {
+        warn!("No API key configured, the validation endpoint is not authenticated.");
+    }
+    run_jit_server(
+        matches
+            .get_one::<String>("listen")
+            .expect("Listen address is required"),
+        state,
+    )
+    .await
+}
+
+// Print the merged outcome of a distributed run
+fn run_report(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let store = matches
+        .get_one::<String>("state")
+        .ok_or("--state is required by the report")?
+        .parse::<StateStore>()?;
+    let run_id = matches
+        .get_one::<String>("runid")
+        .ok_or("--run-id is required by the report")?;
+    println!("{}", store.report(run_id)?);
+    Ok(())
+}
+
+// Send a command to the control endpoint of a running migration
+async fn run_ctl(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let address = matches
+        .get_one::<String>("controladdr")
+        .ok_or("--control-addr is required by ctl")?;
+    let command = match matches.subcommand() {
+        Some(("summary", _)) => ControlCommand::Summary,
+        Some(("pause", _)) => ControlCommand::Pause,
+        Some(("resume", _)) => ControlCommand::Resume,
+        Some(("concurrency", args)) => {
+            let limit = args.get_one::<String>("limit").expect("Limit is required");
+            ControlCommand::Concurrency(
+                limit
+                    .parse::<usize>()
+                    .map_err(|e| format!("Invalid concurrency {limit}: {e}"))?,
+            )
+        }
+        Some(("rate", args)) => {
+            let kind = args
+                .get_one::<String>("kind")
+                .expect("Request kind is required")
+                .parse::<RequestKind>()?;
+            let rps = match args
+                .get_one::<String>("rps")
+                .expect("Rate is required")
+                .as_str()
+            {
+                "off" => None,
+                rps => Some(
+                    rps.parse::<f64>()
+                        .map_err(|e| format!("Invalid rate {rps}: {e}"))?,
+                ),
+            };
+            ControlCommand::Rate(kind, rps)
+        }
+        Some(("stop", _)) => ControlCommand::Stop,
+        _ => unreachable!("ctl requires a command"),
+    };
+    println!("{}", send_control(address, &command).await?);
+    Ok(())
+}
+
+/// Name of this instance in the state database: `--worker-id`, or the host
+/// name and process id.
+fn worker_id(matches: &ArgMatches) -> String {
+    matches
+        .get_one::<String>("workerid")
+        .cloned()
+        .unwrap_or_else(|| {
+            let host = std::env::var("HOSTNAME")
+                .or_else(|_| std::env::var("COMPUTERNAME"))
+                .unwrap_or_else(|_| "worker".to_string());
+            format!("{host}-{}", std::process::id())
+        })
+}
+
+/// Current time, in seconds, as stored in the state database.
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// Renew a lease every third of its timeout with the rows confirmed by
+/// `checkpoint`, until aborted or taken over by another worker, which sets
+/// `lost`.
+async fn renew_lease(
+    store: StateStore,
+    run_id: String,
+    range: WorkRange,
+    worker: String,
+    lease_timeout: std::time::Duration,
+    checkpoint: Checkpoint,
+    lost: Arc<AtomicBool>,
+) {
+    let mut interval = tokio::time::interval(lease_timeout / 3);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let (store, run_id, worker) = (store.clone(), run_id.clone(), worker.clone());
+        let done_until = checkpoint.done_until();
+        match tokio::task::spawn_blocking(move || {
+            store
+                .heartbeat(&run_id, range, &worker, done_until, now())
+                .map_err(|e| e.to_string())
+        })
+        .await
+        {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => {
+                warn!("Lease of {range} was taken over by another worker, the rows after row {done_until} are left to it.");
+                lost.store(true, Ordering::Relaxed);
+                return;
+            }
+            Ok(Err(e)) => warn!("Lease of {range} not renewed: {e}"),
+            Err(e) => warn!("Lease of {range} not renewed: {e}"),
+        }
+    }
+}
+
+/// Graph base URL and API version of the run: `--url` overrides the URL of
+/// the `--cloud` profile.
+fn graph_endpoint(matches: &ArgMatches) -> Result<GraphEndpoint, Box<dyn Error>> {
+    let cloud = matches
+        .get_one::<String>("cloud")
+        .expect("Cloud is required")
+        .parse::<Cloud>()?;
+    let base = match matches.get_one::<String>("url") {
+        Some(url) => url.as_str(),
+        None => cloud
+            .graph_url()
+            .ok_or("--url is required by the custom cloud")?,
+    };
+    let version = matches
+        .get_one::<String>("graphversion")
+        .expect("Graph API version is required");
+    Ok(GraphEndpoint::new(base, version))
+}
+
+/// Token authority of the run: `--authority` overrides the one of the
+/// `--cloud` profile.
+fn token_authority(matches: &ArgMatches) -> Result<String, Box<dyn Error>> {
+    if let Some(authority) = matches.get_one::<String>("authority") {
+        return Ok(authority.clone());
+    }
+    let cloud = matches
+        .get_one::<String>("cloud")
+        .expect("Cloud is required")
+        .parse::<Cloud>()?;
+    Ok(cloud
+        .authority()
+        .ok_or("--authority is required by the custom cloud")?
+        .to_string())
+}
+
+/// Tokens of the run: the `--token` given, used as is, or app-only tokens
+/// acquired from the authority of the `--cloud` profile and renewed before
+/// they expire.
+async fn run_tokens(
+    matches: &ArgMatches,
+    client: &reqwest::Client,
+    graph: &GraphEndpoint,
+) -> Result<TokenCache, Box<dyn Error>> {
+    if let Some(token) = matches.get_one::<String>("token") {
+        return Ok(TokenCache::fixed(token));
+    }
+    let credentials = client_credentials(matches, graph)?;
+    let tokens = TokenCache::connect(client, credentials.clone()).await?;
+    info!(
+        "Token acquired from {} for client {}, renewed before it expires.",
+        credentials.authority, credentials.client_id
+    );
+    Ok(tokens)
+}
+
+/// Client credentials given with `--tenant-id`, `--client-id` and
+/// `--client-secret`, to acquire tokens from the authority of the `--cloud`
+/// profile.
+fn client_credentials(
+    matches: &ArgMatches,
+    graph: &GraphEndpoint,
+) -> Result<ClientCredentials, Box<dyn Error>> {
+    let client_id = matches
+        .get_one::<String>("clientid")
+        .expect("Client id is required without a token");
+    let tenant_id = matches
+        .get_one::<String>("tenantid")
+        .expect("Tenant id is required with a client id");
+    let client_secret = matches
+        .get_one::<String>("clientsecret")
+        .cloned()
+        .or_else(|| std::env::var("B2C_MIGRATOR_CLIENT_SECRET").ok())
+        .ok_or("--client-secret (or B2C_MIGRATOR_CLIENT_SECRET) is required to acquire a token")?;
+    Ok(ClientCredentials {
+        authority: token_authority(matches)?,
+        tenant_id: tenant_id.clone(),
+        client_id: client_id.clone(),
+        client_secret,
+        graph: graph.clone(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TempPath;
     use rusqlite::Connection;
     use std::io::Write;
     use std::sync::{Arc, Mutex};
@@ -475,7 +1894,12 @@ mod tests {
                 issuerAssignedId: issuer_assigned_id.to_string(),
             }],
             phoneAuthMethod: None,
+            alternateMobilePhoneAuthMethod: None,
+            officePhoneAuthMethod: None,
             emailAuthMethod: None,
+            softwareOathSecretKey: None,
+            legacyId: None,
+            legacyPassword: None,
             custom_fields: HashMap::new(),
         }
     }
@@ -486,10 +1910,15 @@ mod tests {
         let endpoint = server.url();
         let client = reqwest::Client::new();
         let body = create_dummy_request_body("user_success");
-        let bearer_token = "Bearer token";
+        let tokens = TokenCache::fixed("token");
 
         let mock = server
             .mock("POST", "/")
+            .match_header(
+                "client-request-id",
+                mockito::Matcher::Regex("^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-".to_string()),
+            )
+            .match_header("return-client-request-id", "true")
             .with_status(200)
             .with_body(r#"{"status": "ok"}"#)
             .create_async()
@@ -499,18 +1928,162 @@ mod tests {
             &client,
             &endpoint,
             body,
-            bearer_token,
+            &tokens,
             false,
             false,
-            Customizations {
-                prj1: false,
-                prj1_config: None,
-            },
+            Customizations::default(),
         )
         .await;
         mock.assert_async().await;
     }
 
+    #[tokio::test]
+    async fn test_make_async_rest_call_renews_a_rejected_token() {
+        let mut server = mockito::Server::new_async().await;
+        let first = server
+            .mock("POST", "/contoso/oauth2/v2.0/token")
+            .with_body(r#"{"expires_in": 3599, "access_token": "token-1"}"#)
+            .create_async()
+            .await;
+        let client = reqwest::Client::new();
+        let tokens = TokenCache::connect(
+            &client,
+            ClientCredentials {
+                authority: server.url(),
+                tenant_id: "contoso".to_string(),
+                client_id: "client".to_string(),
+                client_secret: "secret".to_string(),
+                graph: GraphEndpoint::new(&server.url(), "v1.0"),
+            },
+        )
+        .await
+        .unwrap();
+        first.remove_async().await;
+        let renewal = server
+            .mock("POST", "/contoso/oauth2/v2.0/token")
+            .with_body(r#"{"expires_in": 3599, "access_token": "token-2"}"#)
+            .create_async()
+            .await;
+        let rejected = server
+            .mock("POST", "/v1.0/users")
+            .match_header("Authorization", "Bearer token-1")
+            .with_status(401)
+            .create_async()
+            .await;
+        let accepted = server
+            .mock("POST", "/v1.0/users")
+            .match_header("Authorization", "Bearer token-2")
+            .with_status(201)
+            .with_body(r#"{"id": "object-id"}"#)
+            .create_async()
+            .await;
+
+        // The token of the run expired early: renewed instead of exiting
+        let outcome = create_user_api_call(
+            &client,
+            &format!("{}/v1.0/users", server.url()),
+            create_dummy_request_body("user_renewed"),
+            &tokens,
+            false,
+            false,
+            Customizations::default(),
+        )
+        .await;
+        assert!(outcome.created);
+        renewal.assert_async().await;
+        rejected.assert_async().await;
+        accepted.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_make_async_rest_call_counts_a_conflict_of_a_lease_taken_over() {
+        let mut server = mockito::Server::new_async().await;
+        let endpoint = server.url();
+        let client = reqwest::Client::new();
+        let tokens = TokenCache::fixed("token");
+        server
+            .mock("POST", "/")
+            .with_status(409)
+            .with_body(r#"{"error": {"code": "ObjectConflict", "message": "Another object with the same value for property userPrincipalName already exists."}}"#)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let create = |taken_over| {
+            create_user_api_call(
+                &client,
+                &endpoint,
+                create_dummy_request_body("user_conflict"),
+                &tokens,
+                false,
+                false,
+                Customizations {
+                    taken_over,
+                    ..Default::default()
+                },
+            )
+        };
+        // Created by the previous owner of the lease, before its last checkpoint
+        let created = UserOutcome {
+            created: true,
+            failure: None,
+        };
+        assert_eq!(create(true).await, created);
+        let failed = UserOutcome {
+            created: false,
+            failure: Some(StageFailure {
+                stage: Stage::User,
+                status: Some(409),
+            }),
+        };
+        assert_eq!(create(false).await, failed);
+    }
+
+    #[tokio::test]
+    async fn test_make_async_rest_call_creates_software_oath_method() {
+        let mut server = mockito::Server::new_async().await;
+        let endpoint = format!("{}/v1.0/users", server.url());
+        let client = reqwest::Client::new();
+        let mut body = create_dummy_request_body("user_oath");
+        body.softwareOathSecretKey = Some("JBSWY3DPEHPK3PXP".to_string());
+        let tokens = TokenCache::fixed("token");
+
+        let user_mock = server
+            .mock("POST", "/v1.0/users")
+            .with_status(201)
+            .with_body(r#"{"id": "object-id"}"#)
+            .create_async()
+            .await;
+        let oath_mock = server
+            .mock(
+                "POST",
+                "/beta/users/object-id/authentication/softwareOathMethods",
+            )
+            .match_body(mockito::Matcher::Json(
+                serde_json::json!({"secretKey": "JBSWY3DPEHPK3PXP"}),
+            ))
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let customizations = Customizations {
+            oath_endpoint: Some(format!("{}/beta/users", server.url())),
+            ..Default::default()
+        };
+        create_user_api_call(
+            &client,
+            &endpoint,
+            body,
+            &tokens,
+            false,
+            false,
+            customizations,
+        )
+        .await;
+        user_mock.assert_async().await;
+        oath_mock.assert_async().await;
+    }
+
     #[tokio::test]
     async fn test_make_async_rest_call_429_with_retry_after_and_success() {
         // Pause time to control sleep
@@ -520,7 +2093,7 @@ mod tests {
         let endpoint = server.url();
         let client = reqwest::Client::new(); // Keep client for reuse
         let body = create_dummy_request_body("user_429_retry");
-        let bearer_token = "Bearer token";
+        let tokens = TokenCache::fixed("token");
 
         // First call: 429 with Retry-After
         let mock429 = server
@@ -547,13 +2120,10 @@ mod tests {
                 &client_clone,
                 &endpoint_clone,
                 body,
-                bearer_token,
+                &tokens,
                 false,
                 false,
-                Customizations {
-                    prj1: false,
-                    prj1_config: None,
-                },
+                Customizations::default(),
             )
             .await
         });
@@ -580,7 +2150,7 @@ mod tests {
         let endpoint = server.url();
         let client = reqwest::Client::new();
         let body = create_dummy_request_body("user_429_invalid_retry");
-        let bearer_token = "Bearer token";
+        let tokens = TokenCache::fixed("token");
 
         let mock = server
             .mock("POST", "/")
@@ -596,13 +2166,10 @@ mod tests {
             &client,
             &endpoint,
             body,
-            bearer_token,
+            &tokens,
             false,
             false,
-            Customizations {
-                prj1: false,
-                prj1_config: None,
-            },
+            Customizations::default(),
         )
         .await;
         mock.assert_async().await; // Should only be called once
@@ -614,7 +2181,7 @@ mod tests {
         let endpoint = server.url();
         let client = reqwest::Client::new();
         let body = create_dummy_request_body("user_429_no_retry_header");
-        let bearer_token = "Bearer token";
+        let tokens = TokenCache::fixed("token");
 
         let mock = server
             .mock("POST", "/")
@@ -628,13 +2195,10 @@ mod tests {
             &client,
             &endpoint,
             body,
-            bearer_token,
+            &tokens,
             false,
             false,
-            Customizations {
-                prj1: false,
-                prj1_config: None,
-            },
+            Customizations::default(),
         )
         .await;
         mock.assert_async().await; // Should only be called once
@@ -646,7 +2210,7 @@ mod tests {
         let endpoint = server.url();
         let client = reqwest::Client::new();
         let body = create_dummy_request_body("user_400_error");
-        let bearer_token = "Bearer token";
+        let tokens = TokenCache::fixed("token");
 
         let mock = server
             .mock("POST", "/")
@@ -659,13 +2223,10 @@ mod tests {
             &client,
             &endpoint,
             body,
-            bearer_token,
+            &tokens,
             false,
             false,
-            Customizations {
-                prj1: false,
-                prj1_config: None,
-            },
+            Customizations::default(),
         )
         .await;
         mock.assert_async().await; // Should be called once, no retry
@@ -677,7 +2238,7 @@ mod tests {
         let endpoint = server.url();
         let client = reqwest::Client::new();
         let body = create_dummy_request_body("user_500_error");
-        let bearer_token = "Bearer token";
+        let tokens = TokenCache::fixed("token");
 
         let mock = server
             .mock("POST", "/")
@@ -690,13 +2251,10 @@ mod tests {
             &client,
             &endpoint,
             body,
-            bearer_token,
+            &tokens,
             false,
             false,
-            Customizations {
-                prj1: false,
-                prj1_config: None,
-            },
+            Customizations::default(),
         )
         .await;
         mock.assert_async().await; // Should be called once, no retry
@@ -709,7 +2267,7 @@ mod tests {
         let endpoint = "http://localhost:12345"; // Assuming this port is not in use
         let client = reqwest::Client::new();
         let body = create_dummy_request_body("user_network_error");
-        let bearer_token = "Bearer token";
+        let tokens = TokenCache::fixed("token");
 
         // We can't easily assert logs here without a more complex setup,
         // but the main thing is that the function should complete and not panic.
@@ -718,16 +2276,139 @@ mod tests {
             &client,
             endpoint,
             body,
-            bearer_token,
+            &tokens,
             false,
             false,
-            Customizations {
-                prj1: false,
-                prj1_config: None,
-            },
+            Customizations::default(),
         )
         .await;
         // No mockito assertion here as we are not using a mockito server for this specific test.
         // We rely on the function's own error logging and graceful exit from the loop.
     }
+
+    #[tokio::test]
+    async fn test_renew_lease_signals_a_takeover() {
+        let path = TempPath::file("db");
+        let store: StateStore = format!("sqlite://{}", path.display()).parse().unwrap();
+        store.init().unwrap();
+        store.register_run("run", 10, 10, now()).unwrap();
+        let timeout = std::time::Duration::from_secs(60);
+        let range = store
+            .claim("run", "a", now(), timeout)
+            .unwrap()
+            .unwrap()
+            .range;
+        // Worker b takes the lease over, as if a had stopped sending heartbeats
+        let taken = store.claim("run", "b", now() + 120, timeout).unwrap();
+        assert_eq!(taken.map(|lease| lease.range), Some(range));
+
+        let lost = Arc::new(AtomicBool::new(false));
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            renew_lease(
+                store,
+                "run".to_string(),
+                range,
+                "a".to_string(),
+                std::time::Duration::from_millis(300),
+                Checkpoint::new(range.start),
+                lost.clone(),
+            ),
+        )
+        .await
+        .unwrap();
+        assert!(lost.load(Ordering::Relaxed));
+    }
+
+    // Child side of test_interrupted_run_leaves_decryptable_outputs: a run
+    // stopped by a 401, which exits the process
+    async fn interrupted_run(dir: &std::path::Path, recipient: &str) {
+        let recipient = parse_recipient(recipient).unwrap();
+        let mapping_file =
+            MappingFile::create(dir.join("mapping.csv.age"), Some(&recipient)).unwrap();
+        let credentials_file =
+            CredentialsFile::create(dir.join("credentials.csv.age"), &recipient).unwrap();
+        finish_at_exit(mapping_file.clone());
+        finish_at_exit(credentials_file.clone());
+        let customizations = Customizations {
+            mapping_file: Some(mapping_file),
+            credentials_file: Some(credentials_file),
+            ..Default::default()
+        };
+
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("created@test.com".to_string()))
+            .with_status(201)
+            .with_body(r#"{"id": "object-id"}"#)
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/")
+            .with_status(401)
+            .create_async()
+            .await;
+        let client = reqwest::Client::new();
+        for user in ["created@test.com", "rejected@test.com"] {
+            create_user_api_call(
+                &client,
+                &server.url(),
+                create_dummy_request_body(user),
+                &TokenCache::fixed("token"),
+                false,
+                false,
+                customizations.clone(),
+            )
+            .await;
+        }
+        unreachable!("the 401 exits the process");
+    }
+
+    #[test]
+    fn test_interrupted_run_leaves_decryptable_outputs() {
+        const CHILD: &str = "B2C_MIGRATOR_INTERRUPTED_RUN";
+        if let Ok(dir) = std::env::var(CHILD) {
+            let recipient = std::env::var("B2C_MIGRATOR_RECIPIENT").unwrap();
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(interrupted_run(std::path::Path::new(&dir), &recipient));
+        }
+
+        let identity = age::x25519::Identity::generate();
+        let dir = TempPath::dir();
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "tests::test_interrupted_run_leaves_decryptable_outputs",
+            ])
+            .env(CHILD, dir.as_os_str())
+            .env("B2C_MIGRATOR_RECIPIENT", identity.to_public().to_string())
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(0));
+
+        let decrypt = |name: &str| {
+            let encrypted = std::fs::read(dir.join(name)).unwrap();
+            let mut plaintext = String::new();
+            std::io::Read::read_to_string(
+                &mut age::Decryptor::new(&encrypted[..])
+                    .unwrap()
+                    .decrypt(std::iter::once(&identity as &dyn age::Identity))
+                    .unwrap(),
+                &mut plaintext,
+            )
+            .unwrap();
+            plaintext
+        };
+        let mapping = decrypt("mapping.csv.age");
+        let credentials = decrypt("credentials.csv.age");
+        assert_eq!(
+            mapping,
+            "legacyId,issuerAssignedId,objectId\n,created@test.com,object-id\n"
+        );
+        assert!(credentials.ends_with(",Test User,created@test.com,object-id,password\n"));
+        assert!(!credentials.contains("rejected@test.com"));
+    }
 }
@@ -0,0 +1,5 @@
+mod registry;
+mod server;
+
+pub use crate::metrics::registry::*;
+pub use crate::metrics::server::*;
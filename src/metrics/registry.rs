@@ -0,0 +1,380 @@
+use crate::dashboard::observe_request;
+use crate::graph::limiter;
+use crate::run::run_context;
+use indicatif::ProgressBar;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Stages of the migration of a user, each calling its own endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    User,
+    PhoneMethods,
+    EmailMethods,
+    SoftwareOathMethods,
+    Notification,
+}
+
+impl Stage {
+    /// Label of the stage in the metrics.
+    pub fn as_label(&self) -> &'static str {
+        match self {
+            Stage::User => "user",
+            Stage::PhoneMethods => "phoneMethods",
+            Stage::EmailMethods => "emailMethods",
+            Stage::SoftwareOathMethods => "softwareOathMethods",
+            Stage::Notification => "notification",
+        }
+    }
+}
+
+/// Graph call that failed in the migration of a user: its stage, and the
+/// status of its response (`None` when none was received).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StageFailure {
+    pub stage: Stage,
+    pub status: Option<u16>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct MetricsState {
+    /// By result, then stage and status of the first call that failed
+    users: BTreeMap<(&'static str, Option<StageFailure>), u64>,
+    requests: BTreeMap<(Stage, String), u64>,
+    throttled: BTreeMap<Stage, u64>,
+    retries: BTreeMap<Stage, u64>,
+    latency: BTreeMap<Stage, Histogram>,
+}
+
+/// Counters and histograms of a run, rendered in the Prometheus text format.
+///
+/// The concurrency gauges are read from the limiter and the queue depth from
+/// the progress bar when scraped.
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+    progress: OnceLock<ProgressBar>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Follow the progress bar of the run, for the queue depth.
+    pub fn set_progress(&self, pb: ProgressBar) {
+        let _ = self.progress.set(pb);
+    }
+
+    /// Record a response of a stage, `None` meaning the request failed
+    /// without one.
+    pub fn response(&self, stage: Stage, status: Option<u16>, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let status = status_label(status);
+        if status == "429" || status == "503" {
+            *state.throttled.entry(stage).or_default() += 1;
+        }
+        *state.requests.entry((stage, status)).or_default() += 1;
+        state
+            .latency
+            .entry(stage)
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Record a request sent again after a throttled response.
+    pub fn retry(&self, stage: Stage) {
+        *self.state.lock().unwrap().retries.entry(stage).or_default() += 1;
+    }
+
+    /// Record the outcome of the migration of a user, with the first call
+    /// that failed: the user itself, or one of its authentication methods.
+    pub fn user(&self, created: bool, failure: Option<StageFailure>) {
+        let result = if created { "created" } else { "failed" };
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .users
+            .entry((result, failure))
+            .or_default() += 1;
+    }
+
+    /// Metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "users_total",
+            "counter",
+            "Users migrated, by result, and stage and status code of the first call that failed.",
+        );
+        for ((result, failure), n) in &state.users {
+            match failure {
+                Some(failure) => {
+                    let _ = writeln!(
+                        out,
+                        "b2c_migrator_users_total{{result=\"{result}\",stage=\"{}\",status=\"{}\"}} {n}",
+                        failure.stage.as_label(),
+                        status_label(failure.status)
+                    );
+                }
+                None => {
+                    let _ = writeln!(out, "b2c_migrator_users_total{{result=\"{result}\"}} {n}");
+                }
+            }
+        }
+
+        header(
+            &mut out,
+            "requests_total",
+            "counter",
+            "Responses received, by stage and status code (error when none was received).",
+        );
+        for ((stage, status), n) in &state.requests {
+            let _ = writeln!(
+                out,
+                "b2c_migrator_requests_total{{stage=\"{}\",status=\"{status}\"}} {n}",
+                stage.as_label()
+            );
+        }
+
+        header(
+            &mut out,
+            "throttled_total",
+            "counter",
+            "Responses with status 429 or 503, by stage.",
+        );
+        for (stage, n) in &state.throttled {
+            let _ = writeln!(
+                out,
+                "b2c_migrator_throttled_total{{stage=\"{}\"}} {n}",
+                stage.as_label()
+            );
+        }
+
+        header(
+            &mut out,
+            "retries_total",
+            "counter",
+            "Requests sent again after a Retry-After, by stage.",
+        );
+        for (stage, n) in &state.retries {
+            let _ = writeln!(
+                out,
+                "b2c_migrator_retries_total{{stage=\"{}\"}} {n}",
+                stage.as_label()
+            );
+        }
+
+        header(
+            &mut out,
+            "request_duration_seconds",
+            "histogram",
+            "Latency of the requests, by stage.",
+        );
+        for (stage, histogram) in &state.latency {
+            let stage = stage.as_label();
+            for (bound, n) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "b2c_migrator_request_duration_seconds_bucket{{stage=\"{stage}\",le=\"{bound}\"}} {n}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "b2c_migrator_request_duration_seconds_bucket{{stage=\"{stage}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "b2c_migrator_request_duration_seconds_sum{{stage=\"{stage}\"}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "b2c_migrator_request_duration_seconds_count{{stage=\"{stage}\"}} {}",
+                histogram.count
+            );
+        }
+        drop(state);
+
+        if let Some(limiter) = limiter() {
+            header(
+                &mut out,
+                "concurrency_limit",
+                "gauge",
+                "Current limit of concurrent users.",
+            );
+            let _ = writeln!(out, "b2c_migrator_concurrency_limit {}", limiter.limit());
+            header(&mut out, "in_flight", "gauge", "Users being migrated.");
+            let _ = writeln!(out, "b2c_migrator_in_flight {}", limiter.in_flight());
+        }
+        if let Some(pb) = self.progress.get() {
+            let done = pb.position();
+            header(
+                &mut out,
+                "queue_depth",
+                "gauge",
+                "Input rows not processed yet.",
+            );
+            let _ = writeln!(
+                out,
+                "b2c_migrator_queue_depth {}",
+                pb.length().unwrap_or(done).saturating_sub(done)
+            );
+            header(
+                &mut out,
+                "rows_processed",
+                "gauge",
+                "Input rows processed, including skipped ones.",
+            );
+            let _ = writeln!(out, "b2c_migrator_rows_processed {done}");
+        }
+        out
+    }
+}
+
+// Status code label of a response, error when none was received
+fn status_label(status: Option<u16>) -> String {
+    status.map_or("error".to_string(), |s| s.to_string())
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP b2c_migrator_{name} {help}");
+    let _ = writeln!(out, "# TYPE b2c_migrator_{name} {kind}");
+}
+
+/// Metrics of the run, if they were enabled.
+pub fn metrics() -> Option<&'static Metrics> {
+    run_context()?.metrics.as_ref()
+}
+
+/// Record a response of a stage, in the run statistics and, if metrics are
+/// enabled, in the metrics.
+pub fn observe_response(stage: Stage, status: Option<u16>, latency: Duration) {
+    observe_request();
+    if let Some(metrics) = metrics() {
+        metrics.response(stage, status, latency);
+    }
+}
+
+/// Record a retried request, if metrics are enabled.
+pub fn observe_retry(stage: Stage) {
+    if let Some(metrics) = metrics() {
+        metrics.retry(stage);
+    }
+}
+
+/// Record the outcome of a user, if metrics are enabled.
+pub fn observe_user(created: bool, failure: Option<StageFailure>) {
+    if let Some(metrics) = metrics() {
+        metrics.user(created, failure);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters_and_histograms() {
+        let metrics = Metrics::new();
+        metrics.response(Stage::User, Some(201), Duration::from_millis(80));
+        metrics.response(Stage::User, Some(429), Duration::from_millis(20));
+        metrics.retry(Stage::User);
+        metrics.response(Stage::User, Some(201), Duration::from_secs(3));
+        metrics.response(Stage::PhoneMethods, None, Duration::from_secs(90));
+        metrics.user(true, None);
+        metrics.user(true, None);
+        metrics.user(
+            false,
+            Some(StageFailure {
+                stage: Stage::User,
+                status: Some(400),
+            }),
+        );
+
+        let pb = ProgressBar::hidden();
+        pb.set_length(10);
+        pb.inc(4);
+        metrics.set_progress(pb);
+
+        let text = metrics.render();
+        for line in [
+            "b2c_migrator_users_total{result=\"created\"} 2",
+            "b2c_migrator_users_total{result=\"failed\",stage=\"user\",status=\"400\"} 1",
+            "b2c_migrator_requests_total{stage=\"user\",status=\"201\"} 2",
+            "b2c_migrator_requests_total{stage=\"user\",status=\"429\"} 1",
+            "b2c_migrator_requests_total{stage=\"phoneMethods\",status=\"error\"} 1",
+            "b2c_migrator_throttled_total{stage=\"user\"} 1",
+            "b2c_migrator_retries_total{stage=\"user\"} 1",
+            // Buckets are cumulative
+            "b2c_migrator_request_duration_seconds_bucket{stage=\"user\",le=\"0.05\"} 1",
+            "b2c_migrator_request_duration_seconds_bucket{stage=\"user\",le=\"0.1\"} 2",
+            "b2c_migrator_request_duration_seconds_bucket{stage=\"user\",le=\"2.5\"} 2",
+            "b2c_migrator_request_duration_seconds_bucket{stage=\"user\",le=\"5\"} 3",
+            "b2c_migrator_request_duration_seconds_bucket{stage=\"user\",le=\"+Inf\"} 3",
+            "b2c_migrator_request_duration_seconds_count{stage=\"user\"} 3",
+            "b2c_migrator_request_duration_seconds_bucket{stage=\"phoneMethods\",le=\"60\"} 0",
+            "b2c_migrator_request_duration_seconds_bucket{stage=\"phoneMethods\",le=\"+Inf\"} 1",
+            "b2c_migrator_queue_depth 6",
+            "b2c_migrator_rows_processed 4",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line} in\n{text}");
+        }
+        assert!(text.contains("# TYPE b2c_migrator_request_duration_seconds histogram"));
+    }
+
+    #[test]
+    fn test_render_the_stage_a_user_failed_in() {
+        let metrics = Metrics::new();
+        let phone = StageFailure {
+            stage: Stage::PhoneMethods,
+            status: Some(400),
+        };
+        metrics.user(true, Some(phone));
+        metrics.user(true, Some(phone));
+        metrics.user(
+            false,
+            Some(StageFailure {
+                stage: Stage::User,
+                status: None,
+            }),
+        );
+
+        let text = metrics.render();
+        for line in [
+            "b2c_migrator_users_total{result=\"created\",stage=\"phoneMethods\",status=\"400\"} 2",
+            "b2c_migrator_users_total{result=\"failed\",stage=\"user\",status=\"error\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line} in\n{text}");
+        }
+    }
+}
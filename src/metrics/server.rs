@@ -0,0 +1,68 @@
+use crate::metrics::Metrics;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use log::info;
+use std::error::Error;
+use std::net::SocketAddr;
+
+/// Routes of the metrics endpoint.
+pub fn metrics_router(metrics: &'static Metrics) -> Router {
+    Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(metrics)
+}
+
+/// Serve `metrics` on `listen` in the background, returning the address
+/// bound once it is listening.
+pub async fn serve_metrics(
+    listen: &str,
+    metrics: &'static Metrics,
+) -> Result<SocketAddr, Box<dyn Error>> {
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    let address = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, metrics_router(metrics)).await });
+    info!("Metrics available at http://{address}/metrics.");
+    Ok(address)
+}
+
+async fn scrape(State(metrics): State<&'static Metrics>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Stage;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_serves_the_registry() {
+        // Served for the rest of the test process, as for a run
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+        metrics.response(Stage::Notification, Some(200), Duration::from_millis(5));
+        let address = serve_metrics("127.0.0.1:0", metrics).await.unwrap();
+
+        let response = reqwest::get(format!("http://{address}/metrics"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let text = response.text().await.unwrap();
+        assert!(text.contains("b2c_migrator_requests_total{stage=\"notification\",status=\"200\"}"));
+
+        let missing = reqwest::get(format!("http://{address}/other"))
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), 404);
+    }
+}
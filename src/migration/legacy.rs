@@ -0,0 +1,262 @@
+use crate::graph::{extension_attribute, PasswordPolicy, Row};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+
+/// Hash algorithms of legacy passwords that can be verified at first sign-in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    /// Modular crypt format (`$2b$...`), the salt and cost are part of the hash
+    Bcrypt,
+    /// PBKDF2 with HMAC-SHA1, as Keycloak's `pbkdf2`
+    #[serde(alias = "pbkdf2")]
+    Pbkdf2Sha1,
+    Pbkdf2Sha256,
+    Pbkdf2Sha512,
+    /// Single SHA-256 of the salt followed by the password
+    Sha256,
+}
+
+/// Password hash of a user in the source system, carried by the
+/// `legacyPassword` key of a row. `hash` and `salt` are base64 encoded,
+/// except for bcrypt hashes.
+///
+/// Example value:
+/// ```json
+/// {"algorithm": "pbkdf2-sha256", "hash": "Jx8...", "salt": "c2FsdA==", "iterations": 27500}
+/// ```
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct LegacyPassword {
+    pub algorithm: HashAlgorithm,
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iterations: Option<u32>,
+}
+
+// Hashes are credentials too, keep them out of the logs
+impl fmt::Debug for LegacyPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LegacyPassword")
+            .field("algorithm", &self.algorithm)
+            .field("hash", &"[REDACTED]")
+            .finish()
+    }
+}
+
+/// Length of a SHA-256 digest
+const SHA256_LEN: usize = 32;
+/// Shortest PBKDF2 hash accepted
+const MIN_PBKDF2_LEN: usize = 16;
+
+impl LegacyPassword {
+    /// Check `password` against the legacy hash.
+    pub fn verify(&self, password: &str) -> Result<bool, String> {
+        let password = password.as_bytes();
+        let expected = match self.algorithm {
+            HashAlgorithm::Bcrypt => {
+                return bcrypt::verify(password, &self.hash).map_err(|e| e.to_string());
+            }
+            _ => decode(&self.hash, "hash")?,
+        };
+        // The key is derived at the length of the stored hash, a short one
+        // would match too many passwords
+        match self.algorithm {
+            HashAlgorithm::Sha256 if expected.len() != SHA256_LEN => {
+                return Err(format!(
+                    "SHA-256 hashes must be {SHA256_LEN} bytes, found {}",
+                    expected.len()
+                ));
+            }
+            HashAlgorithm::Sha256 => {}
+            _ if expected.len() < MIN_PBKDF2_LEN => {
+                return Err(format!(
+                    "PBKDF2 hashes must be at least {MIN_PBKDF2_LEN} bytes, found {}",
+                    expected.len()
+                ));
+            }
+            _ => {}
+        }
+        let salt = match &self.salt {
+            Some(salt) => decode(salt, "salt")?,
+            None => Vec::new(),
+        };
+        let actual = match self.algorithm {
+            HashAlgorithm::Sha256 => Sha256::new()
+                .chain_update(&salt)
+                .chain_update(password)
+                .finalize()
+                .to_vec(),
+            HashAlgorithm::Bcrypt => unreachable!(),
+            pbkdf2 => {
+                let rounds = self
+                    .iterations
+                    .filter(|i| *i > 0)
+                    .ok_or("PBKDF2 hashes need the number of iterations")?;
+                // The derived key has the length of the stored hash
+                let mut key = vec![0u8; expected.len()];
+                match pbkdf2 {
+                    HashAlgorithm::Pbkdf2Sha1 => {
+                        pbkdf2::pbkdf2_hmac::<sha1::Sha1>(password, &salt, rounds, &mut key)
+                    }
+                    HashAlgorithm::Pbkdf2Sha256 => {
+                        pbkdf2::pbkdf2_hmac::<Sha256>(password, &salt, rounds, &mut key)
+                    }
+                    _ => pbkdf2::pbkdf2_hmac::<Sha512>(password, &salt, rounds, &mut key),
+                }
+                key
+            }
+        };
+        Ok(constant_time_eq(&actual, &expected))
+    }
+}
+
+fn decode(value: &str, name: &str) -> Result<Vec<u8>, String> {
+    BASE64
+        .decode(value.trim())
+        .map_err(|e| format!("Invalid base64 {name}: {e}"))
+}
+
+// Compare without leaking the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether the row carries a legacy password hash.
+pub fn has_legacy_password(row: &Row) -> bool {
+    match row.get("legacyPassword") {
+        None | Some(Value::Null) => false,
+        Some(Value::String(s)) => !s.trim().is_empty(),
+        Some(_) => true,
+    }
+}
+
+/// Flag a row with a legacy password for just-in-time migration: the
+/// `attribute` extension attribute is set to true and the user gets a random
+/// password nobody knows, replaced at first sign-in once the legacy password
+/// has been verified. Returns whether the row was flagged.
+pub fn flag_for_migration(
+    row: &mut Row,
+    extension_app_id: &str,
+    attribute: &str,
+    policy: &PasswordPolicy,
+) -> bool {
+    if !has_legacy_password(row) {
+        return false;
+    }
+    row.insert(
+        extension_attribute(extension_app_id, attribute),
+        Value::Bool(true),
+    );
+    row.insert(
+        "passwordProfile".into(),
+        json!({
+            "forceChangePasswordNextSignIn": false,
+            "password": policy.generate(),
+        }),
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy(algorithm: HashAlgorithm, hash: &str, salt: Option<&str>) -> LegacyPassword {
+        LegacyPassword {
+            algorithm,
+            hash: hash.to_string(),
+            salt: salt.map(str::to_string),
+            iterations: Some(1),
+        }
+    }
+
+    #[test]
+    fn test_verify_pbkdf2() {
+        // RFC 6070 test vector: P = "password", S = "salt", c = 1
+        let hash = BASE64.encode([
+            0x0c, 0x60, 0xc8, 0x0f, 0x96, 0x1f, 0x0e, 0x71, 0xf3, 0xa9, 0xb5, 0x24, 0xaf, 0x60,
+            0x12, 0x06, 0x2f, 0xe0, 0x37, 0xa6,
+        ]);
+        let password = legacy(HashAlgorithm::Pbkdf2Sha1, &hash, Some("c2FsdA=="));
+        assert_eq!(password.verify("password"), Ok(true));
+        assert_eq!(password.verify("Password"), Ok(false));
+
+        let mut no_iterations = password.clone();
+        no_iterations.iterations = None;
+        assert!(no_iterations.verify("password").is_err());
+    }
+
+    #[test]
+    fn test_verify_bcrypt_and_sha256() {
+        let hash = bcrypt::hash("S3cret!", 4).unwrap();
+        let password = legacy(HashAlgorithm::Bcrypt, &hash, None);
+        assert_eq!(password.verify("S3cret!"), Ok(true));
+        assert_eq!(password.verify("s3cret!"), Ok(false));
+
+        let digest = Sha256::digest(b"saltS3cret!");
+        let password = legacy(
+            HashAlgorithm::Sha256,
+            &BASE64.encode(digest),
+            Some("c2FsdA=="),
+        );
+        assert_eq!(password.verify("S3cret!"), Ok(true));
+        assert_eq!(password.verify("S3cret"), Ok(false));
+    }
+
+    #[test]
+    fn test_verify_rejects_short_hashes() {
+        let truncated = BASE64.encode([0x0c, 0x60, 0xc8, 0x0f]);
+        for algorithm in [HashAlgorithm::Pbkdf2Sha1, HashAlgorithm::Sha256] {
+            for hash in ["", truncated.as_str()] {
+                let password = legacy(algorithm, hash, Some("c2FsdA=="));
+                assert!(
+                    password.verify("password").is_err(),
+                    "{algorithm:?} {hash:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_flag_for_migration() {
+        let mut row: Row = serde_json::from_str(
+            r#"{"displayName": "Jane", "legacyPassword": {"algorithm": "pbkdf2", "hash": "AA==", "salt": "AA==", "iterations": 10}}"#,
+        )
+        .unwrap();
+        let policy = PasswordPolicy::default();
+        assert!(flag_for_migration(
+            &mut row,
+            "0000-1111",
+            "requiresMigration",
+            &policy
+        ));
+        assert_eq!(
+            row["extension_00001111_requiresMigration"],
+            Value::Bool(true)
+        );
+        assert_eq!(
+            row["passwordProfile"]["forceChangePasswordNextSignIn"],
+            Value::Bool(false)
+        );
+
+        let mut row: Row = serde_json::from_str(r#"{"legacyPassword": ""}"#).unwrap();
+        assert!(!flag_for_migration(
+            &mut row,
+            "0000-1111",
+            "requiresMigration",
+            &policy
+        ));
+        assert!(!row.contains_key("passwordProfile"));
+    }
+
+    #[test]
+    fn test_legacy_password_is_not_in_debug_output() {
+        let password = legacy(HashAlgorithm::Sha256, "c2VjcmV0aGFzaA==", Some("c2FsdA=="));
+        assert!(!format!("{password:?}").contains("c2VjcmV0aGFzaA=="));
+    }
+}
@@ -0,0 +1,7 @@
+mod legacy;
+mod server;
+mod store;
+
+pub use crate::migration::legacy::*;
+pub use crate::migration::server::*;
+pub use crate::migration::store::*;
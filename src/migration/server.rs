@@ -0,0 +1,319 @@
+use crate::db::pii;
+use crate::graph::{new_client_request_id, GraphError, TokenCache, CLIENT_REQUEST_ID};
+use crate::migration::HashStore;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::error::Error;
+use std::sync::Arc;
+
+// Message shown to the user by the B2C policy when the validation fails
+const INVALID_CREDENTIALS: &str = "The username or password provided in the request is invalid.";
+
+/// Everything the just-in-time migration API needs to verify a legacy
+/// password and clear the migration flag of the user.
+#[derive(Clone)]
+pub struct JitState {
+    pub store: HashStore,
+    pub client: reqwest::Client,
+    /// Graph users endpoint, e.g. `https://graph.microsoft.com/v1.0/users`
+    pub users_endpoint: String,
+    /// Token of the app clearing the flag, renewed before it expires
+    pub tokens: Arc<TokenCache>,
+    /// Full name of the migration flag, `extension_<appId>_requiresMigration`
+    pub attribute: String,
+    /// Expected value of the `X-Api-Key` header, if any
+    pub api_key: Option<String>,
+}
+
+/// Body sent by the REST API technical profile of the custom policy.
+#[derive(Debug, Deserialize)]
+pub struct ValidateRequest {
+    #[serde(rename = "signInName")]
+    pub sign_in_name: String,
+    pub password: String,
+}
+
+/// Routes of the just-in-time migration API.
+pub fn jit_router(state: JitState) -> Router {
+    Router::new()
+        .route("/health", get(|| async { "ok" }))
+        .route("/validate", post(validate))
+        .with_state(state)
+}
+
+/// Serve the just-in-time migration API on `listen` until the process stops.
+pub async fn run_jit_server(listen: &str, state: JitState) -> Result<(), Box<dyn Error>> {
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    info!(
+        "JIT migration server listening on {}.",
+        listener.local_addr()?
+    );
+    axum::serve(listener, jit_router(state)).await?;
+    Ok(())
+}
+
+// Error response understood by B2C: the userMessage is shown on the sign-in page
+fn conflict(message: &str) -> Response {
+    (
+        StatusCode::CONFLICT,
+        Json(json!({"version": "1.0.0", "status": 409, "userMessage": message})),
+    )
+        .into_response()
+}
+
+async fn validate(
+    State(state): State<JitState>,
+    headers: HeaderMap,
+    Json(request): Json<ValidateRequest>,
+) -> Response {
+    if let Some(api_key) = &state.api_key {
+        let given = headers.get("x-api-key").and_then(|v| v.to_str().ok());
+        if given != Some(api_key.as_str()) {
+            warn!("Rejected a validation request with a missing or wrong API key.");
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    let user = pii(&request.sign_in_name);
+
+    let (object_id, legacy_password) = match state.store.find(&request.sign_in_name) {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            warn!("[{user:?}] No legacy password found for the user.");
+            return conflict(INVALID_CREDENTIALS);
+        }
+        Err(e) => {
+            error!("[{user:?}] Unable to read the hash store: {e}");
+            return conflict(INVALID_CREDENTIALS);
+        }
+    };
+    // Hashing takes up to hundreds of milliseconds, keep it off the runtime threads
+    let password = request.password;
+    let verified = tokio::task::spawn_blocking(move || legacy_password.verify(&password))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    match verified {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("[{user:?}] Legacy password does not match.");
+            return conflict(INVALID_CREDENTIALS);
+        }
+        Err(e) => {
+            error!("[{user:?}] Unable to verify the legacy password: {e}");
+            return conflict(INVALID_CREDENTIALS);
+        }
+    }
+
+    // Clear the migration flag, so the policy stops calling us for this user
+    let token = state.tokens.token().await;
+    let (mut response, mut client_request_id) = clear_flag(&state, &object_id, &token).await;
+    if matches!(&response, Ok(r) if r.status() == StatusCode::UNAUTHORIZED) {
+        // The token may have been revoked or expired early: retry once with a new one
+        match state.tokens.renew(&token).await {
+            Ok(token) => {
+                warn!("[{user:?}] Graph rejected the token, retrying with a renewed one.");
+                (response, client_request_id) = clear_flag(&state, &object_id, &token).await;
+            }
+            Err(e) => error!("[{user:?}] {e}"),
+        }
+    }
+    match response {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => {
+            let status = response.status();
+            let error = GraphError::from_response(response, &client_request_id).await;
+            error!("[{user:?}] Error clearing the migration flag with status: {status}, {error}.");
+            return conflict("Your account could not be migrated, please try again later.");
+        }
+        Err(e) => {
+            error!(
+                "[{user:?}] Error clearing the migration flag: {e:?} (client-request-id {client_request_id})."
+            );
+            return conflict("Your account could not be migrated, please try again later.");
+        }
+    }
+    if let Err(e) = state.store.remove(&object_id) {
+        error!("[{user:?}] Unable to remove the user from the hash store: {e}");
+    }
+    info!("[{user:?}] Legacy password validated, migration flag cleared.");
+    Json(json!({"migrationRequired": false})).into_response()
+}
+
+// Set the migration flag of the user to false, returning the response with
+// the client-request-id sent
+async fn clear_flag(
+    state: &JitState,
+    object_id: &str,
+    token: &str,
+) -> (Result<reqwest::Response, reqwest::Error>, String) {
+    let client_request_id = new_client_request_id();
+    let response = state
+        .client
+        .patch(format!("{}/{object_id}", state.users_endpoint))
+        .header("Authorization", format!("Bearer {token}"))
+        .header(CLIENT_REQUEST_ID, &client_request_id)
+        .header("return-client-request-id", "true")
+        .json(&json!({ state.attribute.as_str(): false }))
+        .send()
+        .await;
+    (response, client_request_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{ClientCredentials, GraphEndpoint};
+    use crate::migration::{HashAlgorithm, LegacyPassword};
+    use crate::testing::TempPath;
+
+    async fn start(state: JitState) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, jit_router(state)).await });
+        format!("http://{address}")
+    }
+
+    fn test_state(graph_url: &str, path: &std::path::Path) -> JitState {
+        let store = HashStore::open(path).unwrap();
+        store.record(
+            &["jane@example.com"],
+            "id-1",
+            &LegacyPassword {
+                algorithm: HashAlgorithm::Bcrypt,
+                hash: bcrypt::hash("S3cret!", 4).unwrap(),
+                salt: None,
+                iterations: None,
+            },
+        );
+        JitState {
+            store,
+            client: reqwest::Client::new(),
+            users_endpoint: format!("{graph_url}/v1.0/users"),
+            tokens: Arc::new(TokenCache::fixed("token")),
+            attribute: "extension_0000_requiresMigration".to_string(),
+            api_key: Some("key".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_clears_migration_flag() {
+        let mut graph = mockito::Server::new_async().await;
+        let patch = graph
+            .mock("PATCH", "/v1.0/users/id-1")
+            .match_body(mockito::Matcher::Json(
+                json!({"extension_0000_requiresMigration": false}),
+            ))
+            .with_status(204)
+            .create_async()
+            .await;
+        let path = TempPath::file("db");
+        let state = test_state(&graph.url(), &path);
+        let store = state.store.clone();
+        let url = start(state).await;
+        let client = reqwest::Client::new();
+        let validate = |password: &'static str, key: &'static str| {
+            client
+                .post(format!("{url}/validate"))
+                .header("X-Api-Key", key)
+                .json(&json!({"signInName": "Jane@example.com", "password": password}))
+                .send()
+        };
+
+        assert_eq!(validate("S3cret!", "wrong").await.unwrap().status(), 401);
+        let response = validate("wrong", "key").await.unwrap();
+        assert_eq!(response.status(), 409);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["userMessage"], INVALID_CREDENTIALS);
+
+        assert_eq!(validate("S3cret!", "key").await.unwrap().status(), 200);
+        patch.assert_async().await;
+        assert!(store.find("jane@example.com").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_validate_keeps_user_when_graph_fails() {
+        let mut graph = mockito::Server::new_async().await;
+        graph
+            .mock("PATCH", "/v1.0/users/id-1")
+            .with_status(500)
+            .create_async()
+            .await;
+        let path = TempPath::file("db");
+        let state = test_state(&graph.url(), &path);
+        let store = state.store.clone();
+        let url = start(state).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{url}/validate"))
+            .header("X-Api-Key", "key")
+            .json(&json!({"signInName": "jane@example.com", "password": "S3cret!"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 409);
+        assert!(store.find("jane@example.com").unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_validate_renews_a_rejected_token() {
+        let mut server = mockito::Server::new_async().await;
+        let first = server
+            .mock("POST", "/contoso/oauth2/v2.0/token")
+            .with_body(r#"{"expires_in": 3599, "access_token": "token-1"}"#)
+            .create_async()
+            .await;
+        let tokens = TokenCache::connect(
+            &reqwest::Client::new(),
+            ClientCredentials {
+                authority: server.url(),
+                tenant_id: "contoso".to_string(),
+                client_id: "client".to_string(),
+                client_secret: "secret".to_string(),
+                graph: GraphEndpoint::new(&server.url(), "v1.0"),
+            },
+        )
+        .await
+        .unwrap();
+        first.remove_async().await;
+        let renewal = server
+            .mock("POST", "/contoso/oauth2/v2.0/token")
+            .with_body(r#"{"expires_in": 3599, "access_token": "token-2"}"#)
+            .create_async()
+            .await;
+        let rejected = server
+            .mock("PATCH", "/v1.0/users/id-1")
+            .match_header("Authorization", "Bearer token-1")
+            .with_status(401)
+            .create_async()
+            .await;
+        let accepted = server
+            .mock("PATCH", "/v1.0/users/id-1")
+            .match_header("Authorization", "Bearer token-2")
+            .with_status(204)
+            .create_async()
+            .await;
+        let path = TempPath::file("db");
+        let mut state = test_state(&server.url(), &path);
+        state.tokens = Arc::new(tokens);
+        let store = state.store.clone();
+        let url = start(state).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{url}/validate"))
+            .header("X-Api-Key", "key")
+            .json(&json!({"signInName": "jane@example.com", "password": "S3cret!"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        renewal.assert_async().await;
+        rejected.assert_async().await;
+        accepted.assert_async().await;
+        assert!(store.find("jane@example.com").unwrap().is_none());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::db::pii;
+use crate::migration::LegacyPassword;
+use log::error;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// SQLite store of the legacy password hashes of the users flagged for
+/// just-in-time migration, written by the migration and read by `jit-server`.
+/// Shared by all tasks.
+///
+/// Each local sign-in name (lowercased) points to the user's object id and hash.
+#[derive(Clone)]
+pub struct HashStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl HashStore {
+    /// Open (or create) the store.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<HashStore, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS legacy_passwords (
+                sign_in_name TEXT PRIMARY KEY,
+                object_id TEXT NOT NULL,
+                password TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(HashStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Store the legacy password of a created user under each of its sign-in names.
+    pub fn record(&self, sign_in_names: &[&str], object_id: &str, password: &LegacyPassword) {
+        let result = serde_json::to_string(password)
+            .map_err(|e| e.to_string())
+            .and_then(|password| {
+                let conn = self.conn.lock().unwrap();
+                sign_in_names.iter().try_for_each(|name| {
+                    conn.execute(
+                        "INSERT OR REPLACE INTO legacy_passwords (sign_in_name, object_id, password) VALUES (?, ?, ?)",
+                        params![name.to_lowercase(), object_id, password],
+                    )
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+                })
+            });
+        if let Err(e) = result {
+            error!(
+                "[{:?}] Unable to write the hash store: {e}",
+                pii(sign_in_names.first().copied().unwrap_or_default())
+            );
+        }
+    }
+
+    /// Look up the object id and legacy password of a sign-in name.
+    pub fn find(
+        &self,
+        sign_in_name: &str,
+    ) -> Result<Option<(String, LegacyPassword)>, Box<dyn Error>> {
+        let found = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT object_id, password FROM legacy_passwords WHERE sign_in_name = ?",
+                params![sign_in_name.to_lowercase()],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+        match found {
+            Some((object_id, password)) => Ok(Some((object_id, serde_json::from_str(&password)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Forget a migrated user, under all its sign-in names.
+    pub fn remove(&self, object_id: &str) -> Result<(), Box<dyn Error>> {
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM legacy_passwords WHERE object_id = ?",
+            params![object_id],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migration::HashAlgorithm;
+    use crate::testing::TempPath;
+
+    #[test]
+    fn test_hash_store_round_trip() {
+        let path = TempPath::file("db");
+        let store = HashStore::open(&path).unwrap();
+        let password = LegacyPassword {
+            algorithm: HashAlgorithm::Bcrypt,
+            hash: "$2b$04$hash".to_string(),
+            salt: None,
+            iterations: None,
+        };
+        store.record(&["Jane@Example.com", "janes"], "id-1", &password);
+
+        let (object_id, found) = store.find("jane@example.com").unwrap().unwrap();
+        assert_eq!(object_id, "id-1");
+        assert_eq!(found, password);
+        assert!(store.find("JANES").unwrap().is_some());
+
+        store.remove("id-1").unwrap();
+        assert!(store.find("janes").unwrap().is_none());
+    }
+}
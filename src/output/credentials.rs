@@ -0,0 +1,114 @@
+use crate::db::pii;
+use crate::output::create_encrypted;
+use age::stream::StreamWriter;
+use log::error;
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Encrypted CSV file with the generated passwords of the created users,
+/// meant for a later welcome flow. Shared by all tasks.
+///
+/// Columns: `legacyId,displayName,issuerAssignedId,objectId,password`.
+#[derive(Clone)]
+pub struct CredentialsFile {
+    writer: Arc<Mutex<Option<csv::Writer<StreamWriter<File>>>>>,
+}
+
+impl CredentialsFile {
+    /// Create (or truncate) the credentials file, encrypted for `recipient`.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        recipient: &age::x25519::Recipient,
+    ) -> Result<CredentialsFile, Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(create_encrypted(path, recipient)?);
+        writer.write_record([
+            "legacyId",
+            "displayName",
+            "issuerAssignedId",
+            "objectId",
+            "password",
+        ])?;
+        Ok(CredentialsFile {
+            writer: Arc::new(Mutex::new(Some(writer))),
+        })
+    }
+
+    /// Append the credentials of a created user.
+    pub fn record(
+        &self,
+        legacy_id: Option<&str>,
+        display_name: &str,
+        issuer_assigned_id: &str,
+        object_id: &str,
+        password: &str,
+    ) {
+        let mut guard = self.writer.lock().unwrap();
+        let Some(writer) = guard.as_mut() else {
+            error!(
+                "[{:?}] The credentials file is already closed.",
+                pii(issuer_assigned_id)
+            );
+            return;
+        };
+        if let Err(e) = writer.write_record([
+            legacy_id.unwrap_or_default(),
+            display_name,
+            issuer_assigned_id,
+            object_id,
+            password,
+        ]) {
+            // The error never contains the record itself, so the password is not logged
+            error!(
+                "[{:?}] Unable to write the credentials file: {e}",
+                pii(issuer_assigned_id)
+            );
+        }
+    }
+
+    /// Flush the remaining records and finish the encryption. Must be called
+    /// at the end of the run, otherwise the file cannot be decrypted.
+    pub fn finish(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+    use std::io::Read;
+
+    #[test]
+    fn test_credentials_file_is_encrypted() {
+        let identity = age::x25519::Identity::generate();
+        let path = TempPath::file("csv.age");
+        let credentials = CredentialsFile::create(&path, &identity.to_public()).unwrap();
+        credentials.record(
+            Some("auth0|1"),
+            "Jane Doe",
+            "jane@example.com",
+            "id-1",
+            "S3cr3t!pw",
+        );
+        credentials.finish().unwrap();
+
+        let encrypted = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&encrypted).contains("S3cr3t!pw"));
+
+        let decryptor = age::Decryptor::new(&encrypted[..]).unwrap();
+        let mut reader = decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .unwrap();
+        let mut plaintext = String::new();
+        reader.read_to_string(&mut plaintext).unwrap();
+        assert_eq!(
+            plaintext,
+            "legacyId,displayName,issuerAssignedId,objectId,password\nauth0|1,Jane Doe,jane@example.com,id-1,S3cr3t!pw\n"
+        );
+    }
+}
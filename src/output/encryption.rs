@@ -0,0 +1,68 @@
+use age::stream::StreamWriter;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Parse an age recipient (X25519 public key, `age1...`).
+pub fn parse_recipient(recipient: &str) -> Result<age::x25519::Recipient, Box<dyn Error>> {
+    recipient
+        .trim()
+        .parse::<age::x25519::Recipient>()
+        .map_err(|e| format!("Invalid age recipient: {e}").into())
+}
+
+/// Create `path` and return a writer encrypting everything written to it for `recipient`.
+///
+/// `StreamWriter::finish` must be called once done, otherwise the file is truncated.
+pub fn create_encrypted<P: AsRef<Path>>(
+    path: P,
+    recipient: &age::x25519::Recipient,
+) -> Result<StreamWriter<File>, Box<dyn Error>> {
+    let encryptor =
+        age::Encryptor::with_recipients(std::iter::once(recipient as &dyn age::Recipient))?;
+    Ok(encryptor.wrap_output(File::create(path)?)?)
+}
+
+/// An output file, encrypted when a recipient is configured.
+pub enum OutputFile {
+    Plain(File),
+    Encrypted(StreamWriter<File>),
+}
+
+impl OutputFile {
+    /// Create (or truncate) `path`, encrypted for `recipient` if any.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        recipient: Option<&age::x25519::Recipient>,
+    ) -> Result<OutputFile, Box<dyn Error>> {
+        Ok(match recipient {
+            Some(recipient) => OutputFile::Encrypted(create_encrypted(path, recipient)?),
+            None => OutputFile::Plain(File::create(path)?),
+        })
+    }
+
+    /// Flush the file and, when encrypted, write the final chunk.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(mut file) => file.flush(),
+            OutputFile::Encrypted(writer) => writer.finish().map(|_| ()),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Plain(file) => file.write(buf),
+            OutputFile::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(file) => file.flush(),
+            OutputFile::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::db::pii;
+use crate::output::OutputFile;
+use log::error;
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// CSV file mapping each migrated user to its new B2C object id, shared by all tasks.
+///
+/// Columns: `legacyId,issuerAssignedId,objectId`.
+#[derive(Clone)]
+pub struct MappingFile {
+    writer: Arc<Mutex<Option<csv::Writer<OutputFile>>>>,
+}
+
+impl MappingFile {
+    /// Create (or truncate) the mapping file, encrypted for `recipient` if
+    /// any, and write its header.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        recipient: Option<&age::x25519::Recipient>,
+    ) -> Result<MappingFile, Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(OutputFile::create(path, recipient)?);
+        writer.write_record(["legacyId", "issuerAssignedId", "objectId"])?;
+        writer.flush()?;
+        Ok(MappingFile {
+            writer: Arc::new(Mutex::new(Some(writer))),
+        })
+    }
+
+    /// Append a migrated user. Lines are flushed right away, so a plain file
+    /// is complete even if the run is interrupted (an encrypted one is only
+    /// readable after `finish`).
+    pub fn record(&self, legacy_id: Option<&str>, issuer_assigned_id: &str, object_id: &str) {
+        let mut guard = self.writer.lock().unwrap();
+        let Some(writer) = guard.as_mut() else {
+            error!(
+                "[{:?}] The mapping file is already closed.",
+                pii(issuer_assigned_id)
+            );
+            return;
+        };
+        let result = writer
+            .write_record([legacy_id.unwrap_or_default(), issuer_assigned_id, object_id])
+            .and_then(|_| writer.flush().map_err(csv::Error::from));
+        if let Err(e) = result {
+            error!(
+                "[{:?}] Unable to write the mapping file: {e:?}",
+                pii(issuer_assigned_id)
+            );
+        }
+    }
+
+    /// Close the file, finishing the encryption if any. Must be called at
+    /// the end of the run.
+    pub fn finish(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+    use std::io::Read;
+
+    #[test]
+    fn test_mapping_file_records() {
+        let path = TempPath::file("csv");
+        let mapping = MappingFile::create(&path, None).unwrap();
+        mapping
+            .clone()
+            .record(Some("auth0|1"), "jane@example.com", "id-1");
+        mapping.record(None, "jdoe", "id-2");
+
+        // Plain lines are readable before the file is closed
+        let contents = std::fs::read_to_string(&path).unwrap();
+        mapping.finish().unwrap();
+        assert_eq!(
+            contents,
+            "legacyId,issuerAssignedId,objectId\nauth0|1,jane@example.com,id-1\n,jdoe,id-2\n"
+        );
+    }
+
+    #[test]
+    fn test_encrypted_mapping_file() {
+        let identity = age::x25519::Identity::generate();
+        let path = TempPath::file("csv.age");
+        let mapping = MappingFile::create(&path, Some(&identity.to_public())).unwrap();
+        mapping.record(Some("auth0|1"), "jane@example.com", "id-1");
+        mapping.finish().unwrap();
+
+        let encrypted = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&encrypted).contains("jane@example.com"));
+        let mut plaintext = String::new();
+        age::Decryptor::new(&encrypted[..])
+            .unwrap()
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .unwrap()
+            .read_to_string(&mut plaintext)
+            .unwrap();
+        assert!(plaintext.ends_with("auth0|1,jane@example.com,id-1\n"));
+    }
+}
@@ -0,0 +1,9 @@
+mod credentials;
+mod encryption;
+mod mapping;
+mod shutdown;
+
+pub use crate::output::credentials::*;
+pub use crate::output::encryption::*;
+pub use crate::output::mapping::*;
+pub use crate::output::shutdown::*;
@@ -0,0 +1,56 @@
+use log::error;
+use std::error::Error;
+use std::sync::Mutex;
+
+/// An output of the run that must be finished before the process exits,
+/// e.g. to write the final chunk of an encrypted file.
+pub trait Finish: Send {
+    fn finish(&self) -> Result<(), Box<dyn Error>>;
+}
+
+impl Finish for crate::output::MappingFile {
+    fn finish(&self) -> Result<(), Box<dyn Error>> {
+        crate::output::MappingFile::finish(self)
+    }
+}
+
+impl Finish for crate::output::CredentialsFile {
+    fn finish(&self) -> Result<(), Box<dyn Error>> {
+        crate::output::CredentialsFile::finish(self)
+    }
+}
+
+static OUTPUTS: Mutex<Vec<Box<dyn Finish>>> = Mutex::new(Vec::new());
+
+/// Register an output to finish on every way out of the run: the end of the
+/// input, an early error or an exit of the process.
+pub fn finish_at_exit(output: impl Finish + 'static) {
+    OUTPUTS.lock().unwrap().push(Box::new(output));
+}
+
+/// Finish the registered outputs, each once. All are tried, the first error
+/// is returned.
+pub fn finish_outputs() -> Result<(), Box<dyn Error>> {
+    let outputs = std::mem::take(&mut *OUTPUTS.lock().unwrap());
+    let mut result = Ok(());
+    for output in outputs {
+        if let Err(e) = output.finish() {
+            error!("Unable to finish an output file: {e}");
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+/// Finishes the registered outputs when dropped, so that an early return
+/// with an error still leaves them readable.
+pub struct OutputsGuard;
+
+impl Drop for OutputsGuard {
+    fn drop(&mut self) {
+        // Errors are already logged
+        let _ = finish_outputs();
+    }
+}
@@ -0,0 +1,46 @@
+use crate::dashboard::RunStats;
+use crate::db::OutcomeLog;
+use crate::graph::{AdaptiveLimiter, AppPool, RateLimits};
+use crate::metrics::Metrics;
+use crate::source::DecryptionKeys;
+use crate::telemetry::Tracer;
+use std::error::Error;
+use std::sync::OnceLock;
+
+/// State of a migration run shared by all its tasks, installed once by
+/// `main` before the first row is read.
+///
+/// It is the one global of a run, so that every Graph call, log line and
+/// input read can reach it without threading it through each call. Before
+/// it is installed (and in unit tests, which never install it) every part
+/// is absent: no limits, no metrics, no traces and no outcome log. The only
+/// other globals are the redactor, needed by the logger, and the outputs
+/// finished when the process exits.
+#[derive(Default)]
+pub struct RunContext {
+    /// Keys of age-encrypted input files
+    pub decryption: DecryptionKeys,
+    pub limiter: Option<AdaptiveLimiter>,
+    /// Rate limits of a run without an app pool, each app has its own
+    pub rate_limits: Option<RateLimits>,
+    pub app_pool: Option<AppPool>,
+    pub metrics: Option<Metrics>,
+    pub tracer: Option<Tracer>,
+    /// Statistics of the dashboard and the control endpoint
+    pub stats: Option<RunStats>,
+    pub outcome_log: Option<OutcomeLog>,
+}
+
+static RUN_CONTEXT: OnceLock<RunContext> = OnceLock::new();
+
+/// Install the context of the run. It can only be installed once.
+pub fn init_run_context(context: RunContext) -> Result<(), Box<dyn Error>> {
+    RUN_CONTEXT
+        .set(context)
+        .map_err(|_| "The run context is already installed".into())
+}
+
+/// Context of the run, once installed.
+pub fn run_context() -> Option<&'static RunContext> {
+    RUN_CONTEXT.get()
+}
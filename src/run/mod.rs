@@ -0,0 +1,3 @@
+mod context;
+
+pub use crate::run::context::*;
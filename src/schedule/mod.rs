@@ -0,0 +1,5 @@
+mod watcher;
+mod window;
+
+pub use crate::schedule::watcher::*;
+pub use crate::schedule::window::*;
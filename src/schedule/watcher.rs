@@ -0,0 +1,62 @@
+use crate::graph::AdaptiveLimiter;
+use crate::schedule::{Schedule, WindowClose};
+use chrono::{DateTime, Local, Utc};
+use log::{info, warn};
+use std::time::Duration;
+
+/// Longest sleep between two checks of the schedule, so that a suspended
+/// machine or a clock change is caught up with quickly.
+const RECHECK: Duration = Duration::from_secs(30);
+
+/// Time of a change of the schedule, as logged.
+pub fn format_change(at: Option<DateTime<Utc>>) -> String {
+    match at {
+        Some(at) => at
+            .with_timezone(&Local)
+            .format("%a %Y-%m-%d %H:%M %:z")
+            .to_string(),
+        None => "never".to_string(),
+    }
+}
+
+/// Keep the dispatch of `limiter` to the windows of `schedule`, until the
+/// run stops: outside them, new users wait for the next window to open, or
+/// the run stops once the running users finish.
+pub async fn keep_to_schedule(
+    schedule: Schedule,
+    close: WindowClose,
+    limiter: &'static AdaptiveLimiter,
+) {
+    let mut was_open = None;
+    while !limiter.is_closed() {
+        let now = Utc::now();
+        let open = schedule.is_open(now);
+        let next = schedule.next_change(now);
+        if was_open != Some(open) {
+            match (open, close) {
+                (true, _) => match next {
+                    Some(_) => info!("Migration window open until {}.", format_change(next)),
+                    None => info!("Migration window open, it never closes."),
+                },
+                (false, WindowClose::Sleep) => warn!(
+                    "Outside the migration window: no new users are dispatched until {}, running users finish.",
+                    format_change(next)
+                ),
+                (false, WindowClose::Exit) => {
+                    warn!(
+                        "Migration window closed: the run stops once running users finish, the next window opens at {}.",
+                        format_change(next)
+                    );
+                    limiter.close();
+                    return;
+                }
+            }
+            limiter.set_outside_window(!open);
+            was_open = Some(open);
+        }
+        let wait = next
+            .and_then(|next| (next - now).to_std().ok())
+            .map_or(RECHECK, |wait| wait.min(RECHECK));
+        tokio::time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,359 @@
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+    Timelike, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use std::fmt;
+use std::str::FromStr;
+
+const DAY_SECONDS: u32 = 24 * 3600;
+
+/// Time zone of a window: a tz database name, or the zone of the machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    Local,
+    Named(Tz),
+}
+
+impl FromStr for Zone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(Zone::Local);
+        }
+        s.parse::<Tz>().map(Zone::Named).map_err(|_| {
+            format!("Unknown time zone {s}: expected a name like Europe/Rome, UTC or local")
+        })
+    }
+}
+
+impl Zone {
+    fn local_time(&self, at: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Local => at.with_timezone(&chrono::Local).naive_local(),
+            Zone::Named(tz) => at.with_timezone(tz).naive_local(),
+        }
+    }
+
+    /// Instant of a local time. A time skipped by a clock change happens
+    /// an hour later, a repeated one the first time.
+    fn instant(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        let resolve = |local: NaiveDateTime| match self {
+            Zone::Local => first(chrono::Local.from_local_datetime(&local)),
+            Zone::Named(tz) => first(tz.from_local_datetime(&local)),
+        };
+        resolve(local).or_else(|| resolve(local + Duration::hours(1)))
+    }
+}
+
+fn first<T: TimeZone>(result: LocalResult<DateTime<T>>) -> Option<DateTime<Utc>> {
+    result.earliest().map(|at| at.with_timezone(&Utc))
+}
+
+/// Days and times at which writing to the tenant is allowed, in the
+/// `[DAYS] HH:MM-HH:MM[,HH:MM-HH:MM...] [ZONE]` form, e.g.
+/// `Mon-Fri 22:00-06:00 Europe/Rome`.
+///
+/// A range ending before it starts goes on past midnight, and belongs to
+/// the day it starts. Without days, every day; without zone, the zone of
+/// the machine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    spec: String,
+    /// Days by `Weekday::num_days_from_monday`
+    days: [bool; 7],
+    /// Seconds since midnight, the end being past the start
+    ranges: Vec<(u32, u32)>,
+    zone: Zone,
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| format!("Invalid window \"{s}\": {reason}");
+        let mut tokens: Vec<&str> = s.split_whitespace().collect();
+        if tokens.is_empty() {
+            return Err(invalid("no time range".to_string()));
+        }
+        let days = match parse_days(tokens[0]) {
+            Some(days) => {
+                tokens.remove(0);
+                days?
+            }
+            None => [true; 7],
+        };
+        let zone = match tokens.last() {
+            Some(last) if !last.contains(':') => {
+                let zone = last.parse::<Zone>().map_err(invalid)?;
+                tokens.pop();
+                zone
+            }
+            _ => Zone::Local,
+        };
+        if tokens.is_empty() {
+            return Err(invalid("no time range".to_string()));
+        }
+        let ranges = tokens
+            .join(",")
+            .split(',')
+            .filter(|range| !range.is_empty())
+            .map(parse_range)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid)?;
+        Ok(Window {
+            spec: s.trim().to_string(),
+            days,
+            ranges,
+            zone,
+        })
+    }
+}
+
+/// Days of the first token, `None` when it is not a list of days:
+/// `Mon-Fri`, `Sat,Sun`, `Mon,Wed-Fri` or `daily`.
+fn parse_days(token: &str) -> Option<Result<[bool; 7], String>> {
+    if token.eq_ignore_ascii_case("daily") {
+        return Some(Ok([true; 7]));
+    }
+    if token.contains(':') {
+        return None;
+    }
+    let day = |name: &str| {
+        name.parse::<Weekday>()
+            .map(|day| day.num_days_from_monday() as usize)
+            .map_err(|_| format!("unknown day {name}"))
+    };
+    let mut days = [false; 7];
+    for part in token.split(',') {
+        let (from, to) = match part.split_once('-') {
+            Some((from, to)) => (day(from), day(to)),
+            None => (day(part), day(part)),
+        };
+        let (from, to) = match (from, to) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(e), _) | (_, Err(e)) => return Some(Err(e)),
+        };
+        // Fri-Mon goes on through the weekend
+        let mut current = from;
+        loop {
+            days[current] = true;
+            if current == to {
+                break;
+            }
+            current = (current + 1) % 7;
+        }
+    }
+    Some(Ok(days))
+}
+
+/// `HH:MM-HH:MM` as seconds since midnight, `24:00` being allowed as end.
+fn parse_range(range: &str) -> Result<(u32, u32), String> {
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| format!("expected HH:MM-HH:MM instead of {range}"))?;
+    let start = parse_time(start)?;
+    let end = match end {
+        "24:00" => DAY_SECONDS,
+        end => parse_time(end)?,
+    };
+    match end {
+        _ if end == start => Err(format!("empty time range {range}")),
+        _ if end < start => Ok((start, end + DAY_SECONDS)),
+        _ => Ok((start, end)),
+    }
+}
+
+fn parse_time(time: &str) -> Result<u32, String> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map(|time| time.num_seconds_from_midnight())
+        .map_err(|_| format!("invalid time {time}, expected HH:MM"))
+}
+
+impl Window {
+    /// Whether the window is open at `at`.
+    pub fn is_open(&self, at: DateTime<Utc>) -> bool {
+        let local = self.zone.local_time(at);
+        let seconds = local.num_seconds_from_midnight();
+        // Ranges of the day, and ranges of the day before going on past midnight
+        [
+            (local.date(), seconds),
+            (day_before(local.date()), seconds + DAY_SECONDS),
+        ]
+        .iter()
+        .any(|(date, seconds)| {
+            self.days[date.weekday().num_days_from_monday() as usize]
+                && self
+                    .ranges
+                    .iter()
+                    .any(|(start, end)| (*start..*end).contains(seconds))
+        })
+    }
+
+    /// Openings and closings of the window around `at`, in no order.
+    fn edges(&self, at: DateTime<Utc>) -> impl Iterator<Item = DateTime<Utc>> + '_ {
+        let today = self.zone.local_time(at).date();
+        (-1..=8)
+            .filter_map(move |offset| today.checked_add_signed(Duration::days(offset)))
+            .flat_map(move |date| {
+                self.ranges.iter().flat_map(move |(start, end)| {
+                    [*start, *end].into_iter().filter_map(move |seconds| {
+                        let local =
+                            date.and_hms_opt(0, 0, 0)? + Duration::seconds(i64::from(seconds));
+                        self.zone.instant(local)
+                    })
+                })
+            })
+    }
+}
+
+fn day_before(date: NaiveDate) -> NaiveDate {
+    date.pred_opt().unwrap_or(date)
+}
+
+/// Migration windows of a run: writing is allowed while any of them is
+/// open.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub windows: Vec<Window>,
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let windows: Vec<String> = self.windows.iter().map(|w| w.to_string()).collect();
+        f.write_str(&windows.join(" | "))
+    }
+}
+
+impl Schedule {
+    pub fn new(windows: Vec<Window>) -> Schedule {
+        Schedule { windows }
+    }
+
+    /// Whether writing is allowed at `at`.
+    pub fn is_open(&self, at: DateTime<Utc>) -> bool {
+        self.windows.iter().any(|window| window.is_open(at))
+    }
+
+    /// Next time the schedule opens or closes after `at`, within a week.
+    /// `None` when it never changes, e.g. `00:00-24:00` every day.
+    pub fn next_change(&self, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let open = self.is_open(at);
+        let mut edges: Vec<DateTime<Utc>> = self
+            .windows
+            .iter()
+            .flat_map(|window| window.edges(at))
+            .filter(|edge| *edge > at)
+            .collect();
+        edges.sort();
+        edges.into_iter().find(|edge| self.is_open(*edge) != open)
+    }
+}
+
+/// What a run does when its window closes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WindowClose {
+    /// Hold the dispatch until the next window opens
+    #[default]
+    Sleep,
+    /// Stop once the running users finish, for a later run to resume
+    Exit,
+}
+
+impl FromStr for WindowClose {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "sleep" => Ok(WindowClose::Sleep),
+            "exit" => Ok(WindowClose::Exit),
+            other => Err(format!(
+                "Unknown window close action {other}: expected sleep or exit"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_parse_windows() {
+        let window: Window = "Mon-Fri 22:00-06:00 Europe/Rome".parse().unwrap();
+        assert_eq!(window.days, [true, true, true, true, true, false, false]);
+        assert_eq!(window.ranges, vec![(22 * 3600, 30 * 3600)]);
+        assert_eq!(window.zone, Zone::Named(chrono_tz::Europe::Rome));
+
+        let window: Window = "Fri-Mon 12:00-13:00,20:00-24:00 UTC".parse().unwrap();
+        assert_eq!(window.days, [true, false, false, false, true, true, true]);
+        assert_eq!(window.ranges.len(), 2);
+        let window: Window = "22:00-06:00".parse().unwrap();
+        assert_eq!((window.days, window.zone), ([true; 7], Zone::Local));
+
+        for invalid in [
+            "",
+            "Mon-Fri",
+            "Mon-Fri 22:00 UTC",
+            "Someday 22:00-06:00",
+            "22:00-22:00",
+            "25:00-06:00",
+            "22:00-06:00 Mars/Olympus",
+        ] {
+            assert!(invalid.parse::<Window>().is_err(), "{invalid} parsed");
+        }
+    }
+
+    #[test]
+    fn test_window_past_midnight_belongs_to_its_first_day() {
+        let schedule = Schedule::new(vec!["Mon-Fri 22:00-06:00 UTC".parse().unwrap()]);
+        // Friday night to Saturday morning is open, Saturday night is not
+        assert!(schedule.is_open(utc("2024-06-07T23:00:00Z")));
+        assert!(schedule.is_open(utc("2024-06-08T05:59:59Z")));
+        assert!(!schedule.is_open(utc("2024-06-08T06:00:00Z")));
+        assert!(!schedule.is_open(utc("2024-06-08T23:00:00Z")));
+        // Monday morning belongs to Sunday night
+        assert!(!schedule.is_open(utc("2024-06-10T01:00:00Z")));
+
+        assert_eq!(
+            schedule.next_change(utc("2024-06-08T05:00:00Z")),
+            Some(utc("2024-06-08T06:00:00Z"))
+        );
+        assert_eq!(
+            schedule.next_change(utc("2024-06-08T06:00:00Z")),
+            Some(utc("2024-06-10T22:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_schedule_in_a_time_zone() {
+        let schedule = Schedule::new(vec![
+            "Mon-Fri 22:00-06:00 Europe/Rome".parse().unwrap(),
+            "Sat,Sun 00:00-24:00 Europe/Rome".parse().unwrap(),
+        ]);
+        // 22:00 in Rome is 20:00 UTC in summer
+        assert!(!schedule.is_open(utc("2024-06-05T19:59:00Z")));
+        assert!(schedule.is_open(utc("2024-06-05T20:00:00Z")));
+        // Friday night goes on through the weekend, until Monday 00:00: the
+        // night of Sunday is not a night of Mon-Fri
+        assert_eq!(
+            schedule.next_change(utc("2024-06-07T21:00:00Z")),
+            Some(utc("2024-06-09T22:00:00Z"))
+        );
+        let always = Schedule::new(vec!["daily 00:00-24:00 UTC".parse().unwrap()]);
+        assert!(always.is_open(utc("2024-06-05T12:00:00Z")));
+        assert_eq!(always.next_change(utc("2024-06-05T12:00:00Z")), None);
+        assert_eq!("EXIT".parse::<WindowClose>(), Ok(WindowClose::Exit));
+    }
+}
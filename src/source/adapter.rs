@@ -0,0 +1,159 @@
+use crate::graph::{extension_attribute, Row};
+use crate::source::{
+    auth0_to_row, cognito_to_row, count_rows, keycloak_to_row, read_cognito_rows,
+    read_keycloak_rows, read_rows, InputFormat, RowIter,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::{fs, path::Path};
+
+/// Mirrors the keys that appear in the source adapter TOML configuration file.
+///
+/// Example file:
+/// ```toml
+/// issuer           = "contoso.onmicrosoft.com"
+/// extension_app_id = "00000000-0000-0000-0000-000000000000" # b2c-extensions-app client id
+///
+/// [attributes] # source field -> extension attribute name
+/// "user_metadata.loyalty_id" = "loyaltyId"
+/// "app_metadata.plan"        = "plan"
+/// ```
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdapterConfig {
+    pub issuer: String,
+    pub extension_app_id: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+/// Load and parse a source adapter configuration file.
+///
+/// # Errors
+/// * I/O failures while reading the file
+/// * TOML-syntax or type mismatches while parsing
+/// * `attributes` configured without an `extension_app_id`
+pub fn adapter_load_config<P: AsRef<Path>>(path: P) -> Result<AdapterConfig, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let config = toml::from_str::<AdapterConfig>(&contents)?;
+    if !config.attributes.is_empty() && config.extension_app_id.is_none() {
+        return Err("extension_app_id is required to map attributes".into());
+    }
+    Ok(config)
+}
+
+impl AdapterConfig {
+    /// Full name of an extension attribute, e.g. `extension_<appId>_loyaltyId`.
+    pub fn extension_attribute(&self, name: &str) -> String {
+        extension_attribute(self.extension_app_id.as_deref().unwrap_or_default(), name)
+    }
+
+    /// Copy the configured source fields of `record` into extension attributes of `row`.
+    /// Fields are looked up by their dotted path; missing or null fields are ignored.
+    pub fn map_attributes(&self, record: &Value, row: &mut Row) {
+        for (path, name) in &self.attributes {
+            let value = path
+                .split('.')
+                .try_fold(record, |v, key| v.get(key))
+                .filter(|v| !v.is_null());
+            if let Some(value) = value {
+                // Extension attributes only hold scalars
+                let value = match value {
+                    Value::Array(_) | Value::Object(_) => Value::String(value.to_string()),
+                    v => v.clone(),
+                };
+                row.insert(self.extension_attribute(name), value);
+            }
+        }
+    }
+
+    /// Extension attribute name for a custom attribute of the source, when an
+    /// `extension_app_id` is configured.
+    pub fn custom_attribute(&self, name: &str) -> Option<String> {
+        self.extension_app_id
+            .as_ref()
+            .map(|_| self.extension_attribute(name))
+    }
+
+    /// Identity object using the configured issuer.
+    pub fn identity(&self, sign_in_type: &str, issuer_assigned_id: &str) -> Value {
+        json!({
+            "signInType": sign_in_type,
+            "issuer": self.issuer,
+            "issuerAssignedId": issuer_assigned_id,
+        })
+    }
+}
+
+/// Join given and family name, when at least one of them is known.
+pub fn full_name(given_name: Option<&str>, family_name: Option<&str>) -> Option<String> {
+    match (given_name, family_name) {
+        (Some(g), Some(f)) => Some(format!("{g} {f}")),
+        (Some(n), None) | (None, Some(n)) => Some(n.to_string()),
+        (None, None) => None,
+    }
+}
+
+/// Non-empty, trimmed string value of a top-level field.
+pub fn text_field<'a>(record: &'a Value, key: &str) -> Option<&'a str> {
+    record
+        .get(key)
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+}
+
+/// Built-in adapters turning exports of other identity providers into rows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Adapter {
+    /// Auth0 bulk user export (NDJSON)
+    Auth0,
+    /// AWS Cognito `ListUsers` JSON output or CSV export
+    Cognito,
+    /// Keycloak realm or users export (JSON)
+    Keycloak,
+}
+
+impl FromStr for Adapter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auth0" => Ok(Adapter::Auth0),
+            "cognito" => Ok(Adapter::Cognito),
+            "keycloak" => Ok(Adapter::Keycloak),
+            other => Err(format!("Unknown source adapter: {other}")),
+        }
+    }
+}
+
+impl Adapter {
+    /// Count the records of the export file.
+    pub fn count_rows<P: AsRef<Path>>(&self, path: P) -> Result<u64, Box<dyn Error>> {
+        match self {
+            // Auth0 exports are NDJSON, even when saved with a .json extension
+            Adapter::Auth0 => count_rows(path, InputFormat::Jsonl),
+            Adapter::Cognito | Adapter::Keycloak => Ok(self.read_rows(path)?.count() as u64),
+        }
+    }
+
+    /// Open the export file and return an iterator over its raw records.
+    pub fn read_rows<P: AsRef<Path>>(&self, path: P) -> Result<RowIter, Box<dyn Error>> {
+        match self {
+            Adapter::Auth0 => read_rows(path, InputFormat::Jsonl, &[]),
+            Adapter::Cognito => read_cognito_rows(path),
+            Adapter::Keycloak => read_keycloak_rows(path),
+        }
+    }
+
+    /// Convert a raw record of the export into a row for `RequestBody`.
+    pub fn convert(&self, cfg: &AdapterConfig, record: Row) -> Result<Row, String> {
+        match self {
+            Adapter::Auth0 => auth0_to_row(cfg, record),
+            Adapter::Cognito => cognito_to_row(cfg, record),
+            Adapter::Keycloak => keycloak_to_row(cfg, record),
+        }
+    }
+}
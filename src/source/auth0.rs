@@ -0,0 +1,188 @@
+use crate::graph::Row;
+use crate::source::{full_name, text_field, AdapterConfig};
+use serde_json::{json, Value};
+
+// Issuer of the federated identity for the Auth0 social connections
+fn social_issuer(provider: &str) -> &str {
+    match provider {
+        "google-oauth2" => "google.com",
+        "facebook" => "facebook.com",
+        "windowslive" => "live.com",
+        "apple" => "appleid.apple.com",
+        "twitter" => "twitter.com",
+        "linkedin" => "linkedin.com",
+        "github" => "github.com",
+        other => other,
+    }
+}
+
+/// Convert a record of an Auth0 user export into a row.
+///
+/// * `email` and `username` become `emailAddress`/`userName` identities with our issuer
+/// * social `identities` become `federated` identities
+/// * `phone_number` becomes the `phoneAuthMethod`
+/// * `blocked` users are created with `accountEnabled` set to false
+/// * the configured metadata fields become extension attributes
+/// * `user_id` is kept as the legacy id
+///
+/// Auth0 password hashes cannot be imported, so no `passwordProfile` is set
+/// and a temporary password is generated for the row.
+pub fn auth0_to_row(cfg: &AdapterConfig, record: Row) -> Result<Row, String> {
+    let record = Value::Object(record);
+    let user_id = text_field(&record, "user_id").unwrap_or("<unknown>");
+
+    let mut identities = Vec::new();
+    if let Some(email) = text_field(&record, "email") {
+        identities.push(cfg.identity("emailAddress", email));
+    }
+    if let Some(username) = text_field(&record, "username") {
+        identities.push(cfg.identity("userName", username));
+    }
+    for identity in record
+        .get("identities")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|i| i.get("isSocial").and_then(Value::as_bool) == Some(true))
+    {
+        let provider = identity.get("provider").and_then(Value::as_str);
+        let id = identity.get("user_id").map(|v| match v {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+        });
+        if let (Some(provider), Some(id)) = (provider, id) {
+            identities.push(json!({
+                "signInType": "federated",
+                "issuer": social_issuer(provider),
+                "issuerAssignedId": id,
+            }));
+        }
+    }
+    if identities.is_empty() {
+        return Err(format!(
+            "Auth0 user {user_id} has no email, username or social identity"
+        ));
+    }
+
+    let given_name = text_field(&record, "given_name");
+    let family_name = text_field(&record, "family_name");
+    let display_name = text_field(&record, "name")
+        .map(str::to_string)
+        .or_else(|| full_name(given_name, family_name))
+        .or_else(|| text_field(&record, "nickname").map(str::to_string))
+        .or_else(|| text_field(&record, "email").map(str::to_string))
+        .unwrap_or_else(|| user_id.to_string());
+
+    let mut row = Row::new();
+    row.insert("legacyId".into(), user_id.into());
+    row.insert("displayName".into(), display_name.into());
+    row.insert("identities".into(), identities.into());
+    if let Some(given_name) = given_name {
+        row.insert("givenName".into(), given_name.into());
+    }
+    if let Some(family_name) = family_name {
+        row.insert("surname".into(), family_name.into());
+    }
+    if let Some(phone) = text_field(&record, "phone_number") {
+        row.insert("phoneAuthMethod".into(), phone.into());
+    }
+    if let Some(blocked) = record.get("blocked").and_then(Value::as_bool) {
+        row.insert("accountEnabled".into(), (!blocked).into());
+    }
+    cfg.map_attributes(&record, &mut row);
+    Ok(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{PasswordPolicy, RequestBody};
+
+    // Fill in the generated password, as done for every adapter row
+    fn to_body(mut row: Row) -> RequestBody {
+        assert!(!row.contains_key("passwordProfile"));
+        row.insert(
+            "passwordProfile".into(),
+            PasswordPolicy::default().password_profile(),
+        );
+        RequestBody::from_row(row).unwrap()
+    }
+
+    fn test_config() -> AdapterConfig {
+        toml::from_str(
+            r#"
+            issuer = "contoso.onmicrosoft.com"
+            extension_app_id = "0a1b2c3d-0000-0000-0000-000000000000"
+            [attributes]
+            "user_metadata.loyalty_id" = "loyaltyId"
+            "app_metadata.roles" = "roles"
+            "app_metadata.missing" = "missing"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn record(json: &str) -> Row {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_auth0_database_user() {
+        let row = auth0_to_row(
+            &test_config(),
+            record(
+                r#"{"user_id": "auth0|123", "email": "jane@example.com", "email_verified": true,
+                    "given_name": "Jane", "family_name": "Doe", "phone_number": "+15555551234",
+                    "identities": [{"provider": "auth0", "user_id": "123", "connection": "Username-Password-Authentication", "isSocial": false}],
+                    "user_metadata": {"loyalty_id": 42}, "app_metadata": {"roles": ["admin"]}}"#,
+            ),
+        )
+        .unwrap();
+        let body = to_body(row);
+        assert_eq!(body.displayName, "Jane Doe");
+        assert_eq!(body.legacyId.as_deref(), Some("auth0|123"));
+        assert_eq!(body.identities.len(), 1);
+        assert_eq!(body.identities[0].signInType, "emailAddress");
+        assert_eq!(body.identities[0].issuer, "contoso.onmicrosoft.com");
+        assert_eq!(body.identities[0].issuerAssignedId, "jane@example.com");
+        assert_eq!(body.phoneAuthMethod.as_deref(), Some("+15555551234"));
+        assert!(body.passwordProfile.forceChangePasswordNextSignIn);
+        assert_eq!(body.passwordProfile.password.len(), 16);
+        assert_eq!(body.custom_fields["givenName"], "Jane");
+        assert_eq!(
+            body.custom_fields["extension_0a1b2c3d000000000000000000000000_loyaltyId"],
+            42
+        );
+        assert_eq!(
+            body.custom_fields["extension_0a1b2c3d000000000000000000000000_roles"],
+            r#"["admin"]"#
+        );
+        assert!(!body
+            .custom_fields
+            .contains_key("extension_0a1b2c3d000000000000000000000000_missing"));
+    }
+
+    #[test]
+    fn test_auth0_social_blocked_user() {
+        let row = auth0_to_row(
+            &test_config(),
+            record(
+                r#"{"user_id": "google-oauth2|987", "nickname": "jd", "blocked": true,
+                    "identities": [{"provider": "google-oauth2", "user_id": "987", "isSocial": true}]}"#,
+            ),
+        )
+        .unwrap();
+        let body = to_body(row);
+        assert_eq!(body.displayName, "jd");
+        assert_eq!(body.identities[0].signInType, "federated");
+        assert_eq!(body.identities[0].issuer, "google.com");
+        assert_eq!(body.identities[0].issuerAssignedId, "987");
+        assert_eq!(body.custom_fields["accountEnabled"], false);
+    }
+
+    #[test]
+    fn test_auth0_user_without_identity() {
+        let result = auth0_to_row(&test_config(), record(r#"{"user_id": "auth0|1"}"#));
+        assert!(result.unwrap_err().contains("auth0|1"));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::graph::Row;
+use crate::source::{
+    full_name, input_extension, open_input, read_csv_text_rows, text_field, AdapterConfig, RowIter,
+};
+use serde_json::Value;
+use std::error::Error;
+use std::io::BufReader;
+use std::path::Path;
+
+// Prefix of the Cognito custom attributes
+const CUSTOM_PREFIX: &str = "custom:";
+
+/// Open a Cognito export. CSV files (detected by extension) are read with one
+/// column per attribute; JSON files hold the `ListUsers` output, either as the
+/// `{"Users": [...]}` response or as a plain array of users.
+pub fn read_cognito_rows<P: AsRef<Path>>(path: P) -> Result<RowIter, Box<dyn Error>> {
+    if input_extension(&path).as_deref() == Some("csv") {
+        return read_csv_text_rows(path);
+    }
+
+    let users = match serde_json::from_reader(BufReader::new(open_input(path)?))? {
+        Value::Object(mut response) => match response.remove("Users") {
+            Some(Value::Array(users)) => users,
+            _ => return Err("Cognito export has no Users array".into()),
+        },
+        Value::Array(users) => users,
+        _ => return Err("Cognito export must be a JSON object or array".into()),
+    };
+    Ok(Box::new(users.into_iter().enumerate().map(
+        |(index, user)| match user {
+            Value::Object(user) => Ok((index as u64 + 1, flatten_list_users_entry(user))),
+            _ => Err(format!("Cognito user {} is not a JSON object", index + 1).into()),
+        },
+    )))
+}
+
+// Turn a ListUsers entry into the flat attribute layout of the CSV export:
+// `Attributes` name/value pairs become keys, `Username` becomes `cognito:username`.
+fn flatten_list_users_entry(mut user: Row) -> Row {
+    let mut row = Row::new();
+    if let Some(Value::Array(attributes)) = user.remove("Attributes") {
+        for attribute in attributes {
+            if let (Some(name), Some(value)) = (
+                attribute.get("Name").and_then(Value::as_str),
+                attribute.get("Value"),
+            ) {
+                row.insert(name.to_string(), value.clone());
+            }
+        }
+    }
+    if let Some(username) = user.remove("Username") {
+        row.insert("cognito:username".into(), username);
+    }
+    for (key, value) in user {
+        row.entry(key).or_insert(value);
+    }
+    row
+}
+
+/// Convert a Cognito user into a row.
+///
+/// * `email` becomes an `emailAddress` identity, `preferred_username` (or the
+///   Cognito username, for users without email) a `userName` identity
+/// * `phone_number` becomes the `phoneAuthMethod`
+/// * `custom:*` attributes become extension attributes, when `extension_app_id` is set
+/// * disabled users (`Enabled: false`) are created with `accountEnabled` set to false
+/// * `sub` (or the Cognito username) is kept as the legacy id
+///
+/// Cognito does not export password hashes, so no `passwordProfile` is set
+/// and a temporary password is generated for the row.
+pub fn cognito_to_row(cfg: &AdapterConfig, record: Row) -> Result<Row, String> {
+    let record = Value::Object(record);
+    let username = text_field(&record, "cognito:username");
+    let legacy_id = text_field(&record, "sub")
+        .or(username)
+        .ok_or("Cognito user has no sub or username")?;
+
+    let email = text_field(&record, "email");
+    let mut identities = Vec::new();
+    if let Some(email) = email {
+        identities.push(cfg.identity("emailAddress", email));
+    }
+    match (text_field(&record, "preferred_username"), email) {
+        (Some(preferred), _) => identities.push(cfg.identity("userName", preferred)),
+        (None, None) => {
+            if let Some(username) = username {
+                identities.push(cfg.identity("userName", username));
+            }
+        }
+        (None, Some(_)) => {}
+    }
+    if identities.is_empty() {
+        return Err(format!("Cognito user {legacy_id} has no email or username"));
+    }
+
+    let given_name = text_field(&record, "given_name");
+    let family_name = text_field(&record, "family_name");
+    let display_name = text_field(&record, "name")
+        .map(str::to_string)
+        .or_else(|| full_name(given_name, family_name))
+        .or_else(|| email.map(str::to_string))
+        .unwrap_or_else(|| legacy_id.to_string());
+
+    let mut row = Row::new();
+    row.insert("legacyId".into(), legacy_id.into());
+    row.insert("displayName".into(), display_name.into());
+    row.insert("identities".into(), identities.into());
+    if let Some(given_name) = given_name {
+        row.insert("givenName".into(), given_name.into());
+    }
+    if let Some(family_name) = family_name {
+        row.insert("surname".into(), family_name.into());
+    }
+    if let Some(phone) = text_field(&record, "phone_number") {
+        row.insert("phoneAuthMethod".into(), phone.into());
+    }
+    let enabled = match record.get("Enabled") {
+        Some(Value::Bool(enabled)) => Some(*enabled),
+        Some(Value::String(enabled)) => enabled.parse::<bool>().ok(),
+        _ => None,
+    };
+    if let Some(enabled) = enabled {
+        row.insert("accountEnabled".into(), enabled.into());
+    }
+    if let Value::Object(attributes) = &record {
+        for (name, value) in attributes {
+            let Some(custom) = name.strip_prefix(CUSTOM_PREFIX) else {
+                continue;
+            };
+            if value.as_str().is_some_and(|v| v.trim().is_empty()) {
+                continue;
+            }
+            if let Some(attribute) = cfg.custom_attribute(custom) {
+                row.insert(attribute, value.clone());
+            }
+        }
+    }
+    cfg.map_attributes(&record, &mut row);
+    Ok(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{PasswordPolicy, RequestBody};
+    use crate::testing::TempPath;
+
+    // Fill in the generated password, as done for every adapter row
+    fn to_body(mut row: Row) -> RequestBody {
+        assert!(!row.contains_key("passwordProfile"));
+        row.insert(
+            "passwordProfile".into(),
+            PasswordPolicy::default().password_profile(),
+        );
+        RequestBody::from_row(row).unwrap()
+    }
+
+    fn test_config() -> AdapterConfig {
+        toml::from_str(
+            r#"
+            issuer = "contoso.onmicrosoft.com"
+            extension_app_id = "0a1b2c3d-0000-0000-0000-000000000000"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn convert_all(path: &Path) -> Vec<RequestBody> {
+        read_cognito_rows(path)
+            .unwrap()
+            .map(|r| {
+                let row = cognito_to_row(&test_config(), r.unwrap().1).unwrap();
+                to_body(row)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cognito_list_users_json() {
+        let path = TempPath::with_contents(
+            "json",
+            r#"{"Users": [{
+                "Username": "6f1c1c0e-aaaa-bbbb-cccc-000000000001",
+                "Enabled": false,
+                "UserStatus": "CONFIRMED",
+                "Attributes": [
+                    {"Name": "sub", "Value": "6f1c1c0e-aaaa-bbbb-cccc-000000000001"},
+                    {"Name": "email", "Value": "jane@example.com"},
+                    {"Name": "given_name", "Value": "Jane"},
+                    {"Name": "family_name", "Value": "Doe"},
+                    {"Name": "phone_number", "Value": "+15555551234"},
+                    {"Name": "custom:tier", "Value": "gold"}
+                ]
+            }]}"#,
+        );
+        let bodies = convert_all(&path);
+        assert_eq!(bodies.len(), 1);
+        let body = &bodies[0];
+        assert_eq!(
+            body.legacyId.as_deref(),
+            Some("6f1c1c0e-aaaa-bbbb-cccc-000000000001")
+        );
+        assert_eq!(body.displayName, "Jane Doe");
+        assert_eq!(body.identities.len(), 1);
+        assert_eq!(body.identities[0].issuerAssignedId, "jane@example.com");
+        assert_eq!(body.phoneAuthMethod.as_deref(), Some("+15555551234"));
+        assert_eq!(body.custom_fields["accountEnabled"], false);
+        assert_eq!(
+            body.custom_fields["extension_0a1b2c3d000000000000000000000000_tier"],
+            "gold"
+        );
+    }
+
+    #[test]
+    fn test_cognito_csv_export() {
+        let path = TempPath::with_contents(
+            "csv",
+            concat!(
+                "cognito:username,sub,email,phone_number,preferred_username,custom:tier\n",
+                "jdoe,1111,,+15555551234,,\n",
+                "asmith,2222,anna@example.com,,anna,silver\n",
+            ),
+        );
+        let bodies = convert_all(&path);
+        assert_eq!(bodies[0].legacyId.as_deref(), Some("1111"));
+        assert_eq!(bodies[0].identities[0].signInType, "userName");
+        assert_eq!(bodies[0].identities[0].issuerAssignedId, "jdoe");
+        // Phone numbers keep their leading + since CSV cells are read as text
+        assert_eq!(bodies[0].phoneAuthMethod.as_deref(), Some("+15555551234"));
+        assert!(!bodies[0]
+            .custom_fields
+            .contains_key("extension_0a1b2c3d000000000000000000000000_tier"));
+        assert_eq!(bodies[1].identities.len(), 2);
+        assert_eq!(bodies[1].identities[1].issuerAssignedId, "anna");
+    }
+
+    #[test]
+    fn test_cognito_user_without_identity() {
+        let record: Row = serde_json::from_str(r#"{"sub": "3333"}"#).unwrap();
+        let mut cfg = test_config();
+        cfg.extension_app_id = None;
+        let err = cognito_to_row(&cfg, record).unwrap_err();
+        assert!(err.contains("3333"));
+    }
+}
@@ -0,0 +1,162 @@
+use crate::run::run_context;
+use age::armor::ArmoredReader;
+use age::secrecy::SecretString;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+// Magic strings at the start of binary and armored age files
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+const AGE_ARMOR_MAGIC: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Keys used to decrypt encrypted input files.
+#[derive(Default)]
+pub struct DecryptionKeys {
+    /// Contents of age identity files (`AGE-SECRET-KEY-1...` lines)
+    identities: Vec<String>,
+    /// Passphrase of passphrase-encrypted files
+    passphrase: Option<SecretString>,
+}
+
+impl DecryptionKeys {
+    /// Collect the keys from an identity file and/or a passphrase.
+    /// The identities are checked right away, so a wrong file fails early.
+    pub fn new(
+        identity: Option<String>,
+        passphrase: Option<String>,
+    ) -> Result<DecryptionKeys, Box<dyn Error>> {
+        let keys = DecryptionKeys {
+            identities: identity.into_iter().collect(),
+            passphrase: passphrase.map(|p| SecretString::from(p.trim_end_matches(['\r', '\n']))),
+        };
+        keys.age_identities()?;
+        Ok(keys)
+    }
+
+    fn age_identities(&self) -> Result<Vec<Box<dyn age::Identity>>, Box<dyn Error>> {
+        let mut identities = Vec::new();
+        for contents in &self.identities {
+            identities.extend(
+                age::IdentityFile::from_buffer(contents.as_bytes())
+                    .map_err(|e| format!("Invalid age identity: {e}"))?
+                    .into_identities()?,
+            );
+        }
+        if let Some(passphrase) = &self.passphrase {
+            identities.push(Box::new(age::scrypt::Identity::new(passphrase.clone())));
+        }
+        Ok(identities)
+    }
+}
+
+/// Open an input file. Age-encrypted files (binary or armored) are detected
+/// from their header and decrypted on the fly while being read, with the
+/// keys of the run, so the plaintext never touches the disk.
+pub fn open_input<P: AsRef<Path>>(path: P) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    match run_context() {
+        Some(context) => open_with_keys(path, &context.decryption),
+        None => open_with_keys(path, &DecryptionKeys::default()),
+    }
+}
+
+fn open_with_keys<P: AsRef<Path>>(
+    path: P,
+    keys: &DecryptionKeys,
+) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path.as_ref())?);
+    let header = reader.fill_buf()?;
+    if !header.starts_with(AGE_MAGIC) && !header.starts_with(AGE_ARMOR_MAGIC) {
+        return Ok(Box::new(reader));
+    }
+
+    let identities = keys.age_identities()?;
+    if identities.is_empty() {
+        return Err(format!(
+            "{} is encrypted, but no identity or passphrase was given",
+            path.as_ref().display()
+        )
+        .into());
+    }
+    let decryptor = age::Decryptor::new(ArmoredReader::new(reader))?;
+    let decrypted = decryptor
+        .decrypt(identities.iter().map(|i| i.as_ref()))
+        .map_err(|e| format!("Unable to decrypt {}: {e}", path.as_ref().display()))?;
+    Ok(Box::new(decrypted))
+}
+
+/// Extension of an input file, ignoring a trailing `.age` (e.g. `users.csv.age` is `csv`).
+pub fn input_extension<P: AsRef<Path>>(path: P) -> Option<String> {
+    let path = path.as_ref();
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    if extension == "age" {
+        input_extension(path.file_stem()?)
+    } else {
+        Some(extension)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::create_encrypted;
+    use crate::testing::TempPath;
+    use age::secrecy::ExposeSecret;
+    use std::io::Write;
+
+    fn read_all(mut reader: Box<dyn Read>) -> String {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_open_plain_and_encrypted_input() {
+        let identity = age::x25519::Identity::generate();
+        let plain = TempPath::with_contents("csv", "displayName\nJane\n");
+        let encrypted = TempPath::file("csv.age");
+        let mut writer = create_encrypted(&encrypted, &identity.to_public()).unwrap();
+        writer.write_all(b"displayName\nJane\n").unwrap();
+        writer.finish().unwrap();
+
+        let keys =
+            DecryptionKeys::new(Some(identity.to_string().expose_secret().to_string()), None)
+                .unwrap();
+        let no_keys = DecryptionKeys::default();
+        assert_eq!(
+            read_all(open_with_keys(&plain, &no_keys).unwrap()),
+            "displayName\nJane\n"
+        );
+        assert_eq!(
+            read_all(open_with_keys(&encrypted, &keys).unwrap()),
+            "displayName\nJane\n"
+        );
+        assert!(open_with_keys(&encrypted, &no_keys).is_err());
+    }
+
+    #[test]
+    fn test_open_armored_passphrase_input() {
+        let path = TempPath::file("jsonl.age");
+        let encryptor = age::Encryptor::with_user_passphrase(SecretString::from("correct horse"));
+        let armored = age::armor::ArmoredWriter::wrap_output(
+            File::create(&path).unwrap(),
+            age::armor::Format::AsciiArmor,
+        )
+        .unwrap();
+        let mut writer = encryptor.wrap_output(armored).unwrap();
+        writer.write_all(b"{}\n").unwrap();
+        writer.finish().unwrap().finish().unwrap();
+
+        let keys = DecryptionKeys::new(None, Some("correct horse\n".to_string())).unwrap();
+        assert_eq!(read_all(open_with_keys(&path, &keys).unwrap()), "{}\n");
+        let wrong = DecryptionKeys::new(None, Some("wrong".to_string())).unwrap();
+        assert!(open_with_keys(&path, &wrong).is_err());
+    }
+
+    #[test]
+    fn test_input_extension_skips_age() {
+        assert_eq!(input_extension("users.csv.age").as_deref(), Some("csv"));
+        assert_eq!(input_extension("users.JSONL").as_deref(), Some("jsonl"));
+        assert_eq!(input_extension("users.age"), None);
+    }
+}
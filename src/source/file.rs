@@ -0,0 +1,274 @@
+use crate::graph::{Row, PHONE_COLUMNS};
+use crate::source::{input_extension, open_input};
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Row of an input paired with its line number (or its 1-based position,
+/// for JSON arrays).
+pub type NumberedRow = Result<(u64, Row), Box<dyn Error>>;
+
+/// Iterator over the rows of an input.
+pub type RowIter = Box<dyn Iterator<Item = NumberedRow>>;
+
+/// Supported input file formats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    /// Comma separated values with a header line
+    Csv,
+    /// A single JSON array of user objects
+    Json,
+    /// One JSON user object per line
+    Jsonl,
+}
+
+impl InputFormat {
+    /// Detect the format from the file extension (ignoring `.age`), defaulting to CSV.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> InputFormat {
+        match input_extension(path).as_deref() {
+            Some("json") => InputFormat::Json,
+            Some("jsonl") | Some("ndjson") => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(InputFormat::Csv),
+            "json" => Ok(InputFormat::Json),
+            "jsonl" | "ndjson" => Ok(InputFormat::Jsonl),
+            other => Err(format!("Unknown input format: {other}")),
+        }
+    }
+}
+
+/// Count the rows of the input file, without keeping them in memory.
+pub fn count_rows<P: AsRef<Path>>(path: P, format: InputFormat) -> Result<u64, Box<dyn Error>> {
+    let count = match format {
+        InputFormat::Csv => csv::Reader::from_reader(open_input(path)?)
+            .records()
+            .filter_map(Result::ok)
+            .count(),
+        InputFormat::Json => serde_json::from_reader::<_, Vec<serde::de::IgnoredAny>>(
+            BufReader::new(open_input(path)?),
+        )?
+        .len(),
+        InputFormat::Jsonl => BufReader::new(open_input(path)?)
+            .lines()
+            .map_while(Result::ok)
+            .filter(|l| !l.trim().is_empty())
+            .count(),
+    };
+    Ok(count as u64)
+}
+
+/// Open the input file and return an iterator over its rows.
+///
+/// CSV cells go through the csv crate type inference (except phone numbers,
+/// kept as text, along with the `text_columns`), while JSON values are
+/// kept as they are, so `identities` and `passwordProfile` can be written as
+/// nested objects instead of JSON strings.
+pub fn read_rows<P: AsRef<Path>>(
+    path: P,
+    format: InputFormat,
+    text_columns: &[String],
+) -> Result<RowIter, Box<dyn Error>> {
+    match format {
+        InputFormat::Csv => {
+            let mut rdr = csv::Reader::from_reader(open_input(path)?);
+            let headers = rdr.headers()?.clone();
+            let text_columns = text_columns.to_vec();
+            Ok(Box::new(rdr.into_records().enumerate().map(
+                move |(index, result)| {
+                    let record = result?;
+                    let mut row: Row = record.deserialize(Some(&headers))?;
+                    // Phone numbers must not go through the type inference
+                    for (header, value) in headers.iter().zip(record.iter()) {
+                        if PHONE_COLUMNS.contains(&header)
+                            || text_columns.iter().any(|column| column == header)
+                        {
+                            row.insert(header.to_string(), value.into());
+                        }
+                    }
+                    // Line 1 holds the CSV headers
+                    Ok((index as u64 + 2, row))
+                },
+            )))
+        }
+        InputFormat::Json => {
+            let rows: Vec<Row> = serde_json::from_reader(BufReader::new(open_input(path)?))?;
+            Ok(Box::new(
+                rows.into_iter()
+                    .enumerate()
+                    .map(|(index, row)| Ok((index as u64 + 1, row))),
+            ))
+        }
+        InputFormat::Jsonl => {
+            let lines = BufReader::new(open_input(path)?).lines();
+            Ok(Box::new(lines.enumerate().filter_map(|(index, line)| {
+                let line_number = index as u64 + 1;
+                match line {
+                    Ok(l) if l.trim().is_empty() => None,
+                    Ok(l) => Some(
+                        serde_json::from_str::<Row>(&l)
+                            .map(|row| (line_number, row))
+                            .map_err(|e| format!("Invalid JSON at line {line_number}: {e}").into()),
+                    ),
+                    Err(e) => Some(Err(e.into())),
+                }
+            })))
+        }
+    }
+}
+
+/// Open a CSV file keeping every cell as text, without the type inference
+/// of `read_rows` (e.g. phone numbers keep their leading `+`).
+pub fn read_csv_text_rows<P: AsRef<Path>>(path: P) -> Result<RowIter, Box<dyn Error>> {
+    let rdr = csv::Reader::from_reader(open_input(path)?);
+    Ok(Box::new(
+        rdr.into_deserialize::<std::collections::HashMap<String, String>>()
+            .enumerate()
+            .map(|(index, result)| {
+                let row = result?
+                    .into_iter()
+                    .map(|(k, v)| (k, serde_json::Value::String(v)))
+                    .collect();
+                // Line 1 holds the CSV headers
+                Ok((index as u64 + 2, row))
+            }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::RequestBody;
+    use crate::testing::TempPath;
+
+    fn read_bodies(path: &Path, format: InputFormat) -> Vec<(u64, RequestBody)> {
+        read_rows(path, format, &[])
+            .unwrap()
+            .map(|r| {
+                let (line, row) = r.unwrap();
+                (line, RequestBody::from_row(row).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_input_format_detection() {
+        assert_eq!(InputFormat::from_path("users.csv"), InputFormat::Csv);
+        assert_eq!(InputFormat::from_path("users.JSON"), InputFormat::Json);
+        assert_eq!(InputFormat::from_path("users.jsonl"), InputFormat::Jsonl);
+        assert_eq!(InputFormat::from_path("users.ndjson"), InputFormat::Jsonl);
+        assert_eq!(InputFormat::from_path("users"), InputFormat::Csv);
+        assert_eq!(
+            InputFormat::from_path("users.ndjson.age"),
+            InputFormat::Jsonl
+        );
+        assert_eq!("jsonl".parse::<InputFormat>(), Ok(InputFormat::Jsonl));
+        assert!("xml".parse::<InputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_read_json_array_with_nested_objects() {
+        let path = TempPath::with_contents(
+            "json",
+            r#"[
+                {"displayName": "John Doe", "accountEnabled": true,
+                 "passwordProfile": {"forceChangePasswordNextSignIn": false, "password": "Str0ngP@ss!"},
+                 "identities": [{"signInType": "emailAddress", "issuer": "mydomain.com", "issuerAssignedId": "john.doe@mydomain.com"}],
+                 "extension_123_loyalty": {"tier": "gold"}},
+                {"displayName": "Jane Smith",
+                 "passwordProfile": "{\"forceChangePasswordNextSignIn\": true, \"password\": \"AnotherP@ssw0rd\"}",
+                 "identities": "[{\"signInType\": \"userName\", \"issuer\": \"mydomain.com\", \"issuerAssignedId\": \"janes\"}]"}
+            ]"#,
+        );
+        assert_eq!(count_rows(&path, InputFormat::Json).unwrap(), 2);
+        let bodies = read_bodies(&path, InputFormat::Json);
+        assert_eq!(bodies[0].0, 1);
+        assert_eq!(
+            bodies[0].1.identities[0].issuerAssignedId,
+            "john.doe@mydomain.com"
+        );
+        assert_eq!(
+            bodies[0].1.custom_fields["extension_123_loyalty"]["tier"],
+            "gold"
+        );
+        assert_eq!(bodies[1].1.passwordProfile.password, "AnotherP@ssw0rd");
+    }
+
+    #[test]
+    fn test_read_jsonl_skips_blank_lines() {
+        let path = TempPath::with_contents(
+            "jsonl",
+            concat!(
+                r#"{"displayName": "A", "passwordProfile": {"forceChangePasswordNextSignIn": true, "password": "p"}, "identities": [{"signInType": "userName", "issuer": "i", "issuerAssignedId": "a"}]}"#,
+                "\n\n",
+                r#"{"displayName": "B", "passwordProfile": {"forceChangePasswordNextSignIn": true, "password": "p"}, "identities": [{"signInType": "userName", "issuer": "i", "issuerAssignedId": "b"}], "phoneAuthMethod": "+1 5555551234"}"#,
+                "\n"
+            ),
+        );
+        assert_eq!(count_rows(&path, InputFormat::Jsonl).unwrap(), 2);
+        let bodies = read_bodies(&path, InputFormat::Jsonl);
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[1].0, 3);
+        assert_eq!(
+            bodies[1].1.phoneAuthMethod.as_deref(),
+            Some("+1 5555551234")
+        );
+    }
+
+    #[test]
+    fn test_read_jsonl_invalid_line() {
+        let path = TempPath::with_contents("jsonl", "{\"displayName\": \n");
+        let mut rows = read_rows(&path, InputFormat::Jsonl, &[]).unwrap();
+        assert!(rows.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_read_csv_rows() {
+        let path = TempPath::with_contents(
+            "csv",
+            concat!(
+                "displayName,passwordProfile,identities,accountEnabled,phoneAuthMethod\n",
+                r#""John Doe","{""forceChangePasswordNextSignIn"":false,""password"":""Str0ngP@ss!""}","[{""signInType"":""emailAddress"",""issuer"":""mydomain.com"",""issuerAssignedId"":""john.doe@mydomain.com""}]",true,+15555551234"#,
+                "\n"
+            ),
+        );
+        assert_eq!(count_rows(&path, InputFormat::Csv).unwrap(), 1);
+        let bodies = read_bodies(&path, InputFormat::Csv);
+        assert_eq!(bodies[0].0, 2);
+        assert_eq!(bodies[0].1.custom_fields["accountEnabled"], true);
+        // Phone numbers are not inferred as integers
+        assert_eq!(bodies[0].1.phoneAuthMethod.as_deref(), Some("+15555551234"));
+    }
+
+    #[test]
+    fn test_read_csv_keeps_phone_fields_as_text() {
+        let path = TempPath::with_contents(
+            "csv",
+            "displayName,homePhone,faxNumber\nJohn Doe,+393471234567,00390669821234\n",
+        );
+        let fields = vec!["homePhone".to_string(), "faxNumber".to_string()];
+        let (_, row) = read_rows(&path, InputFormat::Csv, &fields)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(row["homePhone"], "+393471234567");
+        assert_eq!(row["faxNumber"], "00390669821234");
+        // Other columns are still inferred
+        let (_, row) = read_rows(&path, InputFormat::Csv, &[])
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(row["homePhone"], 393471234567u64);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::graph::Row;
+use crate::source::{full_name, open_input, text_field, AdapterConfig, RowIter};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::BufReader;
+use std::path::Path;
+
+// Keycloak attributes holding the user's phone number
+const PHONE_ATTRIBUTES: [&str; 3] = ["phoneNumber", "phone_number", "mobile"];
+
+/// Open a Keycloak export: a realm export or a `*-users-*.json` partial
+/// export, both holding a `users` array, or a plain array of users.
+pub fn read_keycloak_rows<P: AsRef<Path>>(path: P) -> Result<RowIter, Box<dyn Error>> {
+    let users = match serde_json::from_reader(BufReader::new(open_input(path)?))? {
+        Value::Object(mut export) => match export.remove("users") {
+            Some(Value::Array(users)) => users,
+            _ => return Err("Keycloak export has no users array".into()),
+        },
+        Value::Array(users) => users,
+        _ => return Err("Keycloak export must be a JSON object or array".into()),
+    };
+    Ok(Box::new(users.into_iter().enumerate().map(
+        |(index, user)| match user {
+            Value::Object(user) => Ok((index as u64 + 1, user)),
+            _ => Err(format!("Keycloak user {} is not a JSON object", index + 1).into()),
+        },
+    )))
+}
+
+// Keycloak stores every attribute as a list of strings: single values are
+// unwrapped, multiple values are kept as a JSON string.
+fn attribute_value(values: &Value) -> Option<Value> {
+    match values {
+        Value::Array(list) if list.is_empty() => None,
+        Value::Array(list) if list.len() == 1 => Some(list[0].clone()),
+        Value::Array(_) => Some(Value::String(values.to_string())),
+        Value::Null => None,
+        v => Some(v.clone()),
+    }
+}
+
+/// Convert a Keycloak user into a row.
+///
+/// * `email` becomes an `emailAddress` identity and `username` a `userName`
+///   identity (unless it is the email address itself)
+/// * a `phoneNumber`/`phone_number`/`mobile` attribute becomes the `phoneAuthMethod`
+/// * the other `attributes` become extension attributes, when `extension_app_id` is set
+/// * disabled users are created with `accountEnabled` set to false
+/// * `id` is kept as the legacy id
+/// * a PBKDF2 password credential becomes the `legacyPassword`, for the
+///   seamless migration
+///
+/// Keycloak credentials are hashed, so no `passwordProfile` is set and a
+/// temporary password is generated for the row.
+pub fn keycloak_to_row(cfg: &AdapterConfig, record: Row) -> Result<Row, String> {
+    let record = Value::Object(record);
+    let username = text_field(&record, "username");
+    let legacy_id = text_field(&record, "id")
+        .or(username)
+        .ok_or("Keycloak user has no id or username")?;
+
+    let email = text_field(&record, "email");
+    let mut identities = Vec::new();
+    if let Some(email) = email {
+        identities.push(cfg.identity("emailAddress", email));
+    }
+    if let Some(username) = username {
+        if !email.is_some_and(|e| e.eq_ignore_ascii_case(username)) {
+            identities.push(cfg.identity("userName", username));
+        }
+    }
+    if identities.is_empty() {
+        return Err(format!(
+            "Keycloak user {legacy_id} has no email or username"
+        ));
+    }
+
+    let first_name = text_field(&record, "firstName");
+    let last_name = text_field(&record, "lastName");
+    let display_name = full_name(first_name, last_name)
+        .or_else(|| username.map(str::to_string))
+        .or_else(|| email.map(str::to_string))
+        .unwrap_or_else(|| legacy_id.to_string());
+
+    let mut row = Row::new();
+    row.insert("legacyId".into(), legacy_id.into());
+    row.insert("displayName".into(), display_name.into());
+    row.insert("identities".into(), identities.into());
+    if let Some(first_name) = first_name {
+        row.insert("givenName".into(), first_name.into());
+    }
+    if let Some(last_name) = last_name {
+        row.insert("surname".into(), last_name.into());
+    }
+    if let Some(enabled) = record.get("enabled").and_then(Value::as_bool) {
+        row.insert("accountEnabled".into(), enabled.into());
+    }
+    if let Some(Value::Object(attributes)) = record.get("attributes") {
+        for (name, values) in attributes {
+            let Some(value) = attribute_value(values) else {
+                continue;
+            };
+            if PHONE_ATTRIBUTES.contains(&name.as_str()) {
+                row.entry("phoneAuthMethod").or_insert(value);
+            } else if let Some(attribute) = cfg.custom_attribute(name) {
+                row.insert(attribute, value);
+            }
+        }
+    }
+    if let Some(legacy_password) = legacy_password(&record) {
+        row.insert("legacyPassword".into(), legacy_password);
+    }
+    cfg.map_attributes(&record, &mut row);
+    Ok(row)
+}
+
+// Keycloak stores the hash and salt in `secretData` and the algorithm and
+// iterations in `credentialData`, both as JSON strings. Only the PBKDF2
+// algorithms can be verified by the JIT migration server.
+fn legacy_password(record: &Value) -> Option<Value> {
+    let credential = record
+        .get("credentials")?
+        .as_array()?
+        .iter()
+        .find(|c| c.get("type").and_then(Value::as_str) == Some("password"))?;
+    let parse =
+        |key: &str| -> Option<Value> { serde_json::from_str(credential.get(key)?.as_str()?).ok() };
+    let secret = parse("secretData")?;
+    let data = parse("credentialData")?;
+    let algorithm = match data.get("algorithm")?.as_str()? {
+        "pbkdf2" => "pbkdf2-sha1",
+        algorithm @ ("pbkdf2-sha256" | "pbkdf2-sha512") => algorithm,
+        _ => return None,
+    };
+    Some(json!({
+        "algorithm": algorithm,
+        "hash": secret.get("value")?,
+        "salt": secret.get("salt")?,
+        "iterations": data.get("hashIterations")?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{PasswordPolicy, RequestBody};
+    use crate::migration::HashAlgorithm;
+    use crate::testing::TempPath;
+
+    // Fill in the generated password, as done for every adapter row
+    fn to_body(mut row: Row) -> RequestBody {
+        assert!(!row.contains_key("passwordProfile"));
+        row.insert(
+            "passwordProfile".into(),
+            PasswordPolicy::default().password_profile(),
+        );
+        RequestBody::from_row(row).unwrap()
+    }
+
+    fn test_config() -> AdapterConfig {
+        toml::from_str(
+            r#"
+            issuer = "contoso.onmicrosoft.com"
+            extension_app_id = "0a1b2c3d-0000-0000-0000-000000000000"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_keycloak_realm_export() {
+        let path = TempPath::file("json");
+        std::fs::write(
+            &path,
+            r#"{"realm": "legacy", "users": [{
+                "id": "8c5f1a2e-0000-0000-0000-000000000001",
+                "username": "jdoe",
+                "email": "jane@example.com",
+                "emailVerified": true,
+                "firstName": "Jane",
+                "lastName": "Doe",
+                "enabled": true,
+                "attributes": {"phoneNumber": ["+15555551234"], "tier": ["gold"], "groups": ["a", "b"]},
+                "credentials": [{"type": "password", "secretData": "{\"value\":\"aGFzaA==\",\"salt\":\"c2FsdA==\"}", "credentialData": "{\"hashIterations\":27500,\"algorithm\":\"pbkdf2-sha256\"}"}]
+            }, {
+                "id": "8c5f1a2e-0000-0000-0000-000000000002",
+                "username": "anna@example.com",
+                "email": "anna@example.com",
+                "enabled": false
+            }]}"#,
+        )
+        .unwrap();
+
+        let bodies: Vec<RequestBody> = read_keycloak_rows(&path)
+            .unwrap()
+            .map(|r| to_body(keycloak_to_row(&test_config(), r.unwrap().1).unwrap()))
+            .collect();
+
+        let jane = &bodies[0];
+        assert_eq!(
+            jane.legacyId.as_deref(),
+            Some("8c5f1a2e-0000-0000-0000-000000000001")
+        );
+        assert_eq!(jane.displayName, "Jane Doe");
+        assert_eq!(jane.identities.len(), 2);
+        assert_eq!(jane.identities[1].signInType, "userName");
+        assert_eq!(jane.phoneAuthMethod.as_deref(), Some("+15555551234"));
+        assert!(jane.passwordProfile.forceChangePasswordNextSignIn);
+        assert_eq!(
+            jane.custom_fields["extension_0a1b2c3d000000000000000000000000_tier"],
+            "gold"
+        );
+        assert_eq!(
+            jane.custom_fields["extension_0a1b2c3d000000000000000000000000_groups"],
+            r#"["a","b"]"#
+        );
+        assert!(!jane.custom_fields.contains_key("credentials"));
+        let legacy_password = jane.legacyPassword.as_ref().unwrap();
+        assert_eq!(legacy_password.algorithm, HashAlgorithm::Pbkdf2Sha256);
+        assert_eq!(legacy_password.salt.as_deref(), Some("c2FsdA=="));
+        assert_eq!(legacy_password.iterations, Some(27500));
+
+        let anna = &bodies[1];
+        assert_eq!(anna.identities.len(), 1);
+        assert!(anna.legacyPassword.is_none());
+        assert_eq!(anna.custom_fields["accountEnabled"], false);
+    }
+}
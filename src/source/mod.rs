@@ -0,0 +1,15 @@
+mod adapter;
+mod auth0;
+mod cognito;
+mod decryption;
+mod file;
+mod keycloak;
+mod sql;
+
+pub use crate::source::adapter::*;
+pub use crate::source::auth0::*;
+pub use crate::source::cognito::*;
+pub use crate::source::decryption::*;
+pub use crate::source::file::*;
+pub use crate::source::keycloak::*;
+pub use crate::source::sql::*;
@@ -0,0 +1,161 @@
+use crate::telemetry::SpanData;
+use log::warn;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout, Duration};
+
+/// Traces are sent once this many spans are waiting, or after this delay.
+const BATCH_SPANS: usize = 512;
+const BATCH_DELAY: Duration = Duration::from_secs(2);
+
+/// Messages of the background exporter.
+pub enum ExportMessage {
+    /// The spans of a finished trace
+    Spans(Vec<SpanData>),
+    /// Export what is waiting, then acknowledge
+    Flush(oneshot::Sender<()>),
+}
+
+/// Destination of the traces, in the OTLP/JSON encoding.
+pub enum TraceSink {
+    /// An OTLP/HTTP collector, e.g. `http://localhost:4318/v1/traces`
+    Otlp {
+        client: reqwest::Client,
+        url: String,
+    },
+    /// A file receiving one export request per line
+    File(File),
+}
+
+impl TraceSink {
+    /// OTLP/HTTP collector at `endpoint`, with or without the `/v1/traces` path.
+    pub fn otlp(endpoint: &str) -> TraceSink {
+        let endpoint = endpoint.trim_end_matches('/');
+        let url = if endpoint.ends_with("/v1/traces") {
+            endpoint.to_string()
+        } else {
+            format!("{endpoint}/v1/traces")
+        };
+        TraceSink::Otlp {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    /// File at `path`, appended to.
+    pub fn file<P: AsRef<Path>>(path: P) -> Result<TraceSink, Box<dyn Error>> {
+        Ok(TraceSink::File(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        ))
+    }
+
+    async fn export(&mut self, request: &Value) -> Result<(), Box<dyn Error>> {
+        match self {
+            TraceSink::Otlp { client, url } => {
+                let response = client.post(url.as_str()).json(request).send().await?;
+                if !response.status().is_success() {
+                    return Err(format!("collector answered {}", response.status()).into());
+                }
+            }
+            TraceSink::File(file) => writeln!(file, "{request}")?,
+        }
+        Ok(())
+    }
+}
+
+/// OTLP `ExportTraceServiceRequest` of a batch of spans.
+pub fn export_request(spans: &[SpanData]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    {"key": "service.name", "value": {"stringValue": "b2c-migrator"}},
+                    {"key": "service.version", "value": {"stringValue": env!("CARGO_PKG_VERSION")}},
+                ]
+            },
+            "scopeSpans": [{
+                "scope": {"name": "b2c-migrator", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans.iter().map(SpanData::to_otlp).collect::<Vec<_>>(),
+            }]
+        }]
+    })
+}
+
+/// Export the traces received until the channel closes, in batches.
+pub async fn run_exporter(
+    mut receiver: mpsc::UnboundedReceiver<ExportMessage>,
+    mut sinks: Vec<TraceSink>,
+) {
+    let mut batch: Vec<SpanData> = Vec::new();
+    loop {
+        let message = match timeout(BATCH_DELAY, receiver.recv()).await {
+            Ok(Some(message)) => Some(message),
+            Ok(None) => {
+                flush(&mut batch, &mut sinks).await;
+                return;
+            }
+            Err(_) => None,
+        };
+        match message {
+            Some(ExportMessage::Spans(spans)) => {
+                batch.extend(spans);
+                if batch.len() >= BATCH_SPANS {
+                    flush(&mut batch, &mut sinks).await;
+                }
+            }
+            Some(ExportMessage::Flush(done)) => {
+                flush(&mut batch, &mut sinks).await;
+                let _ = done.send(());
+            }
+            None => flush(&mut batch, &mut sinks).await,
+        }
+    }
+}
+
+async fn flush(batch: &mut Vec<SpanData>, sinks: &mut [TraceSink]) {
+    if batch.is_empty() {
+        return;
+    }
+    let request = export_request(batch);
+    batch.clear();
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.export(&request).await {
+            warn!("Traces not exported: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::Tracer;
+    use mockito::Matcher;
+
+    #[tokio::test]
+    async fn test_traces_are_posted_to_the_collector() {
+        let mut server = mockito::Server::new_async().await;
+        let collector = server
+            .mock("POST", "/v1/traces")
+            .match_header("content-type", "application/json")
+            .match_body(Matcher::PartialJson(json!({
+                "resourceSpans": [{
+                    "resource": {"attributes": [{"key": "service.name", "value": {"stringValue": "b2c-migrator"}}]},
+                }]
+            })))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let tracer = Tracer::new(vec![TraceSink::otlp(&format!("{}/", server.url()))]);
+        let root = tracer.start_trace("migrate user");
+        drop(root.child("create user"));
+        drop(root);
+        tracer.flush().await;
+        collector.assert_async().await;
+    }
+}
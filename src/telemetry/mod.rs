@@ -0,0 +1,5 @@
+mod export;
+mod trace;
+
+pub use crate::telemetry::export::*;
+pub use crate::telemetry::trace::*;
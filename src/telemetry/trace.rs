@@ -0,0 +1,373 @@
+use crate::graph::GraphError;
+use crate::run::run_context;
+use crate::telemetry::{ExportMessage, TraceSink};
+use log::warn;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+
+/// Value of a span attribute.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(s: &str) -> Self {
+        AttributeValue::Str(s.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(s: String) -> Self {
+        AttributeValue::Str(s)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(n: i64) -> Self {
+        AttributeValue::Int(n)
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(b: bool) -> Self {
+        AttributeValue::Bool(b)
+    }
+}
+
+/// A finished span, as exported.
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub name: String,
+    /// OTLP span kind: client for HTTP calls, internal otherwise
+    pub client: bool,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(String, AttributeValue)>,
+    /// Error message, for spans that failed
+    pub error: Option<String>,
+}
+
+impl SpanData {
+    /// OTLP/JSON representation of the span.
+    pub fn to_otlp(&self) -> Value {
+        let nanos = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_string()
+        };
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    AttributeValue::Str(s) => json!({"stringValue": s}),
+                    AttributeValue::Int(n) => json!({"intValue": n.to_string()}),
+                    AttributeValue::Bool(b) => json!({"boolValue": b}),
+                };
+                json!({"key": key, "value": value})
+            })
+            .collect();
+        let status = match &self.error {
+            Some(message) => json!({"code": 2, "message": message}),
+            None => json!({"code": 1}),
+        };
+        json!({
+            "traceId": format!("{:032x}", self.trace_id),
+            "spanId": format!("{:016x}", self.span_id),
+            "parentSpanId": self.parent_span_id.map(|id| format!("{id:016x}")).unwrap_or_default(),
+            "name": self.name,
+            "kind": if self.client { 3 } else { 1 },
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(self.end),
+            "attributes": attributes,
+            "status": status,
+        })
+    }
+}
+
+/// Spans of the migration of one input row, exported together when its
+/// root span ends.
+pub struct Trace {
+    trace_id: u128,
+    spans: Mutex<Vec<SpanData>>,
+    failed: AtomicBool,
+    exporter: mpsc::UnboundedSender<ExportMessage>,
+}
+
+/// A running span, ended (and recorded in its trace) on drop. Spans
+/// created without a tracer are inert.
+pub struct SpanGuard {
+    inner: Option<ActiveSpan>,
+}
+
+struct ActiveSpan {
+    trace: Arc<Trace>,
+    data: SpanData,
+    root: bool,
+}
+
+impl SpanGuard {
+    fn inert() -> SpanGuard {
+        SpanGuard { inner: None }
+    }
+
+    fn start(trace: Arc<Trace>, parent: Option<u64>, name: &str) -> SpanGuard {
+        SpanGuard {
+            inner: Some(ActiveSpan {
+                data: SpanData {
+                    trace_id: trace.trace_id,
+                    span_id: rand::random::<u64>().max(1),
+                    parent_span_id: parent,
+                    name: name.to_string(),
+                    client: false,
+                    start: SystemTime::now(),
+                    end: SystemTime::now(),
+                    attributes: Vec::new(),
+                    error: None,
+                },
+                root: parent.is_none(),
+                trace,
+            }),
+        }
+    }
+
+    /// Start a span nested in this one.
+    pub fn child(&self, name: &str) -> SpanGuard {
+        match &self.inner {
+            Some(span) => SpanGuard::start(span.trace.clone(), Some(span.data.span_id), name),
+            None => SpanGuard::inert(),
+        }
+    }
+
+    /// Set an attribute of the span.
+    pub fn set(&mut self, key: &str, value: impl Into<AttributeValue>) {
+        if let Some(span) = &mut self.inner {
+            let value = value.into();
+            match span.data.attributes.iter_mut().find(|(k, _)| k == key) {
+                Some(attribute) => attribute.1 = value,
+                None => span.data.attributes.push((key.to_string(), value)),
+            }
+        }
+    }
+
+    /// Record an HTTP response: its status code and the request ids Graph
+    /// returns, to be quoted to Microsoft support.
+    pub fn record_response(&mut self, response: &reqwest::Response) {
+        if self.inner.is_none() {
+            return;
+        }
+        let status = response.status();
+        self.set("http.response.status_code", status.as_u16() as i64);
+        for (header, key) in [
+            ("client-request-id", "graph.client_request_id"),
+            ("request-id", "graph.request_id"),
+        ] {
+            if let Some(value) = response.headers().get(header).and_then(|v| v.to_str().ok()) {
+                self.set(key, value);
+            }
+        }
+        if let Some(span) = &mut self.inner {
+            span.data.client = true;
+        }
+    }
+
+    /// Record the error of a Graph request: its status code, ids and error code.
+    pub fn record_error(&mut self, error: &GraphError) {
+        if let Some(status) = error.status {
+            self.set("http.response.status_code", status as i64);
+        }
+        self.set("graph.client_request_id", error.client_request_id.as_str());
+        if let Some(request_id) = &error.request_id {
+            self.set("graph.request_id", request_id.as_str());
+        }
+        if let Some(code) = &error.code {
+            self.set("graph.error_code", code.as_str());
+        }
+    }
+
+    /// Mark the span, and its trace, as failed.
+    pub fn fail(&mut self, message: &str) {
+        if let Some(span) = &mut self.inner {
+            span.data.error = Some(message.to_string());
+            span.trace.failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn context(&self) -> Option<SpanContext> {
+        self.inner.as_ref().map(|span| SpanContext {
+            trace: span.trace.clone(),
+            span_id: span.data.span_id,
+        })
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        let Some(mut span) = self.inner.take() else {
+            return;
+        };
+        span.data.end = SystemTime::now();
+        if span.root && span.data.error.is_none() && span.trace.failed.load(Ordering::Relaxed) {
+            span.data.error = Some("A step of the migration failed".to_string());
+        }
+        let mut spans = span.trace.spans.lock().unwrap();
+        spans.push(span.data);
+        if span.root {
+            let spans = std::mem::take(&mut *spans);
+            let _ = span.trace.exporter.send(ExportMessage::Spans(spans));
+        }
+    }
+}
+
+#[derive(Clone)]
+struct SpanContext {
+    trace: Arc<Trace>,
+    span_id: u64,
+}
+
+tokio::task_local! {
+    static CURRENT_SPAN: SpanContext;
+}
+
+/// Run `future` with `span` as the parent of the spans it starts.
+pub async fn in_span<F: Future>(span: &SpanGuard, future: F) -> F::Output {
+    match span.context() {
+        Some(context) => CURRENT_SPAN.scope(context, future).await,
+        None => future.await,
+    }
+}
+
+/// Start a span nested in the current one, inert outside a trace.
+pub fn start_span(name: &str) -> SpanGuard {
+    CURRENT_SPAN
+        .try_with(|context| SpanGuard::start(context.trace.clone(), Some(context.span_id), name))
+        .unwrap_or_else(|_| SpanGuard::inert())
+}
+
+/// Starts the traces of a run and hands them over to a background exporter.
+pub struct Tracer {
+    exporter: mpsc::UnboundedSender<ExportMessage>,
+}
+
+impl Tracer {
+    /// Tracer exporting to `sinks`, in batches, from a background task.
+    pub fn new(sinks: Vec<TraceSink>) -> Tracer {
+        let (exporter, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(crate::telemetry::run_exporter(receiver, sinks));
+        Tracer { exporter }
+    }
+
+    /// Start the root span of a new trace.
+    pub fn start_trace(&self, name: &str) -> SpanGuard {
+        let trace = Arc::new(Trace {
+            trace_id: rand::random::<u128>().max(1),
+            spans: Mutex::new(Vec::new()),
+            failed: AtomicBool::new(false),
+            exporter: self.exporter.clone(),
+        });
+        SpanGuard::start(trace, None, name)
+    }
+
+    /// Wait until every trace ended so far has been exported.
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.exporter.send(ExportMessage::Flush(done)).is_err() || wait.await.is_err() {
+            warn!("The trace exporter stopped, some traces may be missing.");
+        }
+    }
+}
+
+/// Tracer of the run, if tracing was enabled.
+pub fn tracer() -> Option<&'static Tracer> {
+    run_context()?.tracer.as_ref()
+}
+
+/// Start the root span of a new trace, inert when tracing is disabled.
+pub fn start_trace(name: &str) -> SpanGuard {
+    match tracer() {
+        Some(tracer) => tracer.start_trace(name),
+        None => SpanGuard::inert(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+
+    #[tokio::test]
+    async fn test_spans_nest_and_failures_reach_the_root() {
+        let path = TempPath::file("jsonl");
+        let tracer = Tracer::new(vec![TraceSink::file(&path).unwrap()]);
+
+        let mut root = tracer.start_trace("migrate user");
+        root.set("line", 7);
+        drop(root.child("validation"));
+        in_span(&root, async {
+            let mut create = start_span("create user");
+            let response = reqwest::Response::from(
+                axum::http::Response::builder()
+                    .status(201)
+                    .header("client-request-id", "client-1")
+                    .header("request-id", "graph-1")
+                    .body("")
+                    .unwrap(),
+            );
+            create.record_response(&response);
+            let mut retry = create.child("retry");
+            retry.set("retry_after_seconds", 2);
+            drop(retry);
+            drop(create);
+            let mut method = start_span("email method");
+            method.fail("Received 400 Bad Request");
+        })
+        .await;
+        drop(root);
+        // Outside a trace, spans are inert
+        drop(start_span("orphan"));
+        tracer.flush().await;
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        let export: Value = serde_json::from_str(line.trim()).unwrap();
+        let spans = export["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        let span = |name: &str| spans.iter().find(|s| s["name"] == name).unwrap();
+        assert_eq!(spans.len(), 5);
+        let root = span("migrate user");
+        assert_eq!(root["parentSpanId"], "");
+        assert_eq!(root["status"]["code"], 2);
+        assert_eq!(root["attributes"][0]["value"]["intValue"], "7");
+        assert_eq!(span("validation")["parentSpanId"], root["spanId"]);
+        assert_eq!(span("create user")["parentSpanId"], root["spanId"]);
+        let create = span("create user");
+        assert_eq!(create["status"]["code"], 1);
+        assert_eq!(create["kind"], 3);
+        assert_eq!(
+            create["attributes"],
+            json!([
+                {"key": "http.response.status_code", "value": {"intValue": "201"}},
+                {"key": "graph.client_request_id", "value": {"stringValue": "client-1"}},
+                {"key": "graph.request_id", "value": {"stringValue": "graph-1"}},
+            ])
+        );
+        assert_eq!(span("retry")["parentSpanId"], span("create user")["spanId"]);
+        assert_eq!(
+            span("email method")["status"]["message"],
+            "Received 400 Bad Request"
+        );
+        assert!(spans.iter().all(|s| s["traceId"] == root["traceId"]));
+        assert_eq!(root["traceId"].as_str().unwrap().len(), 32);
+    }
+}
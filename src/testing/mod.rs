@@ -0,0 +1,3 @@
+mod temp;
+
+pub use crate::testing::temp::*;
@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+
+/// A unique path in the temporary directory, deleted with everything
+/// written to it when the guard is dropped, so that a failed test cleans up
+/// as well as a passing one.
+pub struct TempPath {
+    path: PathBuf,
+}
+
+impl TempPath {
+    /// Path of a file with the given extension, not created yet.
+    pub fn file(extension: &str) -> TempPath {
+        TempPath {
+            path: std::env::temp_dir().join(format!("{}.{extension}", uuid::Uuid::new_v4())),
+        }
+    }
+
+    /// File with the given extension holding `contents`.
+    pub fn with_contents(extension: &str, contents: &str) -> TempPath {
+        let file = TempPath::file(extension);
+        std::fs::write(&file, contents).unwrap();
+        file
+    }
+
+    /// Empty directory.
+    pub fn dir() -> TempPath {
+        let dir = TempPath {
+            path: std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()),
+        };
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl std::ops::Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        // The path may never have been created, or already be gone
+        let _ = if self.path.is_dir() {
+            std::fs::remove_dir_all(&self.path)
+        } else {
+            std::fs::remove_file(&self.path)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_paths_are_deleted_on_drop() {
+        let file = TempPath::with_contents("txt", "contents");
+        let dir = TempPath::dir();
+        std::fs::write(dir.join("nested.txt"), "contents").unwrap();
+        let (file_path, dir_path) = (file.to_path_buf(), dir.to_path_buf());
+        assert!(file_path.is_file() && dir_path.is_dir());
+
+        drop(file);
+        drop(dir);
+        assert!(!file_path.exists() && !dir_path.exists());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::graph::Row;
+use log::info;
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{Dynamic, Engine, EvalAltResult, Position, Scope, AST};
+use std::{fs, path::Path};
+
+// Columns holding JSON documents, exposed to scripts as native maps/arrays
+const NESTED_COLUMNS: [&str; 2] = ["identities", "passwordProfile"];
+
+/// Marker raised by the `skip(reason)` script function.
+#[derive(Debug, Clone)]
+struct SkipRow(String);
+
+/// Result of running the transform script on a row.
+#[derive(Debug)]
+pub enum ScriptOutcome {
+    /// The (possibly modified) row to migrate
+    Keep(Row),
+    /// The row must not be migrated, with the reason given by the script
+    Skip(String),
+}
+
+/// A Rhai script invoked on every input row before it becomes a `RequestBody`.
+///
+/// The script sees the row as the `row` map and the input line as `line`.
+/// It can change any field (e.g. `row.displayName = ...`), push new entries
+/// into `row.identities`, set `row.phoneAuthMethod`/`row.emailAuthMethod`,
+/// or call `skip("reason")` to leave the row out of the migration.
+///
+/// Example script:
+/// ```rhai
+/// if row.mail.ends_with("@test.local") { skip("test account"); }
+/// if row.displayName == "" { row.displayName = `${row.givenName} ${row.surname}`; }
+/// ```
+pub struct RowScript {
+    engine: Engine,
+    ast: AST,
+}
+
+impl RowScript {
+    /// Load and compile a script file.
+    ///
+    /// # Errors
+    /// * I/O failures while reading the file
+    /// * Syntax errors in the script
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RowScript, Box<dyn std::error::Error>> {
+        let source = fs::read_to_string(path)?;
+        RowScript::from_source(&source)
+    }
+
+    /// Compile a script from its source code.
+    pub fn from_source(source: &str) -> Result<RowScript, Box<dyn std::error::Error>> {
+        let mut engine = Engine::new();
+        engine.register_fn("skip", |reason: &str| -> Result<(), Box<EvalAltResult>> {
+            Err(EvalAltResult::ErrorRuntime(
+                Dynamic::from(SkipRow(reason.to_string())),
+                Position::NONE,
+            )
+            .into())
+        });
+        engine.on_print(|s| info!("Script: {s}"));
+        engine.on_debug(|s, _, _| info!("Script: {s}"));
+        let ast = engine.compile(source)?;
+        Ok(RowScript { engine, ast })
+    }
+
+    /// Run the script on `row`, read from input line `line`.
+    pub fn apply(&self, row: Row, line: u64) -> Result<ScriptOutcome, Box<EvalAltResult>> {
+        let mut scope = Scope::new();
+        scope.push("row", to_dynamic(expand_nested(row))?);
+        scope.push_constant("line", line as i64);
+
+        if let Err(e) = self.engine.run_ast_with_scope(&mut scope, &self.ast) {
+            return match skip_reason(&e) {
+                Some(reason) => Ok(ScriptOutcome::Skip(reason)),
+                None => Err(e),
+            };
+        }
+
+        let row = scope.get("row").cloned().unwrap_or_default();
+        Ok(ScriptOutcome::Keep(from_dynamic::<Row>(&row)?))
+    }
+}
+
+// Decode the JSON documents of the nested columns, so that scripts can work
+// on them directly. Values that are not valid JSON are left untouched.
+fn expand_nested(mut row: Row) -> Row {
+    for column in NESTED_COLUMNS {
+        if let Some(serde_json::Value::String(s)) = row.get(column) {
+            if let Ok(v) = serde_json::from_str::<serde_json::Value>(s) {
+                row.insert(column.to_string(), v);
+            }
+        }
+    }
+    row
+}
+
+// Look for the skip marker, also inside errors raised by script functions.
+fn skip_reason(err: &EvalAltResult) -> Option<String> {
+    match err {
+        EvalAltResult::ErrorRuntime(v, _) => v.read_lock::<SkipRow>().map(|s| s.0.clone()),
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _) => skip_reason(inner),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::RequestBody;
+
+    fn test_row() -> Row {
+        serde_json::from_str(
+            r#"{
+                "displayName": "",
+                "fullName": "Jane Doe",
+                "passwordProfile": "{\"forceChangePasswordNextSignIn\": true, \"password\": \"Pass123!\"}",
+                "identities": "[{\"signInType\": \"emailAddress\", \"issuer\": \"example.com\", \"issuerAssignedId\": \"jane@example.com\"}]",
+                "accountEnabled": true
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn keep(outcome: ScriptOutcome) -> Row {
+        match outcome {
+            ScriptOutcome::Keep(row) => row,
+            ScriptOutcome::Skip(reason) => panic!("unexpected skip: {reason}"),
+        }
+    }
+
+    #[test]
+    fn test_script_splits_name_and_derives_display_name() {
+        let script = RowScript::from_source(
+            r#"
+            let parts = row.fullName.split(" ");
+            row.givenName = parts[0];
+            row.surname = parts[1];
+            row.remove("fullName");
+            if row.displayName == "" { row.displayName = `${row.givenName} ${row.surname}`; }
+            "#,
+        )
+        .unwrap();
+        let body = RequestBody::from_row(keep(script.apply(test_row(), 2).unwrap())).unwrap();
+        assert_eq!(body.displayName, "Jane Doe");
+        assert_eq!(body.custom_fields["givenName"], "Jane");
+        assert_eq!(body.custom_fields["surname"], "Doe");
+        assert!(!body.custom_fields.contains_key("fullName"));
+        assert_eq!(body.custom_fields["accountEnabled"], true);
+    }
+
+    #[test]
+    fn test_script_adds_identity_and_auth_methods() {
+        let script = RowScript::from_source(
+            r#"
+            let email = row.identities[0].issuerAssignedId;
+            if email.ends_with("@example.com") {
+                row.identities.push(#{
+                    signInType: "userName",
+                    issuer: "contoso.onmicrosoft.com",
+                    issuerAssignedId: email.split("@")[0]
+                });
+            }
+            row.emailAuthMethod = email;
+            row.phoneAuthMethod = "+1 5555551234";
+            "#,
+        )
+        .unwrap();
+        let body = RequestBody::from_row(keep(script.apply(test_row(), 2).unwrap())).unwrap();
+        assert_eq!(body.identities.len(), 2);
+        assert_eq!(body.identities[1].issuerAssignedId, "jane");
+        assert_eq!(body.emailAuthMethod.as_deref(), Some("jane@example.com"));
+        assert_eq!(body.phoneAuthMethod.as_deref(), Some("+1 5555551234"));
+        assert_eq!(body.passwordProfile.password, "Pass123!");
+    }
+
+    #[test]
+    fn test_script_skip_with_reason() {
+        let script = RowScript::from_source(
+            r#"
+            fn check(row) { if row.fullName.starts_with("Jane") { skip("test account"); } }
+            check(row);
+            row.displayName = "never reached";
+            "#,
+        )
+        .unwrap();
+        match script.apply(test_row(), 7).unwrap() {
+            ScriptOutcome::Skip(reason) => assert_eq!(reason, "test account"),
+            ScriptOutcome::Keep(_) => panic!("row should have been skipped"),
+        }
+    }
+
+    #[test]
+    fn test_script_runtime_error() {
+        let script = RowScript::from_source(r#"throw "boom";"#).unwrap();
+        assert!(script.apply(test_row(), 2).is_err());
+    }
+
+    #[test]
+    fn test_script_syntax_error() {
+        assert!(RowScript::from_source("row.displayName = ").is_err());
+    }
+}